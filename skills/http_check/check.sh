@@ -0,0 +1,13 @@
+#!/bin/sh
+# 用法: check.sh <url> [timeout]
+# 参数由 bot 按 skill.toml 的声明校验后以 argv 传入，未提供的可选参数为空字符串。
+url="$1"
+timeout="${2:-10}"
+
+case "$url" in
+  http://*|https://*) ;;
+  *) echo "URL 必须以 http:// 或 https:// 开头: $url" >&2; exit 2 ;;
+esac
+
+curl -sS -o /dev/null -D - --max-time "$timeout" \
+  -w '\n状态码: %{http_code}\n耗时: %{time_total}s\n' -- "$url"
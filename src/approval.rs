@@ -0,0 +1,263 @@
+//! 审批模块：执行计划与 LLM 修正命令在执行前，通过 inline keyboard 等待授权用户批准。
+//!
+//! 回调数据格式为 `approval:<id>:<action>`，action 为 approve / reject / edit。
+//! 「修改」会让 bot 发出一条 ForceReply 提示，用户回复该提示的内容（每行一条命令）即替换原计划。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId, UserId};
+use tokio::sync::oneshot;
+
+use crate::auth::Authorizer;
+use crate::config::{ApprovalConfig, Role};
+use crate::executor::TaskCommand;
+
+const CALLBACK_PREFIX: &str = "approval";
+
+/// 审批结果
+#[derive(Debug)]
+pub enum ApprovalDecision {
+    Approved { by: String },
+    Rejected { by: String },
+    /// 用户修改了命令，需要对新命令重新审批
+    Edited { commands: Vec<TaskCommand>, by: String },
+    TimedOut,
+}
+
+/// inline keyboard 按钮对应的动作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApprovalAction {
+    Approve,
+    Reject,
+    Edit,
+}
+
+struct Pending {
+    chat_id: ChatId,
+    /// 发起任务的用户，不能批准自己的任务（admin 除外）；没有其他审批人时为 None
+    requester: Option<UserId>,
+    tx: oneshot::Sender<ApprovalDecision>,
+}
+
+pub struct ApprovalRegistry {
+    config: ApprovalConfig,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, Pending>>,
+    /// 等待用户回复修改内容的提示消息：(chat_id, 提示消息 ID) → 审批 ID
+    edit_prompts: Mutex<HashMap<(ChatId, MessageId), u64>>,
+}
+
+impl ApprovalRegistry {
+    pub fn new(config: ApprovalConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            edit_prompts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs)
+    }
+
+    /// 审批权限：admin 总是可以；配置了 approver_ids 时仅限其中的用户，否则可执行命令的角色都可审批。
+    pub fn is_approver(&self, user_id: Option<UserId>, role: Role) -> bool {
+        if role.is_admin() {
+            return true;
+        }
+        if self.config.approver_ids.is_empty() {
+            return role.can_run_commands();
+        }
+        user_id.is_some_and(|id| self.config.approver_ids.contains(&id.0))
+    }
+
+    /// 能否批准某个审批项：须有审批权限，且不是发起该任务的用户本人（admin 除外），
+    /// 否则 require_approval 规则形同虚设。拒绝与修改不受此限制。
+    pub fn can_approve(&self, id: u64, user_id: UserId, role: Role) -> bool {
+        if !self.is_approver(Some(user_id), role) {
+            return false;
+        }
+        role.is_admin() || self.pending.lock().unwrap().get(&id).and_then(|p| p.requester) != Some(user_id)
+    }
+
+    /// 除 `requester` 外是否还有人能批准：仅在配置了 `[auth]` 用户规则时判断（否则所有人同为 operator，
+    /// 常见的单人部署没有别人可以批准）。approver_ids 中的其他用户、其他 admin，未配置 approver_ids 时
+    /// 其他可执行命令的用户（或默认角色可执行命令）都算。
+    pub fn has_other_approver(&self, requester: UserId, auth: &Authorizer) -> bool {
+        if !auth.configured() {
+            return false;
+        }
+        let other = |id: UserId| id != requester;
+        if auth.users().any(|(id, role)| other(id) && role.is_admin()) {
+            return true;
+        }
+        if !self.config.approver_ids.is_empty() {
+            return self.config.approver_ids.iter().any(|&id| other(UserId(id)));
+        }
+        auth.default_role().can_run_commands() || auth.users().any(|(id, role)| other(id) && role.can_run_commands())
+    }
+
+    /// 登记一个待审批项，返回审批 ID 与等待结果的接收端。`requester` 为 None 时不限制谁来批准。
+    pub fn register(&self, chat_id: ChatId, requester: Option<UserId>) -> (u64, oneshot::Receiver<ApprovalDecision>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, Pending { chat_id, requester, tx });
+        (id, rx)
+    }
+
+    /// 待审批项所在的聊天；已处理或不存在时返回 None。
+    pub fn chat_of(&self, id: u64) -> Option<ChatId> {
+        self.pending.lock().unwrap().get(&id).map(|p| p.chat_id)
+    }
+
+    /// 提交审批结果，返回该审批项是否仍在等待。
+    pub fn resolve(&self, id: u64, decision: ApprovalDecision) -> bool {
+        let pending = self.pending.lock().unwrap().remove(&id);
+        self.edit_prompts.lock().unwrap().retain(|_, v| *v != id);
+        match pending {
+            Some(p) => p.tx.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    /// 放弃一个待审批项（超时等），不再接受回调。
+    pub fn discard(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+        self.edit_prompts.lock().unwrap().retain(|_, v| *v != id);
+    }
+
+    /// 记录「请回复修改内容」提示消息，用户回复它时即视为对该审批项的修改。
+    pub fn begin_edit(&self, chat_id: ChatId, prompt_msg_id: MessageId, id: u64) {
+        self.edit_prompts
+            .lock()
+            .unwrap()
+            .insert((chat_id, prompt_msg_id), id);
+    }
+
+    /// 若 reply_to 是某个修改提示，返回对应审批 ID。
+    pub fn edit_target(&self, chat_id: ChatId, reply_to: MessageId) -> Option<u64> {
+        self.edit_prompts
+            .lock()
+            .unwrap()
+            .get(&(chat_id, reply_to))
+            .copied()
+    }
+}
+
+pub fn keyboard(id: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("✅ 批准", callback_data(id, ApprovalAction::Approve)),
+        InlineKeyboardButton::callback("❌ 拒绝", callback_data(id, ApprovalAction::Reject)),
+        InlineKeyboardButton::callback("✏️ 修改", callback_data(id, ApprovalAction::Edit)),
+    ]])
+}
+
+fn callback_data(id: u64, action: ApprovalAction) -> String {
+    let action = match action {
+        ApprovalAction::Approve => "approve",
+        ApprovalAction::Reject => "reject",
+        ApprovalAction::Edit => "edit",
+    };
+    format!("{CALLBACK_PREFIX}:{id}:{action}")
+}
+
+/// 解析审批按钮的回调数据，非审批回调返回 None。
+pub fn parse_callback_data(data: &str) -> Option<(u64, ApprovalAction)> {
+    let mut parts = data.splitn(3, ':');
+    if parts.next()? != CALLBACK_PREFIX {
+        return None;
+    }
+    let id = parts.next()?.parse().ok()?;
+    let action = match parts.next()? {
+        "approve" => ApprovalAction::Approve,
+        "reject" => ApprovalAction::Reject,
+        "edit" => ApprovalAction::Edit,
+        _ => return None,
+    };
+    Some((id, action))
+}
+
+/// 将用户回复的修改内容解析为命令列表：每个非空行一条，忽略 # 开头的注释行。
+pub fn parse_edited_commands(text: &str) -> Vec<TaskCommand> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| TaskCommand {
+            command: l.to_string(),
+            description: "用户修改".to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(approver_ids: Vec<u64>) -> ApprovalRegistry {
+        ApprovalRegistry::new(ApprovalConfig { enabled: true, approver_ids, ..ApprovalConfig::default() })
+    }
+
+    #[test]
+    fn requester_cannot_approve_own_task() {
+        let approvals = registry(Vec::new());
+        let (id, _rx) = approvals.register(ChatId(1), Some(UserId(10)));
+        assert!(!approvals.can_approve(id, UserId(10), Role::Operator));
+        assert!(approvals.can_approve(id, UserId(11), Role::Operator));
+        assert!(approvals.can_approve(id, UserId(10), Role::Admin));
+        assert!(!approvals.can_approve(id, UserId(11), Role::Viewer));
+        // 仍可拒绝或修改自己的任务
+        assert!(approvals.is_approver(Some(UserId(10)), Role::Operator));
+    }
+
+    #[test]
+    fn approver_ids_limit_who_can_approve() {
+        let approvals = registry(vec![20]);
+        let (id, _rx) = approvals.register(ChatId(1), Some(UserId(10)));
+        assert!(!approvals.can_approve(id, UserId(11), Role::Operator));
+        assert!(approvals.can_approve(id, UserId(20), Role::Operator));
+        let (own, _rx) = approvals.register(ChatId(1), Some(UserId(20)));
+        assert!(!approvals.can_approve(own, UserId(20), Role::Operator));
+    }
+
+    #[test]
+    fn self_approval_ban_needs_another_approver() {
+        use crate::config::{AuthConfig, UserRule};
+        let auth = |users: &[(u64, Role)]| {
+            Authorizer::new(&AuthConfig {
+                users: users.iter().map(|&(id, role)| UserRule { id, role }).collect(),
+                ..AuthConfig::default()
+            })
+        };
+        let open = registry(Vec::new());
+        // 未配置 [auth]：所有人都是 operator，单人部署只能自己批准
+        assert!(!open.has_other_approver(UserId(10), &auth(&[])));
+        assert!(!open.has_other_approver(UserId(10), &auth(&[(10, Role::Operator)])));
+        assert!(!open.has_other_approver(UserId(10), &auth(&[(10, Role::Operator), (11, Role::Viewer)])));
+        assert!(open.has_other_approver(UserId(10), &auth(&[(10, Role::Operator), (11, Role::Operator)])));
+        assert!(open.has_other_approver(UserId(10), &auth(&[(10, Role::Operator), (12, Role::Admin)])));
+
+        let listed = registry(vec![10]);
+        let team = auth(&[(10, Role::Operator), (11, Role::Operator)]);
+        assert!(!listed.has_other_approver(UserId(10), &team));
+        assert!(listed.has_other_approver(UserId(11), &team));
+        assert!(listed.has_other_approver(UserId(10), &auth(&[(10, Role::Operator), (12, Role::Admin)])));
+    }
+
+    #[test]
+    fn callback_data_round_trip() {
+        for action in [ApprovalAction::Approve, ApprovalAction::Reject, ApprovalAction::Edit] {
+            assert_eq!(parse_callback_data(&callback_data(42, action)), Some((42, action)));
+        }
+        assert_eq!(parse_callback_data("approval:x:approve"), None);
+        assert_eq!(parse_callback_data("cancel:1"), None);
+        let edited = parse_edited_commands("ls\n\n# 注释\n  df -h  ");
+        assert_eq!(edited.iter().map(|c| c.command.as_str()).collect::<Vec<_>>(), ["ls", "df -h"]);
+    }
+}
@@ -0,0 +1,247 @@
+//! 任务产物目录：每个任务有一个新建的空目录，以环境变量 `BOT_ARTIFACTS` 传给命令。
+//! 任务结束时目录中的文件按 MIME 类型发给用户（图片 / 视频 / 音频 / 文档），随后删除整个目录，
+//! 不再从命令输出中猜测文件路径，也不会重复发送之前任务留下的旧文件。
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// 传给命令的环境变量名
+pub const ARTIFACTS_ENV: &str = "BOT_ARTIFACTS";
+/// media group 最多包含的文件数
+const MEDIA_GROUP_MAX: usize = 10;
+/// Bot API 以图片形式发送的大小上限，更大的图片改为文档发送
+const MAX_PHOTO_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Photo,
+    Video,
+    Audio,
+    Document,
+}
+
+impl ArtifactKind {
+    /// 同一个 media group 中只能混合图片与视频，音频、文档各自成组
+    fn group(self) -> u8 {
+        match self {
+            ArtifactKind::Photo | ArtifactKind::Video => 0,
+            ArtifactKind::Audio => 1,
+            ArtifactKind::Document => 2,
+        }
+    }
+}
+
+/// 产物目录中的一个文件
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub mime: &'static str,
+    pub kind: ArtifactKind,
+    pub size: u64,
+}
+
+impl Artifact {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// 一个任务的产物目录，drop 时删除
+pub struct ArtifactDir {
+    path: PathBuf,
+}
+
+impl ArtifactDir {
+    /// 在 `root` 下为任务创建空目录；同名的残留目录（如上次异常退出）会先被清空
+    pub fn create(root: &Path, tid: u64) -> Result<Self> {
+        let path = root.join(format!("task-{tid}"));
+        if path.exists() {
+            std::fs::remove_dir_all(&path)
+                .with_context(|| format!("无法清理残留的产物目录: {}", path.display()))?;
+        }
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("无法创建产物目录: {}", path.display()))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 把目录外的文件复制进来（同名时覆盖），返回复制后的路径
+    pub fn import(&self, file: &Path) -> Result<PathBuf> {
+        let name = file
+            .file_name()
+            .with_context(|| format!("无效的文件路径: {}", file.display()))?;
+        let dest = self.path.join(name);
+        std::fs::copy(file, &dest).with_context(|| format!("无法复制 {} 到产物目录", file.display()))?;
+        Ok(dest)
+    }
+
+    /// 递归列出目录中的普通文件（按路径排序）；不跟随符号链接，避免把目录外的文件发出去
+    pub fn collect(&self) -> Vec<Artifact> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+            for entry in entries.flatten() {
+                let Ok(meta) = entry.path().symlink_metadata() else { continue };
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                } else if meta.is_file() {
+                    let path = entry.path();
+                    let mime = mime_for(&path);
+                    files.push(Artifact {
+                        kind: kind_for(mime, meta.len()),
+                        mime,
+                        size: meta.len(),
+                        path,
+                    });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+}
+
+impl Drop for ArtifactDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tlog!("产物", "删除产物目录失败 {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// 按扩展名推断 MIME 类型
+pub fn mime_for(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "pdf" => "application/pdf",
+        "html" | "htm" => "text/html",
+        "txt" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// 决定发送方式：Telegram 以图片发送时会压缩且不支持动图 / 矢量图，这些及过大的图片按文档发送
+fn kind_for(mime: &str, size: u64) -> ArtifactKind {
+    match mime.split('/').next().unwrap_or("") {
+        "image" if matches!(mime, "image/png" | "image/jpeg" | "image/webp" | "image/bmp") && size <= MAX_PHOTO_BYTES => {
+            ArtifactKind::Photo
+        }
+        "video" => ArtifactKind::Video,
+        "audio" => ArtifactKind::Audio,
+        _ => ArtifactKind::Document,
+    }
+}
+
+/// 把文件分成可以一次发送的批次：图片与视频一组、音频一组、文档一组，每批最多 10 个
+pub fn media_batches(artifacts: Vec<Artifact>) -> Vec<Vec<Artifact>> {
+    let mut groups: [Vec<Artifact>; 3] = Default::default();
+    for a in artifacts {
+        groups[a.kind.group() as usize].push(a);
+    }
+    groups
+        .into_iter()
+        .flat_map(|g| g.chunks(MEDIA_GROUP_MAX).map(<[Artifact]>::to_vec).collect::<Vec<_>>())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(name: &str, size: u64) -> Artifact {
+        let path = PathBuf::from(name);
+        let mime = mime_for(&path);
+        Artifact { kind: kind_for(mime, size), mime, size, path }
+    }
+
+    #[test]
+    fn mime_and_kind_by_extension() {
+        use ArtifactKind::*;
+        let cases = [
+            ("shot.png", "image/png", Photo),
+            ("SHOT.PNG", "image/png", Photo),
+            ("photo.JpEg", "image/jpeg", Photo),
+            ("anim.gif", "image/gif", Document),
+            ("logo.svg", "image/svg+xml", Document),
+            ("clip.MP4", "video/mp4", Video),
+            ("clip.mov", "video/quicktime", Video),
+            ("song.mp3", "audio/mpeg", Audio),
+            ("voice.opus", "audio/ogg", Audio),
+            ("slides.html", "text/html", Document),
+            ("data.csv", "text/csv", Document),
+            ("backup.tar.gz", "application/gzip", Document),
+            ("Makefile", "application/octet-stream", Document),
+            (".env", "application/octet-stream", Document),
+            ("dir.d/noext", "application/octet-stream", Document),
+            ("archive.unknown", "application/octet-stream", Document),
+        ];
+        for (name, mime, kind) in cases {
+            let a = artifact(name, 1024);
+            assert_eq!((a.mime, a.kind), (mime, kind), "{name}");
+        }
+        // 超过图片上限的图片改为文档发送
+        assert_eq!(artifact("big.png", MAX_PHOTO_BYTES).kind, Photo);
+        assert_eq!(artifact("big.png", MAX_PHOTO_BYTES + 1).kind, Document);
+    }
+
+    #[test]
+    fn batches_split_by_group_and_size() {
+        let names = |batch: &[Artifact]| batch.iter().map(Artifact::name).collect::<Vec<_>>();
+        assert!(media_batches(Vec::new()).is_empty());
+
+        // 图片与视频同组，音频、文档各自成组，组内保持原顺序
+        let mixed = ["a.png", "b.pdf", "c.mp4", "d.mp3", "e.jpg", "f.txt"].map(|n| artifact(n, 1)).to_vec();
+        let batches = media_batches(mixed);
+        assert_eq!(
+            batches.iter().map(|b| names(b)).collect::<Vec<_>>(),
+            [vec!["a.png", "c.mp4", "e.jpg"], vec!["d.mp3"], vec!["b.pdf", "f.txt"]]
+        );
+
+        // 每批最多 10 个
+        let photos: Vec<Artifact> = (0..23).map(|i| artifact(&format!("{i:02}.png"), 1)).collect();
+        let batches = media_batches(photos);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [10, 10, 3]);
+        assert_eq!(names(&batches[2]), ["20.png", "21.png", "22.png"]);
+
+        let docs: Vec<Artifact> = (0..11).map(|i| artifact(&format!("{i}.pdf"), 1)).collect();
+        let mut mixed = docs;
+        mixed.push(artifact("x.mp4", 1));
+        let batches = media_batches(mixed);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [1, 10, 1]);
+        assert_eq!(batches[0][0].kind, ArtifactKind::Video);
+    }
+}
@@ -0,0 +1,84 @@
+//! 权限模块：按 Telegram 用户 ID（或频道署名）确定角色。
+//!
+//! 未配置任何 users / channel_authors 时保持旧行为：所有人按 default_role（默认 operator）处理。
+
+use std::collections::HashMap;
+use teloxide::types::{Message, UserId};
+
+use crate::config::{AuthConfig, Role};
+
+pub struct Authorizer {
+    users: HashMap<u64, Role>,
+    channel_authors: HashMap<String, Role>,
+    default_role: Role,
+}
+
+impl Authorizer {
+    pub fn new(config: &AuthConfig) -> Self {
+        let configured = !config.users.is_empty() || !config.channel_authors.is_empty();
+        let default_role = config
+            .default_role
+            .unwrap_or(if configured { Role::None } else { Role::Operator });
+        Self {
+            users: config.users.iter().map(|u| (u.id, u.role)).collect(),
+            channel_authors: config
+                .channel_authors
+                .iter()
+                .map(|a| (a.signature.clone(), a.role))
+                .collect(),
+            default_role,
+        }
+    }
+
+    /// 是否配置了按用户的权限规则
+    pub fn configured(&self) -> bool {
+        !self.users.is_empty() || !self.channel_authors.is_empty()
+    }
+
+    /// 按用户 ID 配置了角色的用户
+    pub fn users(&self) -> impl Iterator<Item = (UserId, Role)> + '_ {
+        self.users.iter().map(|(&id, &role)| (UserId(id), role))
+    }
+
+    /// 未列出用户的角色
+    pub fn default_role(&self) -> Role {
+        self.default_role
+    }
+
+    pub fn role_of_user(&self, user_id: UserId) -> Role {
+        self.users.get(&user_id.0).copied().unwrap_or(self.default_role)
+    }
+
+    /// 消息发送者的角色：优先 msg.from；频道消息无 from 时必须有匹配署名的显式规则。
+    pub fn role_of(&self, msg: &Message) -> Role {
+        if let Some(user) = &msg.from {
+            return self.role_of_user(user.id);
+        }
+        if !self.configured() {
+            return self.default_role;
+        }
+        msg.author_signature()
+            .and_then(|sig| self.channel_authors.get(sig).copied())
+            .unwrap_or(Role::None)
+    }
+}
+
+impl Role {
+    pub fn can_run_commands(self) -> bool {
+        self >= Role::Operator
+    }
+
+    /// 管理 bot（审批、重载技能等），同时可跳过命令策略
+    pub fn is_admin(self) -> bool {
+        self == Role::Admin
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::None => "none",
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
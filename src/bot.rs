@@ -1,256 +1,1182 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::{InputFile, MessageId};
+use teloxide::types::{
+    ForceReply, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaAudio, InputMediaDocument, InputMediaPhoto,
+    InputMediaVideo, MessageId, ParseMode,
+};
+use teloxide::{ApiError, RequestError};
 use teloxide::update_listeners::webhooks;
+use tokio::sync::watch;
 use tracing::{error, info, warn};
 
-use crate::config::AppConfig;
-use crate::executor::{CommandResult, Executor, TaskCommand};
-use crate::llm_client::{LlmClient, LlmIntent};
-use crate::skills;
+use crate::approval::{self, ApprovalAction, ApprovalDecision, ApprovalRegistry};
+use crate::artifacts::{self, Artifact, ArtifactDir, ArtifactKind};
+use crate::auth::Authorizer;
+use crate::config::{AgentConfig, AppConfig, PolicyVerdict, Role};
+use crate::conversation::ConversationStore;
+use crate::history::{Attempt, HistoryStore, TaskOutcome, TaskRecord};
+use crate::executor::{download_command, CommandResult, Executor, Interruption, RunOptions, TaskCommand};
+use crate::llm_client::{AgentAction, AgentStep, CallContext, ChatMessage, LlmClient, LlmIntent};
+use crate::native_skills;
+use crate::skill_retrieval::SkillRetriever;
+use crate::skills::{self, NativeContext, NativeSkill, Skill, SkillArgs, SkillSet, SkillTarget};
+use crate::tasks::{self, TaskHandle, TaskTable};
+use crate::text::truncate_str;
+use crate::stt::SttClient;
+use crate::uploads::UploadStore;
+use crate::usage::UsageStore;
 
 static TASK_COUNTER: AtomicU64 = AtomicU64::new(1);
+/// Telegram 单条消息的长度上限（按 UTF-16 码元计）
+const TG_MESSAGE_LIMIT: usize = 4096;
+/// 报告改为附件发送时，摘要中每条命令 stdout / stderr 保留的字节数
+const SUMMARY_STDOUT_BYTES: usize = 500;
+const SUMMARY_STDERR_BYTES: usize = 300;
+/// /history 默认与最多列出的任务数
+const HISTORY_DEFAULT: usize = 10;
+const HISTORY_MAX: usize = 50;
+/// 图片大小上限（Bot API 只能下载 20 MB 以内的文件，图片还要 base64 编码后发给模型）
+const MAX_IMAGE_BYTES: u32 = 10 * 1024 * 1024;
+/// 图片没有附带说明时发给模型的文字
+const DEFAULT_IMAGE_PROMPT: &str = "请看这张图片";
+/// 语音 / 音频大小上限（Bot API 只能下载 20 MB 以内的文件）
+const MAX_VOICE_BYTES: u32 = 20 * 1024 * 1024;
+/// Bot API 发送文件的大小上限
+const MAX_SEND_BYTES: u64 = 50 * 1024 * 1024;
+/// 文件没有附带说明时的消息文字
+const DEFAULT_UPLOAD_PROMPT: &str = "我上传了一个文件";
+/// 流式回答刷新状态消息的最小间隔，避免触发 Telegram 的编辑频率限制
+const ANSWER_EDIT_INTERVAL: Duration = Duration::from_secs(2);
+/// 流式回答在状态消息中预览的最大字节数，完整回答在生成结束后发送
+const ANSWER_PREVIEW_BYTES: usize = 3000;
 
-fn format_results(commands: &[TaskCommand], results: &[CommandResult]) -> String {
-    let mut msg = String::from("📋 任务执行报告\n\n");
+/// 各 handler 共享的运行时状态，作为 dptree 依赖注入。
+struct BotState {
+    llm: LlmClient,
+    executor: Executor,
+    skills: SkillSet,
+    retriever: SkillRetriever,
+    approvals: ApprovalRegistry,
+    auth: Authorizer,
+    conversations: ConversationStore,
+    tasks: TaskTable,
+    history: HistoryStore,
+    usage: Arc<UsageStore>,
+    stt: SttClient,
+    uploads: UploadStore,
+    /// 各任务产物目录的根目录
+    artifacts_root: std::path::PathBuf,
+    agent: AgentConfig,
+    allowed_chats: Vec<i64>,
+    /// 管理通知（如技能加载错误）发送到的聊天
+    admin_chat: Option<ChatId>,
+    max_fix_retries: u32,
+    echo_result: bool,
+    report_file_threshold: usize,
+}
+
+impl BotState {
+    /// 聊天是否在 `allowed_chat_ids` 中（列表为空时接收所有聊天）
+    fn chat_allowed(&self, chat_id: ChatId) -> bool {
+        self.allowed_chats.is_empty() || self.allowed_chats.contains(&chat_id.0)
+    }
+}
+
+/// 一条消息的处理过程（任务）的上下文，贯穿计划、审批与执行。
+struct TaskContext {
+    bot: Bot,
+    state: Arc<BotState>,
+    chat_id: ChatId,
+    tid: u64,
+    tag: String,
+    /// 发送者角色
+    role: Role,
+    /// 发送者（频道消息为 None），不能批准自己的任务
+    user_id: Option<UserId>,
+    /// 任务表中的登记项，承载取消信号
+    handle: Arc<TaskHandle>,
+    /// 任务结束后写入历史的记录
+    record: Mutex<TaskRecord>,
+    /// 本任务的 LLM 调用记录（应答的提供方）
+    llm_calls: CallContext,
+    /// 本任务的产物目录，任务结束（drop）时删除
+    artifacts: Option<ArtifactDir>,
+    /// 任务开始时的技能快照，执行期间技能重新加载不影响本任务
+    skills: Arc<Vec<Skill>>,
+}
+
+impl TaskContext {
+    fn run_options(&self) -> RunOptions {
+        RunOptions {
+            bypass_policy: self.role.is_admin(),
+            task: Some(self.handle.clone()),
+            artifacts: self.artifacts.as_ref().map(|a| a.path().to_path_buf()),
+            ..Default::default()
+        }
+    }
+
+    fn note(&self, f: impl FnOnce(&mut TaskRecord)) {
+        f(&mut self.record.lock().unwrap());
+    }
+
+    /// 记录任务结局与最终报告
+    fn finish(&self, outcome: TaskOutcome, report: &str) {
+        self.note(|r| {
+            r.outcome = Some(outcome);
+            r.report = Some(report.to_string());
+        });
+    }
+
+    fn record_attempt(&self, description: &str, started_at: chrono::DateTime<chrono::Local>, elapsed: Duration, result: &CommandResult) {
+        self.note(|r| {
+            r.attempts.push(Attempt {
+                description: description.to_string(),
+                started_at,
+                elapsed_ms: elapsed.as_millis() as u64,
+                result: result.clone(),
+            })
+        });
+    }
+
+    /// 更新进行中的状态消息并附带取消按钮；最终结果用 `edit_or_send` 覆盖时按钮随之移除。
+    async fn update_status(&self, status_msg_id: Option<MessageId>, text: &str) -> Option<MessageId> {
+        edit_or_send_with_keyboard(&self.bot, self.chat_id, status_msg_id, text, tasks::cancel_keyboard(self.tid)).await
+    }
+
+    /// 等待一次 LLM 调用；期间若发生重试，在状态消息 `html` 下方提示「重试中 (2/3)…」；
+    /// 流式生成问答时，每隔 `ANSWER_EDIT_INTERVAL` 把已生成的部分回答刷新到状态消息。
+    async fn with_llm_status<T>(&self, status_msg_id: Option<MessageId>, html: &str, call: impl Future<Output = T>) -> T {
+        let mut retries = self.llm_calls.subscribe_retries();
+        let mut answer = self.llm_calls.subscribe_answer();
+        let mut ticker = tokio::time::interval(ANSWER_EDIT_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        tokio::pin!(call);
+        loop {
+            tokio::select! {
+                out = &mut call => return out,
+                _ = ticker.tick() => {
+                    if !answer.has_changed().unwrap_or(false) {
+                        continue;
+                    }
+                    let partial = answer.borrow_and_update().clone();
+                    if partial.is_empty() {
+                        continue;
+                    }
+                    let text = format!("{} ▌", markdown_to_html(&truncate_str(&partial, ANSWER_PREVIEW_BYTES)));
+                    self.update_status(status_msg_id, &text).await;
+                }
+                Ok(()) = retries.changed() => {
+                    let Some(n) = retries.borrow_and_update().clone() else { continue };
+                    let note = format!(
+                        "{html}\n\n🔁 LLM {}（{}），{:.0}s 后重试中 ({}/{})…",
+                        escape_html(&n.reason),
+                        escape_html(&n.provider),
+                        n.delay.as_secs_f64().ceil(),
+                        n.attempt,
+                        n.max_retries
+                    );
+                    self.update_status(status_msg_id, &note).await;
+                }
+            }
+        }
+    }
+
+    /// 执行前是否需要人工审批：审批模式已开启，或有命令被策略判定为需审批（admin 跳过策略）。
+    fn needs_approval(&self, commands: &[TaskCommand]) -> bool {
+        if self.state.approvals.enabled() {
+            return true;
+        }
+        !self.role.is_admin()
+            && commands
+                .iter()
+                .any(|c| self.state.executor.check_policy(&c.command).verdict == PolicyVerdict::RequireApproval)
+    }
+}
+
+/// 执行计划（HTML），需审批或将被拒绝的命令附带命中的策略规则（`bypass_policy` 时不标注）。
+fn format_plan(commands: &[TaskCommand], executor: &Executor, bypass_policy: bool) -> String {
+    commands
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let mut line = format!(
+                "{}. {} → <code>{}</code>",
+                i + 1,
+                escape_html(&c.description),
+                escape_html(&truncate_str(&c.command, 100))
+            );
+            if bypass_policy {
+                return line;
+            }
+            let decision = executor.check_policy(&c.command);
+            let rule = escape_html(decision.rule.as_deref().unwrap_or(""));
+            match decision.verdict {
+                PolicyVerdict::Allow => {}
+                PolicyVerdict::RequireApproval => line.push_str(&format!("\n   ⚠️ 需审批（{rule}）")),
+                PolicyVerdict::Deny => line.push_str(&format!("\n   🚫 将被拒绝（{rule}）")),
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// 执行报告（HTML，输出放在 `<pre>` 中）。`summary` 为 true 时按 `SUMMARY_*_BYTES` 截断每条命令的输出，否则保留完整输出。
+fn format_results(commands: &[TaskCommand], results: &[CommandResult], summary: bool) -> String {
+    let mut msg = String::from("<b>📋 任务执行报告</b>\n\n");
     for (i, result) in results.iter().enumerate() {
         let desc = commands
             .get(i)
             .map(|c| c.description.as_str())
             .unwrap_or("未知");
-        let status = if result.success { "✅" } else { "❌" };
-        msg.push_str(&format!("{status} {desc}\n"));
-        msg.push_str(&format!("  命令: {}\n", result.command));
+        let status = match result.interrupted {
+            Some(Interruption::Cancelled) => "🛑",
+            Some(Interruption::TimedOut) => "⌛",
+            None if result.success => "✅",
+            None => "❌",
+        };
+        msg.push_str(&format!("{status} {}\n", escape_html(desc)));
+        msg.push_str(&format!("  命令: <code>{}</code>\n", escape_html(&result.command)));
+        if let Some(rule) = &result.policy_rule {
+            msg.push_str(&format!("  策略: 🚫 已拒绝（规则: {}）\n", escape_html(rule)));
+        }
         if !result.stdout.is_empty() {
-            let stdout = truncate(&result.stdout, 500);
-            msg.push_str(&format!("  输出:\n{stdout}\n"));
+            let stdout = if summary { truncate_str(&result.stdout, SUMMARY_STDOUT_BYTES) } else { result.stdout.clone() };
+            msg.push_str(&format!("  输出:\n{}\n", pre_block(&stdout)));
         }
         if !result.stderr.is_empty() {
-            let stderr = truncate(&result.stderr, 300);
-            msg.push_str(&format!("  错误:\n{stderr}\n"));
+            let stderr = if summary { truncate_str(&result.stderr, SUMMARY_STDERR_BYTES) } else { result.stderr.clone() };
+            msg.push_str(&format!("  错误:\n{}\n", pre_block(&stderr)));
         }
         msg.push('\n');
     }
     msg
 }
 
-/// 按字节截断到 max，保证在 UTF-8 字符边界处切断，避免 panic。
-fn truncate(s: &str, max: usize) -> String {
-    if s.len() <= max {
-        return s.to_string();
+/// 发送本任务产物目录中的文件：按类型分批，多个文件以 media group 发送，单个文件直接发送
+async fn deliver_artifacts(task: &TaskContext) {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, task.tag.as_str());
+    let Some(dir) = &task.artifacts else { return };
+    let (artifacts, too_big): (Vec<_>, Vec<_>) = dir.collect().into_iter().partition(|a| a.size <= MAX_SEND_BYTES);
+    if artifacts.is_empty() && too_big.is_empty() {
+        return;
     }
-    let mut end = max;
-    while end > 0 && !s.is_char_boundary(end) {
-        end -= 1;
+    tlog!(tag, "产物目录中有 {} 个文件", artifacts.len() + too_big.len());
+    for a in artifacts.iter().chain(&too_big) {
+        tlog!(tag, "  {} ({}, {} 字节)", a.path.display(), a.mime, a.size);
+    }
+    for a in &too_big {
+        tlog!(tag, "产物过大，跳过: {} ({} 字节)", a.path.display(), a.size);
+        bot.send_message(chat_id, format!("⚠️ 文件过大（超过 {} MB），无法发送: {}", MAX_SEND_BYTES / 1024 / 1024, a.name()))
+            .await
+            .ok();
+    }
+    for batch in artifacts::media_batches(artifacts) {
+        let names: Vec<String> = batch.iter().map(Artifact::name).collect();
+        tlog!(tag, "发送产物: {}", names.join(", "));
+        let sent = match batch.as_slice() {
+            [a] => send_artifact(bot, chat_id, a).await,
+            _ => bot
+                .send_media_group(chat_id, batch.iter().map(artifact_media).collect::<Vec<_>>())
+                .await
+                .map(|_| ()),
+        };
+        if let Err(e) = sent {
+            tlog!(tag, "产物发送失败: {} - {}", names.join(", "), e);
+            error!(err = %e, files = %names.join(", "), "产物发送失败");
+            bot.send_message(chat_id, format!("⚠️ 文件发送失败 {}: {e}", names.join(", ")))
+                .await
+                .ok();
+        }
     }
-    format!("{}...(截断)", &s[..end])
 }
 
-const IMAGE_EXTENSIONS: &[&str] = &[".png", ".jpg", ".jpeg", ".gif", ".bmp", ".webp"];
-const VIDEO_EXTENSIONS: &[&str] = &[".mp4", ".webm", ".mov", ".mkv", ".avi"];
+async fn send_artifact(bot: &Bot, chat_id: ChatId, a: &Artifact) -> ResponseResult<()> {
+    let file = InputFile::file(&a.path);
+    match a.kind {
+        ArtifactKind::Photo => bot.send_photo(chat_id, file).await.map(|_| ()),
+        ArtifactKind::Video => bot.send_video(chat_id, file).await.map(|_| ()),
+        ArtifactKind::Audio => bot.send_audio(chat_id, file).await.map(|_| ()),
+        ArtifactKind::Document => bot.send_document(chat_id, file).await.map(|_| ()),
+    }
+}
 
-fn find_file_paths_by_ext(text: &str, exts: &[&str]) -> Vec<String> {
-    text.split_whitespace()
-        .filter_map(|word| {
-            let path = word.trim_matches(|c| c == '"' || c == '\'');
-            let lower = path.to_lowercase();
-            if exts.iter().any(|ext| lower.ends_with(ext))
-                && (path.starts_with('/') || path.starts_with("./"))
-            {
-                Some(path.to_string())
-            } else {
-                None
+fn artifact_media(a: &Artifact) -> InputMedia {
+    let file = InputFile::file(&a.path);
+    match a.kind {
+        ArtifactKind::Photo => InputMedia::Photo(InputMediaPhoto::new(file)),
+        ArtifactKind::Video => InputMedia::Video(InputMediaVideo::new(file)),
+        ArtifactKind::Audio => InputMedia::Audio(InputMediaAudio::new(file)),
+        ArtifactKind::Document => InputMedia::Document(InputMediaDocument::new(file)),
+    }
+}
+
+/// 处理调用可执行技能的意图：按技能声明校验参数，按命令策略判定入口程序与参数，
+/// 需审批时经审批后不经 shell 执行入口程序。审批时被改成 shell 命令的，按普通命令执行。
+async fn run_skill(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    text: &str,
+    id: &str,
+    args: &serde_json::Map<String, serde_json::Value>,
+) {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, task.tag.as_str());
+    if !task.role.can_run_commands() {
+        tlog!(tag, "角色 {} 无权执行技能，已拒绝", task.role.name());
+        let refusal = format!("🚫 你的角色（{}）只能提问，无权在服务器上执行技能", task.role.name());
+        edit_or_send(bot, chat_id, status_msg_id, &refusal).await;
+        task.finish(TaskOutcome::Refused, &refusal);
+        return;
+    }
+    let Some(skill) = task.skills.iter().find(|s| s.id == id && s.is_executable()) else {
+        tlog!(tag, "未找到可执行技能: {}", id);
+        let reply = format!("❌ 未找到可执行技能 {id}");
+        edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+        task.finish(TaskOutcome::Error, &reply);
+        return;
+    };
+    let call = match skill.prepare(args) {
+        Ok(call) => call,
+        Err(e) => {
+            tlog!(tag, "技能 {} 参数不合法: {}", id, e);
+            let reply = format!("❌ 技能「{}」参数不合法: {e}", skill.name);
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+            return;
+        }
+    };
+    let cmd = TaskCommand {
+        command: call.label.clone(),
+        description: format!("技能「{}」", skill.name),
+    };
+    let policy_cmd = TaskCommand {
+        command: call.policy_command(),
+        ..cmd.clone()
+    };
+    let decision = task.state.executor.check_policy(&policy_cmd.command);
+    if decision.verdict == PolicyVerdict::Deny && !task.role.is_admin() {
+        let rule = decision.rule.as_deref().unwrap_or("deny");
+        tlog!(tag, "技能 {} 被策略拒绝 ← {}", id, rule);
+        let reply = format!("🚫 技能「{}」被命令策略拒绝（{rule}）", skill.name);
+        edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+        task.finish(TaskOutcome::Refused, &reply);
+        return;
+    }
+    if task.needs_approval(std::slice::from_ref(&policy_cmd)) {
+        match request_approval(task, status_msg_id, "📝 执行计划", vec![cmd.clone()]).await {
+            None => {
+                tlog!(tag, "技能调用未获批准，取消执行");
+                let outcome = if task.handle.is_cancelled() { TaskOutcome::Cancelled } else { TaskOutcome::Rejected };
+                task.finish(outcome, "执行计划未获批准");
+                return;
             }
-        })
-        .collect()
+            Some(edited) if !matches!(edited.as_slice(), [c] if c.command == cmd.command) => {
+                tlog!(tag, "审批时改为 {} 条命令，按普通命令执行", edited.len());
+                task.note(|r| r.commands = edited.clone());
+                let results = run_commands_with_fix_retry(task, status_msg_id, &edited).await;
+                report_results(task, status_msg_id, text, &edited, &results).await;
+                return;
+            }
+            Some(_) => {}
+        }
+    } else {
+        let plan = format!("📝 执行计划:\n1. {} → <code>{}</code>\n\n⏳ 执行中...", escape_html(&cmd.description), escape_html(&cmd.command));
+        task.update_status(status_msg_id, &plan).await;
+    }
+
+    task.note(|r| r.commands = vec![cmd.clone()]);
+    let executor = &task.state.executor;
+    let title = format!("⏳ {}", escape_html(&cmd.description));
+    let label = call.label.as_str();
+    let result = match &call.target {
+        SkillTarget::Program { program, args, env } => {
+            run_streamed_with(task, status_msg_id, &title, &cmd, |opts| async move {
+                executor.run_program(program, args, env, label, &opts).await
+            })
+            .await
+        }
+        SkillTarget::Native { skill, args } => {
+            run_streamed_with(task, status_msg_id, &title, &cmd, |opts| async move {
+                run_native_skill(task, skill.as_ref(), args, label, &opts).await
+            })
+            .await
+        }
+    }
+    .unwrap_or_else(|e| CommandResult::failed(cmd.command.clone(), e.to_string()));
+    report_results(task, status_msg_id, text, &[cmd], &[result]).await;
 }
 
-fn find_image_paths(text: &str) -> Vec<String> {
-    find_file_paths_by_ext(text, IMAGE_EXTENSIONS)
+/// 执行内置技能，把结果转成命令结果；技能生成的目录外文件复制到产物目录以便发送。
+async fn run_native_skill(
+    task: &TaskContext,
+    skill: &dyn NativeSkill,
+    args: &SkillArgs,
+    label: &str,
+    opts: &RunOptions,
+) -> Result<CommandResult> {
+    let ctx = NativeContext {
+        llm: &task.state.llm,
+        llm_calls: &task.llm_calls,
+        executor: &task.state.executor,
+        run_options: opts,
+        artifacts: task.artifacts.as_ref().map(ArtifactDir::path),
+        tag: &task.tag,
+    };
+    let output = match skill.run(&ctx, args).await {
+        Ok(output) => output,
+        Err(e) if task.handle.is_cancelled() => {
+            return Ok(CommandResult {
+                interrupted: Some(Interruption::Cancelled),
+                ..CommandResult::failed(label, e.to_string())
+            });
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(dir) = &task.artifacts {
+        for path in output.artifacts.iter().filter(|p| !p.starts_with(dir.path())) {
+            if let Err(e) = dir.import(path) {
+                tlog!(&task.tag, "复制技能产物失败 {}: {:#}", path.display(), e);
+            }
+        }
+    }
+    Ok(CommandResult {
+        command: label.to_string(),
+        success: true,
+        exit_code: Some(0),
+        stdout: output.text,
+        stderr: String::new(),
+        policy_rule: None,
+        interrupted: None,
+    })
 }
 
-fn find_video_paths(text: &str) -> Vec<String> {
-    find_file_paths_by_ext(text, VIDEO_EXTENSIONS)
+/// 处理发送文件的意图：检查角色、路径（restrict_paths、配置文件）与命令策略，
+/// 需审批时经审批后用 send_document 发回给用户。策略按等价命令 `cat <路径>` 判定。
+async fn send_requested_file(task: &TaskContext, status_msg_id: Option<MessageId>, text: &str, path: &str) {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, task.tag.as_str());
+    if !task.role.can_run_commands() {
+        tlog!(tag, "角色 {} 无权获取服务器文件，已拒绝", task.role.name());
+        let refusal = format!("🚫 你的角色（{}）只能提问，无权获取服务器上的文件", task.role.name());
+        edit_or_send(bot, chat_id, status_msg_id, &refusal).await;
+        task.finish(TaskOutcome::Refused, &refusal);
+        return;
+    }
+    let resolved = match task.state.executor.resolve_file(path) {
+        Ok(p) => p,
+        Err(e) => {
+            tlog!(tag, "无法发送文件 {}: {}", path, e);
+            let reply = format!("❌ 无法发送文件: {e}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+            return;
+        }
+    };
+    let shown = resolved.display().to_string();
+    let decision = task.state.executor.check_download(&resolved);
+    if decision.verdict == PolicyVerdict::Deny && !task.role.is_admin() {
+        let rule = decision.rule.as_deref().unwrap_or("deny");
+        tlog!(tag, "发送文件被策略拒绝: {} ← {}", shown, rule);
+        let reply = format!("🚫 文件 {shown} 被命令策略拒绝（{rule}）");
+        edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+        task.finish(TaskOutcome::Refused, &reply);
+        return;
+    }
+    let cmd = TaskCommand {
+        command: download_command(&resolved),
+        description: "发送文件".to_string(),
+    };
+    if task.needs_approval(std::slice::from_ref(&cmd)) {
+        match request_approval(task, status_msg_id, "📤 发送文件", vec![cmd.clone()]).await {
+            Some(approved) if matches!(approved.as_slice(), [c] if c.command == cmd.command) => {}
+            Some(_) => {
+                tlog!(tag, "发送文件的审批被修改，取消发送");
+                let reply = "❌ 发送文件不支持修改，已取消";
+                edit_or_send(bot, chat_id, status_msg_id, reply).await;
+                task.finish(TaskOutcome::Rejected, reply);
+                return;
+            }
+            None => {
+                tlog!(tag, "发送文件未获批准");
+                let outcome = if task.handle.is_cancelled() { TaskOutcome::Cancelled } else { TaskOutcome::Rejected };
+                task.finish(outcome, "发送文件未获批准");
+                return;
+            }
+        }
+    }
+    let size = std::fs::metadata(&resolved).map(|m| m.len()).unwrap_or(0);
+    if size > MAX_SEND_BYTES {
+        let reply = format!("⚠️ 文件过大（{:.1} MB，超过 {} MB），无法发送: {shown}", size as f64 / 1024.0 / 1024.0, MAX_SEND_BYTES / 1024 / 1024);
+        tlog!(tag, "{}", reply);
+        edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+        task.finish(TaskOutcome::Error, &reply);
+        return;
+    }
+    tlog!(tag, "发送文件: {} ({} 字节)", shown, size);
+    edit_or_send(bot, chat_id, status_msg_id, &format!("📤 正在发送 <code>{}</code>...", escape_html(&shown))).await;
+    match bot.send_document(chat_id, InputFile::file(&resolved)).await {
+        Ok(_) => {
+            let reply = format!("📤 已发送 {shown}");
+            edit_or_send(bot, chat_id, status_msg_id, &format!("📤 已发送 <code>{}</code>", escape_html(&shown))).await;
+            task.finish(TaskOutcome::Completed, &reply);
+            remember(task, text, &reply).await;
+        }
+        Err(e) => {
+            tlog!(tag, "文件发送失败: {} - {}", shown, e);
+            error!(err = %e, path = %shown, "文件发送失败");
+            let reply = format!("❌ 文件发送失败 {shown}: {e}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+        }
+    }
 }
 
-fn find_images_in_results(results: &[CommandResult]) -> Vec<String> {
-    let mut images = Vec::new();
-    for r in results {
-        images.extend(find_image_paths(&r.stdout));
-        images.extend(find_image_paths(&r.stderr));
-        images.extend(find_image_paths(&r.command));
+/// 转义 Telegram HTML 中的特殊字符
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
     }
-    images.sort();
-    images.dedup();
-    images
+    out
 }
 
-fn find_videos_in_results(results: &[CommandResult]) -> Vec<String> {
-    let mut videos = Vec::new();
-    for r in results {
-        videos.extend(find_video_paths(&r.stdout));
-        videos.extend(find_video_paths(&r.stderr));
-        videos.extend(find_video_paths(&r.command));
+/// 包在 `<pre>` 中的任意文本（命令输出等），去掉末尾空行
+fn pre_block(s: &str) -> String {
+    format!("<pre>{}</pre>", escape_html(s.trim_end()))
+}
+
+/// 把 LLM 回答、技能列表等常见的 Markdown 子集转成 Telegram HTML：
+/// ``` 代码块 → `<pre>`，`代码` → `<code>`，**粗体** 与 `#` 标题 → `<b>`，其余内容全部转义。
+fn markdown_to_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 32);
+    let mut in_fence = false;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        if content.trim_start().starts_with("```") {
+            if in_fence {
+                if out.ends_with('\n') {
+                    out.pop();
+                }
+                out.push_str("</pre>");
+            } else {
+                out.push_str("<pre>");
+            }
+            in_fence = !in_fence;
+            if !in_fence && line.ends_with('\n') {
+                out.push('\n');
+            }
+            continue;
+        }
+        if in_fence {
+            out.push_str(&escape_html(line));
+            continue;
+        }
+        let heading = content.trim_start_matches('#');
+        if heading.len() < content.len() && content.len() - heading.len() <= 6 && heading.starts_with(' ') {
+            out.push_str(&format!("<b>{}</b>", inline_markdown(heading.trim())));
+        } else {
+            out.push_str(&inline_markdown(content));
+        }
+        if line.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    if in_fence {
+        out.push_str("</pre>");
     }
-    videos.sort();
-    videos.dedup();
-    videos
+    out
 }
 
-async fn send_images(bot: &Bot, chat_id: ChatId, paths: &[String], tid: u64) {
-    for path in paths {
-        let file_path = std::path::Path::new(path);
-        if !file_path.exists() {
-            tlog!(&format!("图片 #{tid}"), "文件不存在，跳过: {}", path);
+/// 单行内的 `代码` 与 **粗体**；不成对的标记原样保留。
+fn inline_markdown(line: &str) -> String {
+    let mut parts: Vec<(bool, &str)> = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('`') {
+        match rest[start + 1..].find('`') {
+            Some(len) if len > 0 => {
+                parts.push((false, &rest[..start]));
+                parts.push((true, &rest[start + 1..start + 1 + len]));
+                rest = &rest[start + 2 + len..];
+            }
+            _ => break,
+        }
+    }
+    parts.push((false, rest));
+
+    let mut markers: usize = parts.iter().filter(|(code, _)| !code).map(|(_, t)| t.matches("**").count()).sum();
+    markers -= markers % 2;
+    let mut out = String::with_capacity(line.len() + 16);
+    let mut bold = false;
+    for (code, text) in parts {
+        if code {
+            out.push_str(&format!("<code>{}</code>", escape_html(text)));
             continue;
         }
-        tlog!(&format!("图片 #{tid}"), "发送: {}", path);
-        match bot
-            .send_photo(chat_id, InputFile::file(file_path))
-            .await
-        {
-            Ok(_) => tlog!(&format!("图片 #{tid}"), "发送成功: {}", path),
-            Err(e) => {
-                tlog!(&format!("图片 #{tid}"), "发送失败: {} - {}", path, e);
-                error!(err = %e, path = %path, "图片发送失败");
-                bot.send_message(chat_id, format!("⚠️ 图片发送失败 {path}: {e}"))
-                    .await
-                    .ok();
+        let mut segments = text.split("**");
+        out.push_str(&escape_html(segments.next().unwrap_or("")));
+        for segment in segments {
+            if markers > 0 {
+                markers -= 1;
+                bold = !bold;
+                out.push_str(if bold { "<b>" } else { "</b>" });
+            } else {
+                out.push_str("**");
             }
+            out.push_str(&escape_html(segment));
         }
     }
+    out
 }
 
-async fn send_document(bot: &Bot, chat_id: ChatId, path: &str, tid: u64) {
-    let file_path = std::path::Path::new(path);
-    if !file_path.exists() {
-        tlog!(&format!("文档 #{tid}"), "文件不存在: {}", path);
-        return;
-    }
-    tlog!(&format!("文档 #{tid}"), "发送: {}", path);
-    match bot.send_document(chat_id, InputFile::file(file_path)).await {
-        Ok(_) => tlog!(&format!("文档 #{tid}"), "发送成功: {}", path),
-        Err(e) => {
-            tlog!(&format!("文档 #{tid}"), "发送失败: {} - {}", path, e);
-            error!(err = %e, path = %path, "文档发送失败");
-            bot.send_message(chat_id, format!("⚠️ 文档发送失败 {path}: {e}"))
-                .await
-                .ok();
+/// HTML 被 Telegram 拒绝时的纯文本回退：去掉标签并还原转义字符。
+fn html_to_plain(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => out.push(c),
+            _ => {}
         }
     }
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
 }
 
-async fn send_videos(bot: &Bot, chat_id: ChatId, paths: &[String], tid: u64) {
-    for path in paths {
-        let file_path = std::path::Path::new(path);
-        if !file_path.exists() {
-            tlog!(&format!("视频 #{tid}"), "文件不存在，跳过: {}", path);
-            continue;
+/// 把 HTML 拆成标签、实体与单个字符，切分消息时不会切断它们。
+fn html_atoms(html: &str) -> Vec<&str> {
+    let mut atoms = Vec::new();
+    let mut i = 0;
+    while i < html.len() {
+        let rest = &html[i..];
+        let len = match rest.as_bytes()[0] {
+            b'<' => rest.find('>').map(|p| p + 1),
+            b'&' => rest.find(';').filter(|&p| p <= 8).map(|p| p + 1),
+            _ => None,
         }
-        tlog!(&format!("视频 #{tid}"), "发送: {}", path);
-        match bot
-            .send_video(chat_id, InputFile::file(file_path))
-            .await
-        {
-            Ok(_) => tlog!(&format!("视频 #{tid}"), "发送成功: {}", path),
-            Err(e) => {
-                tlog!(&format!("视频 #{tid}"), "发送失败: {} - {}", path, e);
-                error!(err = %e, path = %path, "视频发送失败");
-                bot.send_message(chat_id, format!("⚠️ 视频发送失败 {path}: {e}"))
-                    .await
-                    .ok();
+        .unwrap_or_else(|| rest.chars().next().map_or(1, char::len_utf8));
+        atoms.push(&rest[..len]);
+        i += len;
+    }
+    atoms
+}
+
+/// 按行把长 HTML 切成不超过 `limit`（UTF-16 码元）的多段；单行过长时在字符边界处硬切。
+/// 在 `<pre>` 块内切开时，前一段补上 `</pre>`，后一段重新以 `<pre>` 开始。
+fn split_message(html: &str, limit: usize) -> Vec<String> {
+    const OPEN: &str = "<pre>";
+    const CLOSE: &str = "</pre>";
+    let budget = limit.saturating_sub(OPEN.len() + CLOSE.len()).max(1);
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    let mut in_pre = false;
+    // 当前段中最后一个换行之后的位置，以及该处是否在 <pre> 内
+    let mut last_break: Option<(usize, bool)> = None;
+    for atom in html_atoms(html) {
+        let atom_len = atom.encode_utf16().count();
+        if current_len + atom_len > budget && !current.trim().is_empty() {
+            let (cut, pre_at_cut) = last_break.unwrap_or((current.len(), in_pre));
+            let rest = current.split_off(cut);
+            if pre_at_cut {
+                current.push_str(CLOSE);
             }
+            chunks.push(std::mem::take(&mut current));
+            current = if pre_at_cut { format!("{OPEN}{rest}") } else { rest };
+            current_len = current.encode_utf16().count();
+            last_break = None;
         }
+        current.push_str(atom);
+        current_len += atom_len;
+        match atom {
+            OPEN => in_pre = true,
+            CLOSE => in_pre = false,
+            "\n" => last_break = Some((current.len(), in_pre)),
+            _ => {}
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
     }
+    chunks
+        .into_iter()
+        .map(|c| c.trim_end().to_string())
+        .filter(|c| !c.is_empty())
+        .collect()
 }
 
-async fn edit_or_send(bot: &Bot, chat_id: ChatId, status_msg_id: Option<MessageId>, text: &str) -> Option<MessageId> {
+fn is_parse_error(e: &RequestError) -> bool {
+    matches!(e, RequestError::Api(ApiError::CantParseEntities(_)))
+}
+
+/// 以 HTML 编辑状态消息（为空或编辑失败则发新消息），可附带 inline keyboard（不带则移除原有按钮）。
+/// Telegram 无法解析 HTML 时自动改用纯文本重试。
+async fn edit_or_send_markup(
+    bot: &Bot,
+    chat_id: ChatId,
+    status_msg_id: Option<MessageId>,
+    html: &str,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Option<MessageId> {
+    let attempts = || [(html.to_string(), true), (html_to_plain(html), false)];
     if let Some(msg_id) = status_msg_id {
-        match bot.edit_message_text(chat_id, msg_id, text).await {
-            Ok(_) => return Some(msg_id),
+        for (text, is_html) in attempts() {
+            let mut req = bot.edit_message_text(chat_id, msg_id, text);
+            if is_html {
+                req = req.parse_mode(ParseMode::Html);
+            }
+            if let Some(keyboard) = &keyboard {
+                req = req.reply_markup(keyboard.clone());
+            }
+            match req.await {
+                Ok(_) => return Some(msg_id),
+                Err(e) if is_html && is_parse_error(&e) => {
+                    tlog!("TG", "HTML 解析失败，改用纯文本: {}", e);
+                }
+                Err(e) => {
+                    tlog!("TG", "编辑消息失败，改为发送新消息: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    for (text, is_html) in attempts() {
+        let mut req = bot.send_message(chat_id, text);
+        if is_html {
+            req = req.parse_mode(ParseMode::Html);
+        }
+        if let Some(keyboard) = &keyboard {
+            req = req.reply_markup(keyboard.clone());
+        }
+        match req.await {
+            Ok(msg) => return Some(msg.id),
+            Err(e) if is_html && is_parse_error(&e) => {
+                tlog!("TG", "HTML 解析失败，改用纯文本: {}", e);
+            }
             Err(e) => {
-                tlog!("TG", "编辑消息失败，改为发送新消息: {}", e);
+                tlog!("TG", "发送消息失败: {}", e);
+                break;
             }
         }
     }
-    match bot.send_message(chat_id, text).await {
-        Ok(msg) => Some(msg.id),
-        Err(_) => None,
+    None
+}
+
+/// 以 HTML 编辑状态消息，失败则发送新消息。
+async fn edit_or_send(bot: &Bot, chat_id: ChatId, status_msg_id: Option<MessageId>, html: &str) -> Option<MessageId> {
+    edit_or_send_markup(bot, chat_id, status_msg_id, html, None).await
+}
+
+/// 同 `edit_or_send`，但超过单条消息上限时按行分条：第一段覆盖状态消息，其余作为后续消息发送。
+async fn edit_or_send_long(bot: &Bot, chat_id: ChatId, status_msg_id: Option<MessageId>, html: &str) -> Option<MessageId> {
+    let chunks = split_message(html, TG_MESSAGE_LIMIT);
+    let Some((first, rest)) = chunks.split_first() else {
+        return edit_or_send(bot, chat_id, status_msg_id, html).await;
+    };
+    let mut last = edit_or_send(bot, chat_id, status_msg_id, first).await;
+    for chunk in rest {
+        match edit_or_send(bot, chat_id, None, chunk).await {
+            Some(id) => last = Some(id),
+            None => break,
+        }
+    }
+    last
+}
+
+/// 把文本作为 .txt 附件发送
+async fn send_text_document(bot: &Bot, chat_id: ChatId, file_name: &str, content: &str, tid: u64) {
+    tlog!(&format!("文档 #{tid}"), "发送 {} ({} 字节)", file_name, content.len());
+    let file = InputFile::memory(content.as_bytes().to_vec()).file_name(file_name.to_string());
+    if let Err(e) = bot.send_document(chat_id, file).await {
+        tlog!(&format!("文档 #{tid}"), "发送失败: {} - {}", file_name, e);
+        error!(err = %e, file = %file_name, "文档发送失败");
+        bot.send_message(chat_id, format!("⚠️ 完整报告发送失败: {e}")).await.ok();
     }
 }
 
-fn is_asking_skills_list(text: &str) -> bool {
-    let t = text.trim().to_lowercase();
-    t.contains("有哪些技能") || t.contains("列出技能") || t.contains("有什么技能")
-        || t.contains("list skill") || t.contains("已安装的 skill")
+/// 发送执行报告（附带 HTML 形式的 `suffix`，如解决建议），返回完整报告的纯文本。
+/// 不超过 `report_file_threshold` 时完整发送（超过单条上限自动分条）；
+/// 否则消息中只发截断后的摘要，完整报告作为 .txt 附件发送。
+async fn send_report(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    commands: &[TaskCommand],
+    results: &[CommandResult],
+    suffix: &str,
+) -> String {
+    let (bot, chat_id, tid) = (&task.bot, task.chat_id, task.tid);
+    let threshold = task.state.report_file_threshold;
+    let full = format!("{}{suffix}", format_results(commands, results, false));
+    let plain = html_to_plain(&full);
+    if threshold == 0 || plain.chars().count() <= threshold {
+        edit_or_send_long(bot, chat_id, status_msg_id, &full).await;
+        return plain;
+    }
+    let file_name = format!("report-{tid}.txt");
+    let summary = format!(
+        "{}{suffix}\n📎 输出较长，完整报告见附件 {file_name}",
+        format_results(commands, results, true)
+    );
+    tlog!(&task.tag, "报告 {} 字符，超过 {}，改为附件发送", plain.chars().count(), threshold);
+    edit_or_send_long(bot, chat_id, status_msg_id, &summary).await;
+    send_text_document(bot, chat_id, &file_name, &plain, tid).await;
+    plain
 }
 
-/// 是否为「列出 avfoundation 设备」命令
-fn is_list_avfoundation_devices(cmd: &str) -> bool {
-    let c = cmd.to_lowercase();
-    c.contains("avfoundation") && c.contains("list_devices") && c.contains("-i")
+/// 同 `edit_or_send`，但附带 inline keyboard。
+async fn edit_or_send_with_keyboard(
+    bot: &Bot,
+    chat_id: ChatId,
+    status_msg_id: Option<MessageId>,
+    html: &str,
+    keyboard: InlineKeyboardMarkup,
+) -> Option<MessageId> {
+    edit_or_send_markup(bot, chat_id, status_msg_id, html, Some(keyboard)).await
 }
 
-/// 是否为 avfoundation 录屏命令（macOS）
-fn is_avfoundation_record(cmd: &str) -> bool {
-    let c = cmd.to_lowercase();
-    c.contains("avfoundation") && c.contains("-i") && (c.contains("-t") || c.contains(".mp4") || c.contains("screen_record"))
+/// 执行一条命令。有状态消息且开启了实时输出时，运行期间按 `stream_interval_secs`
+/// 把 `title`（HTML）与输出末尾刷新到状态消息（有新输出才编辑，避免触发 Telegram 限流）。
+async fn run_streamed(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    title: &str,
+    cmd: &TaskCommand,
+) -> Result<CommandResult> {
+    let executor = &task.state.executor;
+    run_streamed_with(task, status_msg_id, title, cmd, |opts| async move {
+        executor.run_command(&cmd.command, &opts).await
+    })
+    .await
 }
 
-/// 从 ffmpeg -list_devices 的 stdout 中解析第一个「Capture screen」对应的设备索引。
-/// 格式示例: [AVFoundation indev @ 0x...] [1] Capture screen 0
-fn parse_avfoundation_screen_index(stdout: &str) -> Option<u32> {
-    for line in stdout.lines() {
-        if !line.contains("Capture screen") {
-            continue;
+/// 同 `run_streamed`，实际执行交给 `run`（如不经 shell 的技能程序），`cmd` 用于记录与展示。
+async fn run_streamed_with<F, Fut>(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    title: &str,
+    cmd: &TaskCommand,
+    run: F,
+) -> Result<CommandResult>
+where
+    F: FnOnce(RunOptions) -> Fut,
+    Fut: Future<Output = Result<CommandResult>>,
+{
+    let mut opts = task.run_options();
+    let forwarder = match (status_msg_id, task.state.executor.stream_interval()) {
+        (Some(msg_id), Some(interval)) => {
+            let (tx, mut rx) = watch::channel(String::new());
+            opts.output = Some(tx);
+            let (bot, chat_id, title) = (task.bot.clone(), task.chat_id, title.to_string());
+            let keyboard = tasks::cancel_keyboard(task.tid);
+            Some(tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    match rx.has_changed() {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(_) => break,
+                    }
+                    let tail = rx.borrow_and_update().clone();
+                    let text = format!("{title}\n\n{}", pre_block(&tail));
+                    edit_or_send_with_keyboard(&bot, chat_id, Some(msg_id), &text, keyboard.clone()).await;
+                }
+            }))
         }
-        let before_cap = match line.find("Capture screen") {
-            Some(p) => &line[..p],
-            None => continue,
+        _ => None,
+    };
+    let started_at = chrono::Local::now();
+    let start = Instant::now();
+    let result = run(opts).await;
+    if let Some(forwarder) = forwarder {
+        forwarder.abort();
+    }
+    let recorded = match &result {
+        Ok(r) => r.clone(),
+        Err(e) => CommandResult::failed(cmd.command.clone(), e.to_string()),
+    };
+    task.record_attempt(&cmd.description, started_at, start.elapsed(), &recorded);
+    result
+}
+
+/// 在 `status_msg_id`（为空则新发一条）上展示待审批命令，并等待授权用户点击按钮。
+/// 返回批准执行的命令（可能经用户修改）；拒绝或超时返回 None。
+async fn request_approval(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    title: &str,
+    mut commands: Vec<TaskCommand>,
+) -> Option<Vec<TaskCommand>> {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, task.tag.as_str());
+    let approvals = &task.state.approvals;
+    let mut msg_id = status_msg_id;
+    loop {
+        let plan = format_plan(&commands, &task.state.executor, task.role.is_admin());
+        // 有其他审批人时，发起者不能批准自己的任务
+        let requester = task.user_id.filter(|&u| approvals.has_other_approver(u, &task.state.auth));
+        let (id, rx) = approvals.register(chat_id, requester);
+        let text = format!(
+            "{title}:\n{plan}\n\n⏸ 等待审批（{} 秒内未批准将自动拒绝）",
+            approvals.timeout().as_secs()
+        );
+        msg_id = edit_or_send_with_keyboard(bot, chat_id, msg_id, &text, approval::keyboard(id)).await;
+        tlog!(tag, "等待审批 #{} ({} 条命令)", id, commands.len());
+
+        let decision = tokio::select! {
+            d = tokio::time::timeout(approvals.timeout(), rx) => match d {
+                Ok(Ok(d)) => d,
+                _ => {
+                    approvals.discard(id);
+                    ApprovalDecision::TimedOut
+                }
+            },
+            _ = task.handle.cancelled() => {
+                approvals.discard(id);
+                tlog!(tag, "任务已取消，放弃审批 #{}", id);
+                edit_or_send(bot, chat_id, msg_id, &format!("{title}:\n{plan}\n\n🛑 任务已取消")).await;
+                return None;
+            }
         };
-        let mut idx = before_cap.len();
-        while idx > 0 {
-            let Some(close) = before_cap[..idx].rfind(']') else { break };
-            let Some(open) = before_cap[..close].rfind('[') else { break };
-            let between = before_cap[open + 1..close].trim();
-            if !between.is_empty()
-                && between.chars().all(|c| c.is_ascii_digit())
-                && between.parse::<u32>().is_ok()
-            {
-                return between.parse().ok();
+        let (outcome, approved) = match decision {
+            ApprovalDecision::Approved { by } => {
+                tlog!(tag, "审批 #{} 已由 {} 批准", id, by);
+                (format!("✅ {} 已批准，执行中...", escape_html(&by)), true)
+            }
+            ApprovalDecision::Rejected { by } => {
+                tlog!(tag, "审批 #{} 已由 {} 拒绝", id, by);
+                (format!("❌ {} 已拒绝，不执行", escape_html(&by)), false)
+            }
+            ApprovalDecision::TimedOut => {
+                tlog!(tag, "审批 #{} 超时，自动拒绝", id);
+                ("⌛ 审批超时，已自动拒绝".to_string(), false)
+            }
+            ApprovalDecision::Edited { commands: edited, by } => {
+                tlog!(tag, "审批 #{} 已由 {} 修改为 {} 条命令", id, by, edited.len());
+                if edited.is_empty() {
+                    ("❌ 修改后没有可执行的命令，已取消".to_string(), false)
+                } else {
+                    commands = edited;
+                    continue;
+                }
             }
-            idx = close;
+        };
+        let text = format!("{title}:\n{plan}\n\n{outcome}");
+        if approved {
+            task.update_status(msg_id, &text).await;
+        } else {
+            edit_or_send(bot, chat_id, msg_id, &text).await;
         }
+        return approved.then_some(commands);
     }
-    None
 }
 
-/// 将 avfoundation 录屏命令中的 -i "X:0" 设备号替换为指定索引
-fn replace_avfoundation_device_index(cmd: &str, index: u32) -> String {
-    let mut result = cmd.to_string();
-    let new_index_str = index.to_string();
-    let Some(pos) = result.find("-i ") else { return result };
-    let mut i = pos + 3;
-    let bytes = result.as_bytes();
-    while i < bytes.len() && bytes[i] == b' ' {
-        i += 1;
+/// 记录一轮对话；超出预算时用 LLM 把较早的轮次压缩成摘要。
+async fn remember(task: &TaskContext, user: &str, reply: &str) {
+    let state = &task.state;
+    let Some(overflow) = state.conversations.record(task.chat_id.0, user, reply) else { return };
+    tlog!(&task.tag, "对话记忆超出预算，摘要较早的 {} 条消息", overflow.messages.len());
+    match state
+        .llm
+        .summarize_conversation(&task.llm_calls, overflow.previous_summary.as_deref(), &overflow.messages)
+        .await
+    {
+        Ok(summary) => {
+            tlog!(&task.tag, "对话摘要 ({} 字符): {}", summary.len(), truncate_str(&summary, 200));
+            state.conversations.set_summary(task.chat_id.0, summary);
+        }
+        Err(e) => tlog!(&task.tag, "对话摘要失败，丢弃较早的轮次: {}", e),
     }
-    if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
-        i += 1;
+}
+
+/// 解析 `/cmd@botname 参数` 形式的 bot 命令，返回 (小写命令名, 参数)。
+fn parse_bot_command(text: &str) -> Option<(String, &str)> {
+    let rest = text.trim().strip_prefix('/')?;
+    let (head, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let name = head.split('@').next().unwrap_or(head);
+    if name.is_empty() {
+        return None;
     }
-    let num_start = i;
-    while i < bytes.len() && bytes[i].is_ascii_digit() {
-        i += 1;
+    Some((name.to_lowercase(), args.trim()))
+}
+
+/// 取消任务：向其发送取消信号，返回给用户的提示。非 admin 只能取消本聊天的任务。
+fn cancel_task(state: &BotState, chat_id: ChatId, tid: u64, role: Role, by: &str) -> String {
+    let Some(handle) = state.tasks.get(tid).filter(|t| t.chat_id == chat_id || role.is_admin()) else {
+        return format!("⚠️ 任务 #{tid} 不存在或已结束");
+    };
+    if handle.is_cancelled() {
+        return format!("⏳ 任务 #{tid} 正在取消中");
     }
-    let num_end = i;
-    if num_end > num_start && num_end < bytes.len() && bytes[num_end] == b':' && bytes.get(num_end + 1) == Some(&b'0') {
-        result.replace_range(num_start..num_end, &new_index_str);
+    handle.cancel();
+    match handle.process_group() {
+        Some(pgid) => {
+            tlog!(&format!("取消 #{tid}"), "{} 取消任务，终止进程组 {}", by, pgid);
+            format!("🛑 已取消任务 #{tid}，正在终止进程组 {pgid}")
+        }
+        None => {
+            tlog!(&format!("取消 #{tid}"), "{} 取消任务", by);
+            format!("🛑 已取消任务 #{tid}")
+        }
     }
-    result
+}
+
+/// 处理 bot 自身的管理命令，返回是否已处理（未识别的命令交给 LLM）。
+async fn handle_bot_command(
+    bot: &Bot,
+    state: &BotState,
+    msg: &Message,
+    role: Role,
+    name: &str,
+    args: &str,
+    tag: &str,
+) -> ResponseResult<bool> {
+    let chat_id = msg.chat.id;
+    match name {
+        "cancel" => {
+            let reply = if !role.can_run_commands() {
+                format!("🚫 你的角色（{}）无权取消任务", role.name())
+            } else if let Some(tid) = tasks::parse_tid(args) {
+                let by = msg
+                    .from
+                    .as_ref()
+                    .map(|u| u.first_name.as_str())
+                    .or(msg.author_signature())
+                    .unwrap_or("unknown");
+                cancel_task(state, chat_id, tid, role, by)
+            } else {
+                let running: Vec<String> = state
+                    .tasks
+                    .running_in(chat_id)
+                    .iter()
+                    .map(|t| format!("#{}", t.tid))
+                    .collect();
+                if running.is_empty() {
+                    "ℹ️ 本聊天没有进行中的任务".to_string()
+                } else {
+                    format!("进行中的任务: {}\n用法: /cancel <tid>", running.join(" "))
+                }
+            };
+            tlog!(tag, "{}", reply);
+            bot.send_message(chat_id, reply).await?;
+            Ok(true)
+        }
+        "history" => {
+            let reply = if !state.history.enabled() {
+                "ℹ️ 未开启任务历史（[history] enabled = false）".to_string()
+            } else {
+                let n = args.parse::<usize>().unwrap_or(HISTORY_DEFAULT).clamp(1, HISTORY_MAX);
+                let records = state.history.recent(chat_id.0, n);
+                if records.is_empty() {
+                    "ℹ️ 本聊天还没有任务记录".to_string()
+                } else {
+                    let lines: Vec<String> = records.iter().map(|r| r.summary_line()).collect();
+                    format!("🗂 最近 {} 个任务:\n{}\n\n查看详情: /task <tid>", records.len(), lines.join("\n"))
+                }
+            };
+            edit_or_send_long(bot, chat_id, None, &escape_html(&reply)).await;
+            Ok(true)
+        }
+        "task" => {
+            let reply = match tasks::parse_tid(args) {
+                _ if !state.history.enabled() => "ℹ️ 未开启任务历史（[history] enabled = false）".to_string(),
+                None => "用法: /task <tid>".to_string(),
+                Some(tid) => match state.history.get(tid).filter(|r| r.chat_id == chat_id.0 || role.is_admin()) {
+                    Some(record) => record.describe(),
+                    None if state.tasks.get(tid).is_some() => format!("⏳ 任务 #{tid} 仍在进行中"),
+                    None => format!("⚠️ 没有找到任务 #{tid}"),
+                },
+            };
+            edit_or_send_long(bot, chat_id, None, &escape_html(&reply)).await;
+            Ok(true)
+        }
+        "usage" => {
+            let user_id = msg.from.as_ref().map(|u| u.id.0);
+            let reply = match args.trim() {
+                "all" if role.is_admin() => state.usage.report(None, user_id),
+                "all" => "🚫 只有 admin 可以查看所有聊天的用量".to_string(),
+                _ => state.usage.report(Some(chat_id.0), user_id),
+            };
+            edit_or_send_long(bot, chat_id, None, &escape_html(&reply)).await;
+            Ok(true)
+        }
+        "skills" => {
+            let reply = match args.trim() {
+                "reload" if role.is_admin() => {
+                    let load = state.skills.reload();
+                    tlog!(tag, "重新加载技能: {} 个, {} 个错误", load.skills.len(), load.errors.len());
+                    escape_html(&format_skill_reload(&load))
+                }
+                "reload" => escape_html("🚫 只有 admin 可以重新加载技能"),
+                // 技能列表是 Markdown（**名称** 等），与问答回复一样渲染
+                "" => markdown_to_html(&skills::list_skills_summary(&state.skills.get())),
+                _ => escape_html("用法: /skills 或 /skills reload"),
+            };
+            edit_or_send_long(bot, chat_id, None, &reply).await;
+            Ok(true)
+        }
+        "reset" => {
+            state.conversations.reset(chat_id.0);
+            tlog!(tag, "已清空对话记忆");
+            bot.send_message(chat_id, "🧹 已清空本聊天的对话记忆").await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// 技能加载结果的文字说明
+fn format_skill_reload(load: &skills::SkillLoad) -> String {
+    let mut s = format!("🔄 已重新加载技能，共 {} 个", load.skills.len());
+    if !load.errors.is_empty() {
+        s.push_str(&format_skill_errors(&load.errors));
+    }
+    s
+}
+
+fn format_skill_errors(errors: &[String]) -> String {
+    let lines: Vec<String> = errors.iter().map(|e| format!("- {e}")).collect();
+    format!("\n⚠️ {} 个技能加载失败:\n{}", errors.len(), lines.join("\n"))
+}
+
+/// 把技能加载错误发到管理聊天（未配置 telegram.admin_chat_id 时只写日志）
+async fn report_skill_errors(bot: &Bot, admin_chat: Option<ChatId>, errors: &[String]) {
+    let Some(chat_id) = admin_chat.filter(|_| !errors.is_empty()) else { return };
+    let text = format!("🧩 技能目录{}", format_skill_errors(errors));
+    edit_or_send_long(bot, chat_id, None, &escape_html(&text)).await;
+}
+
+/// 轮询技能目录，清单文件有变化时重新加载并替换技能集合
+async fn watch_skills(bot: Bot, state: Arc<BotState>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if !state.skills.changed() {
+            continue;
+        }
+        let load = state.skills.reload();
+        tlog!("技能", "检测到 {} 变化，已重新加载: {} 个, {} 个错误", state.skills.dir(), load.skills.len(), load.errors.len());
+        report_skill_errors(&bot, state.admin_chat, &load.errors).await;
+    }
+}
+
+fn is_asking_skills_list(text: &str) -> bool {
+    let t = text.trim().to_lowercase();
+    t.contains("有哪些技能") || t.contains("列出技能") || t.contains("有什么技能")
+        || t.contains("list skill") || t.contains("已安装的 skill")
 }
 
 /// 从 LLM 的「解决建议」文本中提取一条可执行的 shell 命令（优先代码块或反引号内的内容）。
@@ -271,7 +1197,7 @@ fn extract_command_from_suggestion(s: &str) -> Option<String> {
             return Some(first_line.to_string());
         }
         if block.lines().count() <= 2 {
-            let one = block.lines().filter(|l| !l.trim().is_empty() && !l.trim().starts_with('#')).next();
+            let one = block.lines().find(|l| !l.trim().is_empty() && !l.trim().starts_with('#'));
             if let Some(line) = one {
                 return Some(line.trim().to_string());
             }
@@ -295,52 +1221,14 @@ fn extract_command_from_suggestion(s: &str) -> Option<String> {
             || line.starts_with("python ")
             || line.starts_with("python3 ")
             || line.starts_with("/usr/bin/python")
-            || line.starts_with("pip ")
-            || line.starts_with("source ")
-            || (line.starts_with('/') && line.contains("python"))
-        {
-            return Some(line.to_string());
-        }
-    }
-    None
-}
-
-/// 解析 ppt-generator "标题" "内容" 形式的命令，返回 (标题, 讲稿内容)。
-fn parse_ppt_generator_args(cmd: &str) -> Option<(String, String)> {
-    let cmd = cmd.trim();
-    if !cmd.starts_with("ppt-generator ") {
-        return None;
-    }
-    let rest = cmd["ppt-generator ".len()..].trim_start();
-    let mut in_quote = false;
-    let mut escape = false;
-    let mut segments: Vec<(usize, usize)> = vec![];
-    let mut segment_start = 0usize;
-    for (i, c) in rest.char_indices() {
-        if escape {
-            escape = false;
-            continue;
-        }
-        if c == '\\' && in_quote {
-            escape = true;
-            continue;
-        }
-        if c == '"' {
-            if !in_quote {
-                in_quote = true;
-                segment_start = i + 1;
-            } else {
-                in_quote = false;
-                segments.push((segment_start, i));
-            }
+            || line.starts_with("pip ")
+            || line.starts_with("source ")
+            || (line.starts_with('/') && line.contains("python"))
+        {
+            return Some(line.to_string());
         }
     }
-    if segments.len() < 2 {
-        return None;
-    }
-    let title = rest[segments[0].0..segments[0].1].to_string();
-    let content = rest[segments[1].0..segments[1].1].to_string();
-    Some((title, content))
+    None
 }
 
 fn extract_install_query(text: &str) -> Option<String> {
@@ -350,7 +1238,7 @@ fn extract_install_query(text: &str) -> Option<String> {
         if lower.contains(prefix) {
             let start = lower.find(prefix).unwrap() + prefix.len();
             let rest = t[start..].trim();
-            let end = rest.find(|c: char| c == '？' || c == '?' || c == '。').unwrap_or(rest.len());
+            let end = rest.find(['？', '?', '。']).unwrap_or(rest.len());
             let query = rest[..end].trim();
             if !query.is_empty() {
                 return Some(query.to_string());
@@ -361,40 +1249,51 @@ fn extract_install_query(text: &str) -> Option<String> {
 }
 
 /// 逐条执行命令；某条失败时若 max_fix_retries > 0 则向 LLM 询问修正并重试，直到成功或达到上限。
+/// 开启审批模式时，每条修正命令执行前都需要再次审批。
 async fn run_commands_with_fix_retry(
-    executor: &Executor,
-    llm: &LlmClient,
-    skills: &[skills::Skill],
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
     commands: &[TaskCommand],
-    max_fix_retries: u32,
-    tag: &str,
 ) -> Vec<CommandResult> {
+    let state = &task.state;
+    let tag = task.tag.as_str();
+    let llm = &state.llm;
+    let skills = task.skills.as_slice();
+    let max_fix_retries = state.max_fix_retries;
 
     let mut results = Vec::new();
-    for (i, task) in commands.iter().enumerate() {
-        tlog!(tag, "[{}/{}] {} → {}", i + 1, commands.len(), task.description, truncate(&task.command, 80));
-        let mut result = match executor.run_command(&task.command).await {
+    for (i, cmd) in commands.iter().enumerate() {
+        if task.handle.is_cancelled() {
+            tlog!(tag, "任务已取消，跳过剩余 {} 条命令", commands.len() - i);
+            break;
+        }
+        tlog!(tag, "[{}/{}] {} → {}", i + 1, commands.len(), cmd.description, truncate_str(&cmd.command, 80));
+        let title = format!(
+            "⏳ [{}/{}] {}\n<code>{}</code>",
+            i + 1,
+            commands.len(),
+            escape_html(&cmd.description),
+            escape_html(&truncate_str(&cmd.command, 100))
+        );
+        let mut result = match run_streamed(task, status_msg_id, &title, cmd).await {
             Ok(r) => r,
             Err(e) => {
                 tlog!(tag, "命令异常: {}", e);
-                results.push(CommandResult {
-                    command: task.command.clone(),
-                    success: false,
-                    exit_code: None,
-                    stdout: String::new(),
-                    stderr: e.to_string(),
-                });
+                results.push(CommandResult::failed(cmd.command.clone(), e.to_string()));
                 break;
             }
         };
         let mut retry_count = 0u32;
-        while !result.success && retry_count < max_fix_retries {
+        while !result.success && retry_count < max_fix_retries && !task.handle.is_cancelled() {
             let fix_context = skills::build_relevant_context_for_fix(skills, &result.command);
             tlog!(tag, "命令失败，第 {} 次请求 LLM 修正 (最多 {})", retry_count + 1, max_fix_retries);
-            let suggestion = match llm
-                .ask_fix_for_failure(&result.command, result.exit_code, &result.stderr, Some(&fix_context))
-                .await
-            {
+            let waiting = format!(
+                "🔧 命令失败，请求 LLM 修正（第 {} 次）\n<code>{}</code>",
+                retry_count + 1,
+                escape_html(&truncate_str(&result.command, 100))
+            );
+            let fix = llm.ask_fix_for_failure(&task.llm_calls, &result.command, result.exit_code, &result.stderr, Some(&fix_context));
+            let suggestion = match task.with_llm_status(status_msg_id, &waiting, fix).await {
                 Ok(s) => s,
                 Err(e) => {
                     tlog!(tag, "获取修正建议失败: {}", e);
@@ -408,17 +1307,36 @@ async fn run_commands_with_fix_retry(
                     break;
                 }
             };
-            tlog!(tag, "执行修正命令: {}", truncate(&fix_cmd, 120));
-            match executor.run_command(&fix_cmd).await {
-                Ok(r) => result = r,
-                Err(e) => {
-                    result = CommandResult {
-                        command: fix_cmd,
-                        success: false,
-                        exit_code: None,
-                        stdout: String::new(),
-                        stderr: e.to_string(),
-                    };
+            let fix_cmds = vec![TaskCommand {
+                command: fix_cmd,
+                description: format!("LLM 修正（第 {} 次）", retry_count + 1),
+            }];
+            let fix_cmds = if task.needs_approval(&fix_cmds) {
+                match request_approval(task, None, "🔧 修正命令", fix_cmds).await {
+                    Some(c) => c,
+                    None => {
+                        tlog!(tag, "修正命令未获批准，停止重试");
+                        break;
+                    }
+                }
+            } else {
+                fix_cmds
+            };
+            for fix in &fix_cmds {
+                tlog!(tag, "执行修正命令: {}", truncate_str(&fix.command, 120));
+                let title = format!(
+                    "🔧 {}\n<code>{}</code>",
+                    escape_html(&fix.description),
+                    escape_html(&truncate_str(&fix.command, 100))
+                );
+                match run_streamed(task, status_msg_id, &title, fix).await {
+                    Ok(r) => result = r,
+                    Err(e) => {
+                        result = CommandResult::failed(fix.command.clone(), e.to_string());
+                    }
+                }
+                if !result.success {
+                    break;
                 }
             }
             retry_count += 1;
@@ -433,49 +1351,266 @@ async fn run_commands_with_fix_retry(
     results
 }
 
-async fn process_message(
-    bot: Bot,
-    chat_id: ChatId,
-    text: String,
-    llm: Arc<LlmClient>,
-    executor: Arc<Executor>,
-    skills: Arc<Vec<skills::Skill>>,
-    max_fix_retries: u32,
-    echo_result: bool,
-    tid: u64,
+/// Agent 状态消息（HTML）：标题 + 每一步的命令与结果图标，未完成的步骤显示 ⏳。
+fn format_agent_status(header: &str, steps: &[TaskCommand], results: &[CommandResult]) -> String {
+    let mut msg = format!("{}\n", escape_html(header));
+    for (i, step) in steps.iter().enumerate() {
+        let icon = match results.get(i) {
+            Some(r) if r.policy_rule.is_some() => "🚫",
+            Some(r) if r.interrupted == Some(Interruption::Cancelled) => "🛑",
+            Some(r) if r.interrupted == Some(Interruption::TimedOut) => "⌛",
+            Some(r) if r.success => "✅",
+            Some(_) => "❌",
+            None => "⏳",
+        };
+        msg.push_str(&format!(
+            "{}. {icon} {} → <code>{}</code>\n",
+            i + 1,
+            escape_html(&step.description),
+            escape_html(&truncate_str(&step.command, 100))
+        ));
+    }
+    msg
+}
+
+/// Agent 模式的「观察-行动」循环：第一步使用分类给出的第一条命令，其余计划作为参考写入 Agent 提示词；
+/// 此后每执行一步都把结果反馈给 LLM，由其决定下一条命令或给出最终回答。状态消息随每一步实时更新；
+/// 超出步数或时间预算时要求 LLM 直接给出结论。每一步同样经过策略检查与审批。
+async fn run_agent(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    user_message: &ChatMessage,
+    prompt_suffix: Option<&str>,
+    history: &[ChatMessage],
+    plan: Vec<TaskCommand>,
 ) {
-    let tag = format!("#{tid}");
+    let (bot, state, chat_id, tag) = (&task.bot, &task.state, task.chat_id, task.tag.as_str());
+    let config = &state.agent;
+    let mut plan = plan.into_iter();
+    let first = plan.next();
+    let rest: Vec<TaskCommand> = plan.collect();
+    let plan_suffix;
+    let prompt_suffix = if rest.is_empty() {
+        prompt_suffix
+    } else {
+        tlog!(tag, "初始计划共 {} 条，先执行第一条，其余 {} 条交给 Agent 参考", rest.len() + 1, rest.len());
+        let mut suffix = prompt_suffix.unwrap_or_default().to_string();
+        suffix.push_str("\n\n分类阶段给出的初始计划中，第一条命令会先执行；其余步骤如下，仅供参考，请根据实际结果决定是否执行或调整：\n");
+        for (i, c) in rest.iter().enumerate() {
+            suffix.push_str(&format!("{}. {} — {}\n", i + 2, c.command, c.description));
+        }
+        plan_suffix = suffix;
+        Some(plan_suffix.as_str())
+    };
+    let budget = Duration::from_secs(config.max_secs);
+    let start = Instant::now();
+    let mut msg_id = status_msg_id;
+    let mut steps: Vec<TaskCommand> = Vec::new();
+    let mut results: Vec<CommandResult> = Vec::new();
+    let mut next = first;
+
+    let answer = loop {
+        if task.handle.is_cancelled() {
+            tlog!(tag, "任务已取消，Agent 停止");
+            break "🛑 任务已取消".to_string();
+        }
+        let cmd = match next.take() {
+            Some(c) => c,
+            None => {
+                let exhausted = steps.len() as u32 >= config.max_steps || start.elapsed() >= budget;
+                if exhausted {
+                    tlog!(tag, "Agent 预算用尽 ({} 步, {:.0}s)，要求给出结论", steps.len(), start.elapsed().as_secs_f64());
+                }
+                let header = format!("🤖 Agent 思考下一步...（已执行 {} 步）", steps.len());
+                let status = format_agent_status(&header, &steps, &results);
+                msg_id = task.update_status(msg_id, &status).await;
+                let observed: Vec<AgentStep> = steps
+                    .iter()
+                    .zip(&results)
+                    .map(|(s, r)| AgentStep {
+                        command: &r.command,
+                        description: &s.description,
+                        exit_code: r.exit_code,
+                        stdout: &r.stdout,
+                        stderr: &r.stderr,
+                    })
+                    .collect();
+                let step = state.llm.agent_step(
+                    &task.llm_calls,
+                    user_message,
+                    prompt_suffix,
+                    history,
+                    &observed,
+                    config.output_chars,
+                    exhausted,
+                );
+                match task.with_llm_status(msg_id, &status, step).await {
+                    Ok(AgentAction::Final { answer }) => break answer,
+                    Ok(AgentAction::Run { command, description }) => TaskCommand { command, description },
+                    Err(e) => {
+                        tlog!(tag, "Agent LLM 调用失败: {}", e);
+                        break format!("❌ LLM 调用失败，Agent 已停止: {e}");
+                    }
+                }
+            }
+        };
+
+        let cmds = vec![cmd];
+        let cmds = if task.needs_approval(&cmds) {
+            match request_approval(task, msg_id, "🤖 Agent 下一步", cmds).await {
+                Some(c) => c,
+                None => {
+                    tlog!(tag, "Agent 下一步未获批准，停止");
+                    break "❌ 下一步命令未获批准，Agent 已停止".to_string();
+                }
+            }
+        } else {
+            cmds
+        };
+        for cmd in cmds {
+            tlog!(tag, "Agent 第 {} 步: {} → {}", steps.len() + 1, cmd.description, truncate_str(&cmd.command, 80));
+            steps.push(cmd.clone());
+            let header = format!(
+                "🤖 Agent 执行中（第 {}/{} 步，已用 {:.0}s）",
+                steps.len(),
+                config.max_steps,
+                start.elapsed().as_secs_f64()
+            );
+            let status = format_agent_status(&header, &steps, &results);
+            msg_id = task.update_status(msg_id, &status).await;
+            let result = match run_streamed(task, msg_id, status.trim_end(), &cmd).await {
+                Ok(r) => r,
+                Err(e) => {
+                    tlog!(tag, "命令异常: {}", e);
+                    CommandResult::failed(cmd.command.clone(), e.to_string())
+                }
+            };
+            results.push(result);
+        }
+    };
+
+    tlog!(tag, "Agent 完成 ({} 步, 耗时 {:.2}s): {}", steps.len(), start.elapsed().as_secs_f64(), truncate_str(&answer, 200));
+    let header = format!("🤖 Agent 完成（{} 步，耗时 {:.0}s）", steps.len(), start.elapsed().as_secs_f64());
+    let summary = format!(
+        "{}\n💬 {}",
+        format_agent_status(&header, &steps, &results),
+        markdown_to_html(answer.trim())
+    );
+    edit_or_send_long(bot, chat_id, msg_id, &summary).await;
+    let outcome = if task.handle.is_cancelled() { TaskOutcome::Cancelled } else { TaskOutcome::Completed };
+    task.finish(outcome, &html_to_plain(&summary));
+    task.note(|r| r.commands = steps);
+
+    let mut memory = state.conversations.describe_results(&results);
+    memory.push_str(&format!("结论：{}", answer.trim()));
+    remember(task, &user_message.content, &memory).await;
+}
+
+/// 下载消息附带的图片并编码为 data URL
+async fn load_images(bot: &Bot, images: &[ImageAttachment]) -> Result<Vec<String>> {
+    let mut urls = Vec::with_capacity(images.len());
+    for image in images {
+        let bytes = download_telegram_file(bot, &image.file_id).await?;
+        urls.push(format!("data:{};base64,{}", image.mime, BASE64.encode(&bytes)));
+    }
+    Ok(urls)
+}
+
+async fn process_message(task: &TaskContext, text: String, images: Vec<ImageAttachment>) {
+    let (bot, state, chat_id, tid) = (&task.bot, &task.state, task.chat_id, task.tid);
+    let llm = &state.llm;
+    let executor = &state.executor;
+    let skills = &task.skills;
+    let max_fix_retries = state.max_fix_retries;
+    let tag = task.tag.clone();
     let total_start = Instant::now();
     tlog!(&tag, "开始处理: {}", text);
 
     tlog!(&tag, "发送「正在分析」提示...");
     let status_msg_id = bot.send_message(chat_id, "🔄 正在分析...")
+        .reply_markup(tasks::cancel_keyboard(tid))
         .await
         .ok()
         .map(|m| m.id);
     tlog!(&tag, "状态消息 ID: {:?}", status_msg_id);
 
-    let prompt_suffix = skills::build_prompt_section(skills.as_slice());
+    // viewer 只能提问，不需要技能
+    let relevant = if !task.role.can_run_commands() {
+        Vec::new()
+    } else if state.retriever.enabled() {
+        match state.retriever.select(skills, &text, &task.llm_calls, &tag).await {
+            Ok(selected) => selected,
+            Err(e) => {
+                tlog!(&tag, "技能检索失败，改按触发关键词选择: {:#}", e);
+                skills::select_for_message(skills, &text)
+            }
+        }
+    } else {
+        skills::select_for_message(skills, &text)
+    };
+    let prompt_suffix = skills::build_prompt_section(&relevant);
     let prompt_suffix_opt = if prompt_suffix.is_empty() {
-        tlog!(&tag, "未使用 skills（无技能或未加载）");
+        tlog!(&tag, "未使用 skills（无相关技能或未加载）");
         None
     } else {
-        tlog!(&tag, "使用 {} 个 skills 注入提示 ({} 字符)", skills.len(), prompt_suffix.len());
+        tlog!(
+            &tag,
+            "使用 {}/{} 个 skills 注入提示 ({} 字符): {:?}",
+            relevant.len(),
+            skills.len(),
+            prompt_suffix.len(),
+            relevant.iter().map(|s| s.id.as_str()).collect::<Vec<_>>()
+        );
         Some(prompt_suffix.as_str())
     };
 
+    let image_urls = match load_images(bot, &images).await {
+        Ok(urls) => urls,
+        Err(e) => {
+            tlog!(&tag, "图片下载失败: {}", e);
+            let reply = format!("❌ 图片下载失败: {e}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+            return;
+        }
+    };
+    if !image_urls.is_empty() {
+        tlog!(&tag, "已下载 {} 张图片", image_urls.len());
+    }
+    let user_message = ChatMessage::user_with_images(text.clone(), image_urls);
+
+    let history = state.conversations.history(chat_id.0);
     tlog!(&tag, "调用 LLM...");
     let llm_start = Instant::now();
-    let intent = match llm.classify(&text, prompt_suffix_opt).await {
+    // 只能提问的角色不做意图分类：命令与技能意图反正会被拒绝，不为此消耗 token 与配额
+    let classify = async {
+        if task.role.can_run_commands() {
+            llm.classify(&task.llm_calls, &user_message, prompt_suffix_opt, &history, &relevant).await
+        } else {
+            tlog!(&tag, "角色 {} 只能提问，跳过意图分类", task.role.name());
+            let content = llm.answer(&task.llm_calls, &user_message, &history).await?;
+            Ok(LlmIntent::Question { content })
+        }
+    };
+    let intent = match task.with_llm_status(status_msg_id, "🔄 正在分析...", classify).await {
         Ok(intent) => intent,
         Err(e) => {
             tlog!(&tag, "LLM 失败 (耗时 {:.2}s): {}", llm_start.elapsed().as_secs_f64(), e);
             error!(err = %e, "LLM 调用失败");
-            edit_or_send(&bot, chat_id, status_msg_id, &format!("❌ LLM 调用失败: {e}")).await;
+            let reply = format!("❌ LLM 调用失败: {e}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
             return;
         }
     };
     tlog!(&tag, "LLM 完成 (耗时 {:.2}s)", llm_start.elapsed().as_secs_f64());
+    task.note(|r| r.intent = Some(intent.clone()));
+    if task.handle.is_cancelled() {
+        tlog!(&tag, "任务已取消");
+        edit_or_send(bot, chat_id, status_msg_id, "🛑 任务已取消").await;
+        task.finish(TaskOutcome::Cancelled, "🛑 任务已取消");
+        return;
+    }
 
     match intent {
         LlmIntent::Question { content } => {
@@ -487,9 +1622,17 @@ async fn process_message(
             } else {
                 content
             };
-            tlog!(&tag, "问答回复: {}", truncate(&reply, 200));
-            edit_or_send(&bot, chat_id, status_msg_id, &reply).await;
+            tlog!(&tag, "问答回复: {}", truncate_str(&reply, 200));
+            edit_or_send_long(bot, chat_id, status_msg_id, &markdown_to_html(&reply)).await;
             tlog!(&tag, "回答已发送（覆盖状态消息）");
+            task.finish(TaskOutcome::Answered, &reply);
+            remember(task, &text, &reply).await;
+        }
+        LlmIntent::Download { path } => {
+            send_requested_file(task, status_msg_id, &text, &path).await;
+        }
+        LlmIntent::Skill { id, args } => {
+            run_skill(task, status_msg_id, &text, &id, &args).await;
         }
         LlmIntent::Command { commands } => {
             let commands: Vec<TaskCommand> = commands
@@ -500,160 +1643,263 @@ async fn process_message(
                 })
                 .collect();
 
-            if commands.is_empty() {
-                tlog!(&tag, "无需执行命令");
-                edit_or_send(&bot, chat_id, status_msg_id, "ℹ️ 该消息不需要执行任何命令").await;
+            if !task.role.can_run_commands() {
+                tlog!(&tag, "角色 {} 无权执行命令，已拒绝", task.role.name());
+                let refusal = format!("🚫 你的角色（{}）只能提问，无权在服务器上执行命令", task.role.name());
+                edit_or_send(bot, chat_id, status_msg_id, &refusal).await;
+                task.finish(TaskOutcome::Refused, &refusal);
                 return;
             }
 
-            let plan: String = commands
-                .iter()
-                .enumerate()
-                .map(|(i, c)| format!("{}. {} → `{}`", i + 1, c.description, truncate(&c.command, 100)))
-                .collect::<Vec<_>>()
-                .join("\n");
-            tlog!(&tag, "执行计划:\n{}", plan);
-            let plan_text = format!("📝 执行计划:\n{plan}\n\n⏳ 执行中...");
-            edit_or_send(&bot, chat_id, status_msg_id, &plan_text).await;
+            if state.agent.enabled {
+                run_agent(task, status_msg_id, &user_message, prompt_suffix_opt, &history, commands).await;
+                deliver_artifacts(task).await;
+                tlog!(&tag, "处理完毕 (总耗时 {:.2}s)", total_start.elapsed().as_secs_f64());
+                return;
+            }
 
-            let exec_start = Instant::now();
-            let (results, extra_doc_paths) = if !commands.is_empty()
-                && commands[0].command.trim_start().starts_with("ppt-generator ")
-                && parse_ppt_generator_args(&commands[0].command).is_some()
-            {
-                let (title, content) = parse_ppt_generator_args(&commands[0].command).unwrap();
-                tlog!(&tag, "使用 LLM 直接生成 PPT HTML（不依赖 Python 模块）");
-                match llm.generate_ppt_html(&content).await {
-                    Ok(html) => {
-                        let path = "/tmp/slides.html";
-                        if let Err(e) = std::fs::write(path, &html) {
-                            tlog!(&tag, "写入 HTML 失败: {}", e);
-                            (
-                                vec![CommandResult {
-                                    command: commands[0].command.clone(),
-                                    success: false,
-                                    exit_code: None,
-                                    stdout: String::new(),
-                                    stderr: format!("写入文件失败: {e}"),
-                                }],
-                                vec![],
-                            )
-                        } else {
-                            tlog!(&tag, "已保存到 {}", path);
-                            (
-                                vec![CommandResult {
-                                    command: format!("LLM 生成乔布斯风 HTML 演示稿（{}）", title),
-                                    success: true,
-                                    exit_code: Some(0),
-                                    stdout: format!("已生成并保存到 {path}"),
-                                    stderr: String::new(),
-                                }],
-                                vec![path.to_string()],
-                            )
-                        }
-                    }
-                    Err(e) => {
-                        tlog!(&tag, "LLM 生成 PPT 失败: {}", e);
-                        (
-                            vec![CommandResult {
-                                command: commands[0].command.clone(),
-                                success: false,
-                                exit_code: None,
-                                stdout: String::new(),
-                                stderr: e.to_string(),
-                            }],
-                            vec![],
-                        )
-                    }
-                }
-            } else if commands.len() >= 2
-                && is_list_avfoundation_devices(&commands[0].command)
-                && is_avfoundation_record(&commands[1].command)
-            {
-                tlog!(&tag, "录屏前先列出 avfoundation 设备...");
-                match executor.run_command(&commands[0].command).await {
-                    Ok(r0) => {
-                        let screen_index = parse_avfoundation_screen_index(&r0.stdout);
-                        let mut rest = commands[1..].to_vec();
-                        if let Some(idx) = screen_index {
-                            tlog!(&tag, "解析到屏幕设备索引: {}", idx);
-                            rest[0].command = replace_avfoundation_device_index(&rest[0].command, idx);
-                            tlog!(&tag, "已替换录屏命令设备号: {}", rest[0].command);
-                        } else {
-                            tlog!(&tag, "未解析到 Capture screen 索引，使用原录屏命令");
-                        }
-                        let rest_results =
-                            run_commands_with_fix_retry(&executor, &llm, skills.as_slice(), &rest, max_fix_retries, &tag).await;
-                        let mut all = vec![r0];
-                        all.extend(rest_results);
-                        (all, vec![])
-                    }
-                    Err(e) => {
-                        tlog!(&tag, "列出设备失败，按原计划执行: {}", e);
-                        (
-                            run_commands_with_fix_retry(&executor, &llm, skills.as_slice(), &commands, max_fix_retries, &tag).await,
-                            vec![],
-                        )
+            let plan = format_plan(&commands, executor, task.role.is_admin());
+            tlog!(&tag, "执行计划:\n{}", html_to_plain(&plan));
+            let commands = if task.needs_approval(&commands) {
+                match request_approval(task, status_msg_id, "📝 执行计划", commands).await {
+                    Some(c) => c,
+                    None => {
+                        tlog!(&tag, "执行计划未获批准，取消执行");
+                        let outcome = if task.handle.is_cancelled() { TaskOutcome::Cancelled } else { TaskOutcome::Rejected };
+                        task.finish(outcome, "执行计划未获批准");
+                        return;
                     }
                 }
             } else {
-                tlog!(&tag, "开始执行命令... (失败时最多修正重试 {} 次)", max_fix_retries);
-                (
-                    run_commands_with_fix_retry(&executor, &llm, skills.as_slice(), &commands, max_fix_retries, &tag).await,
-                    vec![],
-                )
+                let plan_text = format!("📝 执行计划:\n{plan}\n\n⏳ 执行中...");
+                task.update_status(status_msg_id, &plan_text).await;
+                commands
             };
+
+            task.note(|r| r.commands = commands.clone());
+            let exec_start = Instant::now();
+            tlog!(&tag, "开始执行命令... (失败时最多修正重试 {} 次)", max_fix_retries);
+            let results = run_commands_with_fix_retry(task, status_msg_id, &commands).await;
             tlog!(&tag, "命令执行完毕 ({} 条, 耗时 {:.2}s)", results.len(), exec_start.elapsed().as_secs_f64());
+            report_results(task, status_msg_id, &text, &commands, &results).await;
+        }
+    }
 
-            let mut report = format_results(&commands, &results);
-            if let Some(failed) = results.last().filter(|r| !r.success) {
-                tlog!(&tag, "最终仍失败，附加一次解决建议到报告");
-                let fix_context = skills::build_relevant_context_for_fix(skills.as_slice(), &failed.command);
-                match llm.ask_fix_for_failure(&failed.command, failed.exit_code, &failed.stderr, Some(&fix_context)).await {
-                    Ok(suggestion) => {
-                        let suggestion_trim = truncate(suggestion.trim(), 1500);
-                        report.push_str(&format!("\n💡 解决建议：\n{suggestion_trim}"));
-                    }
-                    Err(e) => {
-                        report.push_str(&format!("\n⚠️ 获取解决建议失败: {e}"));
-                    }
-                }
-            }
+    tlog!(&tag, "处理完毕 (总耗时 {:.2}s)", total_start.elapsed().as_secs_f64());
+}
 
-            if echo_result {
-                edit_or_send(&bot, chat_id, status_msg_id, &report).await;
-                tlog!(&tag, "报告已发送（覆盖状态消息）");
-            }
+/// 命令执行后的收尾：写入对话记忆，最终失败时附加一次解决建议，发送报告、记录结局并发送产物。
+async fn report_results(
+    task: &TaskContext,
+    status_msg_id: Option<MessageId>,
+    text: &str,
+    commands: &[TaskCommand],
+    results: &[CommandResult],
+) {
+    let (state, tag) = (&task.state, task.tag.as_str());
+    let (llm, skills) = (&state.llm, &task.skills);
+    remember(task, text, &state.conversations.describe_results(results)).await;
 
-            let images = find_images_in_results(&results);
-            if !images.is_empty() {
-                tlog!(&tag, "发现 {} 个图片", images.len());
-                send_images(&bot, chat_id, &images, tid).await;
+    let mut suggestion = String::new();
+    if let Some(failed) = results
+        .last()
+        .filter(|r| !r.success && r.interrupted != Some(Interruption::Cancelled))
+    {
+        tlog!(tag, "最终仍失败，附加一次解决建议到报告");
+        let fix_context = skills::build_relevant_context_for_fix(skills.as_slice(), &failed.command);
+        let fix = llm.ask_fix_for_failure(&task.llm_calls, &failed.command, failed.exit_code, &failed.stderr, Some(&fix_context));
+        match task.with_llm_status(status_msg_id, "💡 正在获取解决建议...", fix).await {
+            Ok(s) => {
+                let suggestion_trim = truncate_str(s.trim(), 1500);
+                suggestion = format!("\n💡 解决建议：\n{}", markdown_to_html(&suggestion_trim));
             }
-            let videos = find_videos_in_results(&results);
-            if !videos.is_empty() {
-                tlog!(&tag, "发现 {} 个视频", videos.len());
-                send_videos(&bot, chat_id, &videos, tid).await;
-            }
-            for path in &extra_doc_paths {
-                send_document(&bot, chat_id, path, tid).await;
+            Err(e) => {
+                suggestion = format!("\n⚠️ 获取解决建议失败: {}", escape_html(&e.to_string()));
             }
         }
     }
 
-    tlog!(&tag, "处理完毕 (总耗时 {:.2}s)", total_start.elapsed().as_secs_f64());
+    let report = if state.echo_result {
+        let report = send_report(task, status_msg_id, commands, results, &suggestion).await;
+        tlog!(tag, "报告已发送（覆盖状态消息）");
+        report
+    } else {
+        html_to_plain(&format!("{}{suggestion}", format_results(commands, results, false)))
+    };
+    let outcome = match results.last() {
+        Some(r) if r.interrupted == Some(Interruption::Cancelled) => TaskOutcome::Cancelled,
+        Some(r) if !r.success => TaskOutcome::Failed,
+        _ => TaskOutcome::Completed,
+    };
+    task.finish(outcome, &report);
+
+    deliver_artifacts(task).await;
+}
+
+/// 消息附带的图片（照片或图片类文件）
+struct ImageAttachment {
+    file_id: String,
+    mime: String,
+    size: u32,
+}
+
+/// 取出消息中的图片：照片取最大尺寸，文件须为 image/* 类型
+fn image_attachment(msg: &Message) -> Option<ImageAttachment> {
+    if let Some(photo) = msg.photo().and_then(|sizes| sizes.iter().max_by_key(|p| p.width * p.height)) {
+        return Some(ImageAttachment {
+            file_id: photo.file.id.clone(),
+            mime: "image/jpeg".to_string(),
+            size: photo.file.size,
+        });
+    }
+    let doc = msg.document()?;
+    let mime = doc.mime_type.as_ref().filter(|m| m.type_().as_str() == "image")?;
+    Some(ImageAttachment {
+        file_id: doc.file.id.clone(),
+        mime: mime.essence_str().to_string(),
+        size: doc.file.size,
+    })
+}
+
+/// 消息附带的语音或音频文件
+struct VoiceAttachment {
+    file_id: String,
+    /// 发给转写接口的文件名，接口据扩展名判断格式
+    file_name: String,
+    size: u32,
+}
+
+/// 取出消息中的语音消息（ogg/opus）或音频文件
+fn voice_attachment(msg: &Message) -> Option<VoiceAttachment> {
+    if let Some(voice) = msg.voice() {
+        return Some(VoiceAttachment {
+            file_id: voice.file.id.clone(),
+            file_name: "voice.ogg".to_string(),
+            size: voice.file.size,
+        });
+    }
+    let audio = msg.audio()?;
+    let file_name = audio.file_name.clone().unwrap_or_else(|| {
+        let ext = match audio.mime_type.as_ref().map(|m| m.subtype().as_str().to_string()).as_deref() {
+            Some("mpeg") | None => "mp3".to_string(),
+            Some("x-m4a") | Some("mp4") => "m4a".to_string(),
+            Some(other) => other.trim_start_matches("x-").to_string(),
+        };
+        format!("audio.{ext}")
+    });
+    Some(VoiceAttachment {
+        file_id: audio.file.id.clone(),
+        file_name,
+        size: audio.file.size,
+    })
+}
+
+/// 消息附带的文件（非图片的 document）
+struct UploadAttachment {
+    file_id: String,
+    file_name: String,
+    size: u32,
+}
+
+/// 取出消息中的文件；图片类文件由 `image_attachment` 处理
+fn upload_attachment(msg: &Message) -> Option<UploadAttachment> {
+    let doc = msg.document()?;
+    if doc.mime_type.as_ref().is_some_and(|m| m.type_().as_str() == "image") {
+        return None;
+    }
+    Some(UploadAttachment {
+        file_id: doc.file.id.clone(),
+        file_name: doc.file_name.clone().unwrap_or_else(|| "file".to_string()),
+        size: doc.file.size,
+    })
+}
+
+/// 下载用户上传的文件并保存到本聊天的上传目录，把保存路径附加到消息文字中供 LLM 引用；
+/// 无权限或失败时报告并结束任务，返回 None
+async fn receive_upload(task: &TaskContext, upload: &UploadAttachment, text: String) -> Option<String> {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, &task.tag);
+    if !task.role.can_run_commands() {
+        tlog!(tag, "角色 {} 无权上传文件，已拒绝", task.role.name());
+        let refusal = format!("🚫 你的角色（{}）只能提问，无权上传文件", task.role.name());
+        bot.send_message(chat_id, &refusal).await.ok();
+        task.finish(TaskOutcome::Refused, &refusal);
+        return None;
+    }
+    let status_msg_id = bot.send_message(chat_id, "📥 正在接收文件...").await.ok().map(|m| m.id);
+    let result = match download_telegram_file(bot, &upload.file_id).await {
+        Ok(bytes) => task.state.uploads.save(chat_id.0, &upload.file_name, &bytes),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(path) => {
+            let path = path.display().to_string();
+            tlog!(tag, "文件已保存: {} ({} 字节)", path, upload.size);
+            edit_or_send(bot, chat_id, status_msg_id, &format!("📥 已保存到 <code>{}</code>", escape_html(&path))).await;
+            let text = format!("{text}\n\n[用户上传的文件「{}」已保存到服务器: {path}]", upload.file_name);
+            task.note(|r| r.text = text.clone());
+            Some(text)
+        }
+        Err(e) => {
+            tlog!(tag, "文件接收失败: {:#}", e);
+            let reply = format!("❌ 文件接收失败: {e:#}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+            None
+        }
+    }
+}
+
+/// 下载并转写语音，回显转写结果；失败时报告错误并结束任务，返回 None
+async fn transcribe_voice(task: &TaskContext, voice: &VoiceAttachment) -> Option<String> {
+    let (bot, chat_id, tag) = (&task.bot, task.chat_id, &task.tag);
+    let status_msg_id = bot.send_message(chat_id, "🎙 正在转写语音...").await.ok().map(|m| m.id);
+    let start = Instant::now();
+    let result = match download_telegram_file(bot, &voice.file_id).await {
+        Ok(bytes) => {
+            let opts = task.run_options();
+            task.state.stt.transcribe(bytes, &voice.file_name, &task.state.executor, &opts).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(text) if !text.is_empty() => {
+            tlog!(tag, "语音转写完成 (耗时 {:.2}s): {}", start.elapsed().as_secs_f64(), text);
+            edit_or_send_long(bot, chat_id, status_msg_id, &format!("🎙 {}", escape_html(&text))).await;
+            task.note(|r| r.text = text.clone());
+            Some(text)
+        }
+        Ok(_) => {
+            tlog!(tag, "语音转写结果为空");
+            let reply = "⚠️ 没有识别出语音内容";
+            edit_or_send(bot, chat_id, status_msg_id, reply).await;
+            task.finish(TaskOutcome::Error, reply);
+            None
+        }
+        Err(e) => {
+            tlog!(tag, "语音转写失败: {:#}", e);
+            let reply = format!("❌ 语音转写失败: {e:#}");
+            edit_or_send(bot, chat_id, status_msg_id, &escape_html(&reply)).await;
+            task.finish(TaskOutcome::Error, &reply);
+            None
+        }
+    }
+}
+
+/// 通过 Bot API 下载文件到内存
+async fn download_telegram_file(bot: &Bot, file_id: &str) -> Result<Vec<u8>> {
+    let file = bot.get_file(file_id).await.context("获取文件信息失败")?;
+    let mut bytes = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut bytes)
+        .await
+        .context("下载文件失败")?;
+    Ok(bytes)
 }
 
 async fn handle_message(
     bot: Bot,
     msg: Message,
     me: teloxide::types::Me,
-    llm: Arc<LlmClient>,
-    executor: Arc<Executor>,
-    skills: Arc<Vec<skills::Skill>>,
-    max_fix_retries: u32,
-    allowed_chats: Vec<i64>,
-    echo_result: bool,
+    state: Arc<BotState>,
 ) -> ResponseResult<()> {
     if let Some(from_user) = &msg.from {
         if from_user.id == me.id {
@@ -682,88 +1928,332 @@ async fn handle_message(
 
     tlog!(&tag, "========================================");
     tlog!(&tag, "chat_id: {}, 发送者: {}", chat_id.0, from);
-    tlog!(&tag, "内容: {:?}", msg.text().unwrap_or("<非文本消息>"));
+    tlog!(&tag, "内容: {:?}", msg.text().or(msg.caption()).unwrap_or("<非文本消息>"));
     tlog!(&tag, "========================================");
 
-    if !allowed_chats.is_empty() && !allowed_chats.contains(&chat_id.0) {
+    if !state.chat_allowed(chat_id) {
         tlog!(&format!("权限 #{tid}"), "chat_id {} 不在允许列表中，已忽略", chat_id.0);
         return Ok(());
     }
 
-    let text = match msg.text() {
+    let images: Vec<ImageAttachment> = image_attachment(&msg).into_iter().collect();
+    if let Some(too_big) = images.iter().find(|i| i.size > MAX_IMAGE_BYTES) {
+        tlog!(&tag, "图片过大 ({} 字节)，已忽略", too_big.size);
+        bot.send_message(chat_id, format!("⚠️ 图片过大（超过 {} MB），无法处理", MAX_IMAGE_BYTES / 1024 / 1024))
+            .await?;
+        return Ok(());
+    }
+    // 语音在后台任务中转写，转写结果替代消息文本；未启用转写时照旧忽略
+    let voice = voice_attachment(&msg).filter(|_| state.stt.enabled());
+    if let Some(too_big) = voice.as_ref().filter(|v| v.size > MAX_VOICE_BYTES) {
+        tlog!(&tag, "语音过大 ({} 字节)，已忽略", too_big.size);
+        bot.send_message(chat_id, format!("⚠️ 语音过大（超过 {} MB），无法处理", MAX_VOICE_BYTES / 1024 / 1024))
+            .await?;
+        return Ok(());
+    }
+    let upload = upload_attachment(&msg).filter(|_| state.uploads.enabled());
+    if let Some(too_big) = upload.as_ref().filter(|u| u.size > state.uploads.max_bytes()) {
+        tlog!(&tag, "文件过大 ({} 字节)，已忽略", too_big.size);
+        bot.send_message(chat_id, format!("⚠️ 文件过大（超过 {} MB），无法接收", state.uploads.max_bytes() / 1024 / 1024))
+            .await?;
+        return Ok(());
+    }
+    let text = match msg.text().or(msg.caption()) {
         Some(t) => t.to_string(),
+        None if !images.is_empty() => DEFAULT_IMAGE_PROMPT.to_string(),
+        None if voice.is_some() => "🎙 语音消息".to_string(),
+        None if upload.is_some() => DEFAULT_UPLOAD_PROMPT.to_string(),
         None => return Ok(()),
     };
 
-    info!(chat_id = chat_id.0, text = %text, tid = tid, "收到消息");
+    let role = state.auth.role_of(&msg);
+    if role == Role::None {
+        tlog!(&format!("权限 #{tid}"), "{} 无任何角色，已拒绝", from);
+        bot.send_message(chat_id, "🚫 你没有使用此 bot 的权限").await?;
+        return Ok(());
+    }
+
+    if let Some(reply_to) = msg.reply_to_message().filter(|_| voice.is_none()) {
+        if let Some(approval_id) = state.approvals.edit_target(chat_id, reply_to.id) {
+            if !state.approvals.is_approver(msg.from.as_ref().map(|u| u.id), role) {
+                tlog!(&format!("审批 #{tid}"), "{} 无审批权限，忽略修改", from);
+                bot.send_message(chat_id, "🚫 你没有审批权限").await?;
+                return Ok(());
+            }
+            let commands = approval::parse_edited_commands(&text);
+            tlog!(&format!("审批 #{tid}"), "收到对审批 #{} 的修改: {} 条命令", approval_id, commands.len());
+            if !state.approvals.resolve(approval_id, ApprovalDecision::Edited { commands, by: from }) {
+                bot.send_message(chat_id, "⚠️ 该审批已失效").await?;
+            }
+            return Ok(());
+        }
+    }
+
+    if let Some((name, args)) = parse_bot_command(&text).filter(|_| voice.is_none()) {
+        if handle_bot_command(&bot, &state, &msg, role, &name, args, &format!("命令 #{tid}")).await? {
+            return Ok(());
+        }
+    }
+
+    let user_id = msg.from.as_ref().map(|u| u.id.0);
+    if let Some(uid) = user_id.filter(|_| !role.is_admin()) {
+        if let Err(e) = state.usage.check_quota(uid) {
+            tlog!(&format!("配额 #{tid}"), "{} {}", from, e);
+            bot.send_message(chat_id, format!("🚫 {e}，明天再来吧")).await?;
+            return Ok(());
+        }
+    }
+
+    info!(chat_id = chat_id.0, text = %text, tid = tid, role = role.name(), "收到消息");
 
+    let handle = state.tasks.register(tid, chat_id);
+    let record = TaskRecord::new(tid, chat_id.0, from, user_id, text.clone());
+    let task = TaskContext {
+        bot,
+        state: state.clone(),
+        chat_id,
+        tid,
+        tag: format!("#{tid}"),
+        role,
+        user_id: msg.from.as_ref().map(|u| u.id),
+        handle,
+        record: Mutex::new(record),
+        llm_calls: CallContext::new(chat_id.0, user_id, role.is_admin()),
+        skills: state.skills.get(),
+        artifacts: match ArtifactDir::create(&state.artifacts_root, tid) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                tlog!(&format!("产物 #{tid}"), "{:#}，本任务不提供 ${}", e, artifacts::ARTIFACTS_ENV);
+                None
+            }
+        },
+    };
     tokio::spawn(async move {
-        process_message(bot, chat_id, text, llm, executor, skills, max_fix_retries, echo_result, tid).await;
+        let text = match &voice {
+            Some(voice) => transcribe_voice(&task, voice).await,
+            None => Some(text),
+        };
+        let text = match (text, &upload) {
+            (Some(text), Some(upload)) => receive_upload(&task, upload, text).await,
+            (text, _) => text,
+        };
+        if let Some(text) = text {
+            process_message(&task, text, images).await;
+        }
+        state.tasks.remove(tid);
+        let mut record = task.record.into_inner().unwrap();
+        record.providers = task.llm_calls.providers();
+        record.elapsed_ms = (chrono::Local::now() - record.started_at).num_milliseconds().max(0) as u64;
+        if let Err(e) = state.history.append(&record) {
+            tlog!(&task.tag, "写入任务历史失败: {}", e);
+        }
     });
 
     tlog!(&format!("调度 #{tid}"), "已提交后台处理，立即返回接收下一条消息");
     Ok(())
 }
 
+/// 处理 inline 按钮回调：取消按钮终止对应任务；审批按钮中批准 / 拒绝直接提交结果，
+/// 修改则发出 ForceReply 提示等待用户回复新命令。
+async fn handle_callback(bot: Bot, q: CallbackQuery, state: Arc<BotState>) -> ResponseResult<()> {
+    // 与 handle_message 一样只处理允许的聊天；找不到所属消息时无法判断，按不允许处理
+    let chat_id = q.message.as_ref().map(|m| m.chat().id);
+    if !state.allowed_chats.is_empty() && !chat_id.is_some_and(|c| state.chat_allowed(c)) {
+        tlog!("权限", "回调来自不在允许列表中的聊天 {:?}，已忽略", chat_id.map(|c| c.0));
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    }
+    if let Some(tid) = q.data.as_deref().and_then(tasks::parse_callback_data) {
+        let role = state.auth.role_of_user(q.from.id);
+        let notice = match chat_id {
+            _ if !role.can_run_commands() => format!("🚫 你的角色（{}）无权取消任务", role.name()),
+            Some(chat_id) => cancel_task(&state, chat_id, tid, role, &q.from.first_name),
+            None => format!("⚠️ 任务 #{tid} 不存在或已结束"),
+        };
+        bot.answer_callback_query(q.id).text(notice).await?;
+        return Ok(());
+    }
+    let Some((id, action)) = q.data.as_deref().and_then(approval::parse_callback_data) else {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+    tlog!("审批", "{} ({}) 点击审批 #{}: {:?}", q.from.first_name, q.from.id, id, action);
+    // 审批 ID 是顺序编号，只接受来自审批项所在聊天的回调，避免在别的聊天伪造回调数据批准它
+    if chat_id.is_none() || state.approvals.chat_of(id) != chat_id {
+        tlog!("审批", "审批 #{} 不属于聊天 {:?}，已忽略", id, chat_id.map(|c| c.0));
+        bot.answer_callback_query(q.id).text("该审批已失效").await?;
+        return Ok(());
+    }
+    let role = state.auth.role_of_user(q.from.id);
+    if !state.approvals.is_approver(Some(q.from.id), role) {
+        bot.answer_callback_query(q.id).text("🚫 你没有审批权限").await?;
+        return Ok(());
+    }
+    if action == ApprovalAction::Approve && !state.approvals.can_approve(id, q.from.id, role) {
+        tlog!("审批", "{} 不能批准自己发起的任务 #{}", q.from.first_name, id);
+        bot.answer_callback_query(q.id).text("🚫 不能批准自己发起的任务，请由其他审批人或 admin 批准").await?;
+        return Ok(());
+    }
+
+    let by = q.from.first_name.clone();
+    let notice = match action {
+        ApprovalAction::Approve => {
+            if state.approvals.resolve(id, ApprovalDecision::Approved { by }) {
+                "已批准"
+            } else {
+                "该审批已失效"
+            }
+        }
+        ApprovalAction::Reject => {
+            if state.approvals.resolve(id, ApprovalDecision::Rejected { by }) {
+                "已拒绝"
+            } else {
+                "该审批已失效"
+            }
+        }
+        ApprovalAction::Edit => match state.approvals.chat_of(id) {
+            Some(chat_id) => {
+                match bot
+                    .send_message(chat_id, "✏️ 请回复本消息，输入修改后的命令（每行一条）")
+                    .reply_markup(ForceReply::new().selective())
+                    .await
+                {
+                    Ok(prompt) => {
+                        state.approvals.begin_edit(chat_id, prompt.id, id);
+                        "请回复提示消息输入新命令"
+                    }
+                    Err(e) => {
+                        tlog!("审批", "发送修改提示失败: {}", e);
+                        "发送修改提示失败"
+                    }
+                }
+            }
+            None => "该审批已失效",
+        },
+    };
+    bot.answer_callback_query(q.id).text(notice).await?;
+    Ok(())
+}
+
 pub async fn run(config: AppConfig) -> Result<()> {
     let bot = Bot::new(&config.telegram.bot_token);
-    let allowed_chats = config.telegram.allowed_chat_ids.clone();
-    let echo_result = config.executor.echo_result;
-    let max_fix_retries = config.executor.max_fix_retries;
 
-    let llm = Arc::new(LlmClient::new(config.llm.clone()));
-    let executor = Arc::new(Executor::new(config.executor.clone()));
-    let skills = Arc::new(skills::load_skills(config.skills_dir.as_deref()));
+    let history = HistoryStore::new(&config.history)?;
+    TASK_COUNTER.store(history.max_tid() + 1, Ordering::Relaxed);
+    let usage = Arc::new(UsageStore::new(&config.usage)?);
+    let executor = Executor::new(config.executor.clone(), &config.path)?;
+    let uploads = UploadStore::new(&config.uploads, executor.working_dir());
+    let (skills, skill_errors) = SkillSet::load(config.skills_dir.clone(), native_skills::builtins());
+    let state = Arc::new(BotState {
+        llm: LlmClient::new(config.llm.clone(), usage.clone()),
+        executor,
+        skills,
+        retriever: SkillRetriever::new(config.skill_retrieval.clone(), usage.clone()),
+        approvals: ApprovalRegistry::new(config.approval.clone()),
+        auth: Authorizer::new(&config.auth),
+        conversations: ConversationStore::new(config.memory.clone()),
+        tasks: TaskTable::new(),
+        history,
+        usage,
+        stt: SttClient::new(config.stt.clone()),
+        uploads,
+        artifacts_root: config
+            .executor
+            .artifacts_dir
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("rust-bot-artifacts")),
+        agent: config.agent.clone(),
+        allowed_chats: config.telegram.allowed_chat_ids.clone(),
+        admin_chat: config.telegram.admin_chat_id.map(ChatId),
+        max_fix_retries: config.executor.max_fix_retries,
+        echo_result: config.executor.echo_result,
+        report_file_threshold: config.executor.report_file_threshold,
+    });
 
     tlog!("启动", "开始监听 Telegram 消息...");
-    tlog!("启动", "Bot Token: {}...", truncate(&config.telegram.bot_token, 10));
+    tlog!("启动", "Bot Token: {}...", truncate_str(&config.telegram.bot_token, 10));
     tlog!("启动", "允许的聊天 ID: {:?}", &config.telegram.allowed_chat_ids);
-    tlog!("启动", "模型: {}", &config.llm.model);
+    for (i, p) in config.llm.provider_chain().iter().enumerate() {
+        tlog!("启动", "LLM 提供方 {}: {} ({} @ {})", i + 1, p.display_name(), p.model, p.base_url);
+    }
+    if !config.auth.users.is_empty() || !config.auth.channel_authors.is_empty() {
+        tlog!(
+            "启动",
+            "按角色授权: {} 个用户, {} 个频道署名",
+            config.auth.users.len(),
+            config.auth.channel_authors.len()
+        );
+    }
+    if config.memory.enabled {
+        tlog!("启动", "对话记忆: 最近 {} 轮 / 约 {} tokens", config.memory.max_turns, config.memory.max_tokens);
+    }
+    if let Some(path) = state.history.path() {
+        tlog!("启动", "任务历史: {} (下一个任务 #{})", path.display(), TASK_COUNTER.load(Ordering::Relaxed));
+    }
+    if let Some(path) = state.usage.path() {
+        tlog!("启动", "用量统计: {}", path.display());
+    }
+    if config.usage.daily_token_quota > 0 || !config.usage.user_quotas.is_empty() {
+        tlog!(
+            "启动",
+            "每日 token 配额: 默认 {}，{} 个用户单独配置",
+            config.usage.daily_token_quota,
+            config.usage.user_quotas.len()
+        );
+    }
+    tlog!("启动", "任务产物目录: {}/task-<tid> (${})", state.artifacts_root.display(), artifacts::ARTIFACTS_ENV);
+    if state.uploads.enabled() {
+        tlog!("启动", "文件上传目录: {} (上限 {} MB)", state.uploads.root().display(), config.uploads.max_size_mb);
+    }
+    if config.stt.enabled {
+        tlog!("启动", "语音转写: {} ({})", config.stt.base_url, config.stt.model);
+    }
+    if config.agent.enabled {
+        tlog!("启动", "Agent 模式: 最多 {} 步 / {}s", config.agent.max_steps, config.agent.max_secs);
+    }
+    tlog!("启动", "技能: {} 个 (目录 {})", state.skills.get().len(), state.skills.dir());
+    report_skill_errors(&bot, state.admin_chat, &skill_errors).await;
+    if config.skill_retrieval.enabled {
+        tlog!(
+            "启动",
+            "技能检索: {} @ {}，每条消息注入 top {}",
+            config.skill_retrieval.model,
+            config.skill_retrieval.base_url,
+            config.skill_retrieval.top_k
+        );
+    }
+    if config.skills_reload_secs > 0 {
+        tlog!("启动", "每 {}s 检查技能目录变化", config.skills_reload_secs);
+        tokio::spawn(watch_skills(bot.clone(), state.clone(), Duration::from_secs(config.skills_reload_secs)));
+    }
+    if config.approval.enabled {
+        tlog!("启动", "审批模式已开启 (超时 {}s, 审批人: {:?})", config.approval.timeout_secs, config.approval.approver_ids);
+    }
 
     let handler = dptree::entry()
         .branch(
             Update::filter_message().endpoint(
-                |bot: Bot,
-                 msg: Message,
-                 me: teloxide::types::Me,
-                 llm: Arc<LlmClient>,
-                 executor: Arc<Executor>,
-                 skills: Arc<Vec<skills::Skill>>,
-                 max_fix_retries: u32,
-                 allowed_chats: Vec<i64>,
-                 echo_result: bool| {
-                    handle_message(bot, msg, me, llm, executor, skills, max_fix_retries, allowed_chats, echo_result)
+                |bot: Bot, msg: Message, me: teloxide::types::Me, state: Arc<BotState>| {
+                    handle_message(bot, msg, me, state)
                 },
             ),
         )
         .branch(
             Update::filter_channel_post().endpoint(
-                |bot: Bot,
-                 msg: Message,
-                 me: teloxide::types::Me,
-                 llm: Arc<LlmClient>,
-                 executor: Arc<Executor>,
-                 skills: Arc<Vec<skills::Skill>>,
-                 max_fix_retries: u32,
-                 allowed_chats: Vec<i64>,
-                 echo_result: bool| {
-                    handle_message(bot, msg, me, llm, executor, skills, max_fix_retries, allowed_chats, echo_result)
+                |bot: Bot, msg: Message, me: teloxide::types::Me, state: Arc<BotState>| {
+                    handle_message(bot, msg, me, state)
                 },
             ),
+        )
+        .branch(
+            Update::filter_callback_query().endpoint(
+                |bot: Bot, q: CallbackQuery, state: Arc<BotState>| handle_callback(bot, q, state),
+            ),
         );
 
-    let llm_clone = llm.clone();
-    let executor_clone = executor.clone();
-
     let mut dp = Dispatcher::builder(bot.clone(), handler)
-        .dependencies(dptree::deps![
-            llm_clone,
-            executor_clone,
-            skills,
-            max_fix_retries,
-            allowed_chats,
-            echo_result
-        ])
+        .dependencies(dptree::deps![state])
         .default_handler(|upd| async move {
             tlog!("默认", "未匹配的更新: {:?}", upd.kind);
             warn!("未处理的更新: {:?}", upd.kind);
@@ -808,3 +2298,62 @@ pub async fn run(config: AppConfig) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16_len(s: &str) -> usize {
+        s.encode_utf16().count()
+    }
+
+    #[test]
+    fn markdown_to_html_escapes_entities() {
+        assert_eq!(
+            markdown_to_html("a < b & **粗体** `x<y`"),
+            "a &lt; b &amp; <b>粗体</b> <code>x&lt;y</code>"
+        );
+        assert_eq!(markdown_to_html("## 标题\n```\nif a<b {}\n```\n"), "<b>标题</b>\n<pre>if a&lt;b {}</pre>\n");
+        // 不成对的标记原样保留
+        assert_eq!(markdown_to_html("**a `b"), "**a `b");
+    }
+
+    #[test]
+    fn html_atoms_keep_tags_entities_and_chars_whole() {
+        assert_eq!(html_atoms("<b>x</b>&amp;😀"), ["<b>", "x", "</b>", "&amp;", "😀"]);
+        // 没有分号的 & 不算实体
+        assert_eq!(html_atoms("& 中"), ["&", " ", "中"]);
+    }
+
+    #[test]
+    fn split_inside_pre_reopens_block() {
+        let lines: Vec<String> = (0..10).map(|i| format!("line {i}")).collect();
+        let html = format!("<pre>{}</pre>", lines.join("\n"));
+        let chunks = split_message(&html, 40);
+        assert!(chunks.len() > 1);
+        let mut inner = Vec::new();
+        for chunk in &chunks {
+            assert!(utf16_len(chunk) <= 40, "超长: {chunk}");
+            assert!(chunk.starts_with("<pre>") && chunk.ends_with("</pre>"), "未补全 <pre>: {chunk}");
+            inner.push(chunk.trim_start_matches("<pre>").trim_end_matches("</pre>").trim_end().to_string());
+        }
+        assert_eq!(inner.join("\n"), lines.join("\n"));
+    }
+
+    #[test]
+    fn split_at_exact_utf16_limit() {
+        // limit 20 扣除 <pre></pre> 后每段预算为 9 个 UTF-16 码元，emoji 占 2 个
+        let exact = "你好😀😀你😀";
+        assert_eq!(utf16_len(exact), 9);
+        assert_eq!(split_message(exact, 20), [exact]);
+        let over = format!("{exact}好");
+        assert_eq!(split_message(&over, 20), [exact, "好"]);
+    }
+
+    #[test]
+    fn html_to_plain_fallback() {
+        assert_eq!(html_to_plain("<b>a &lt;b&gt; &amp;amp;</b>"), "a <b> &amp;");
+        let text = "x < y && \"q\" 中文";
+        assert_eq!(html_to_plain(&markdown_to_html(text)), text);
+    }
+}
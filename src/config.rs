@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
@@ -11,6 +12,387 @@ pub struct AppConfig {
     /// Skills 目录路径，用于加载扩展能力；留空或不存在则不使用 skills
     #[serde(default)]
     pub skills_dir: Option<String>,
+    /// 检查技能目录变化的间隔（秒），有变化时自动重新加载；0 表示只在启动与 /skills reload 时加载
+    #[serde(default = "default_skills_reload_secs")]
+    pub skills_reload_secs: u64,
+    /// 按 embedding 相似度只注入与消息最相关的 top-k 个技能（技能很多时使用）
+    #[serde(default)]
+    pub skill_retrieval: SkillRetrievalConfig,
+    /// 执行计划的人工审批（inline keyboard 批准/拒绝/修改）
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    /// 按用户分配角色的权限控制
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// 每个聊天的多轮对话记忆
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    /// Agent 模式：逐条执行命令并把结果反馈给 LLM，由其决定下一步
+    #[serde(default)]
+    pub agent: AgentConfig,
+    /// 任务历史持久化（/history、/task）
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// LLM token 用量统计、计价与每日配额（/usage）
+    #[serde(default)]
+    pub usage: UsageConfig,
+    /// 语音消息转写（OpenAI 兼容的 /audio/transcriptions 接口，如本地 whisper.cpp server）
+    #[serde(default)]
+    pub stt: SttConfig,
+    /// 接收用户发来的文件，保存到工作目录下按聊天划分的上传目录
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    /// 配置文件自身的路径（含 bot token 与 API key），由 `load` 填入
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+fn default_skills_reload_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SkillRetrievalConfig {
+    /// 是否启用；关闭时按技能的触发关键词选择
+    #[serde(default)]
+    pub enabled: bool,
+    /// OpenAI 兼容接口地址，请求发往 `{base_url}/embeddings`（本地服务亦可）
+    #[serde(default)]
+    pub base_url: String,
+    /// API Key，本地服务可留空
+    #[serde(default)]
+    pub api_key: String,
+    /// embedding 模型
+    #[serde(default = "default_embedding_model")]
+    pub model: String,
+    /// 每条消息注入的技能数（另加触发关键词命中的技能）
+    #[serde(default = "default_retrieval_top_k")]
+    pub top_k: usize,
+    /// 技能 embedding 的缓存文件，按内容哈希索引，技能未变化时不重复计算
+    #[serde(default = "default_embedding_cache")]
+    pub cache_path: String,
+    /// 单次请求超时（秒）
+    #[serde(default = "default_retrieval_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_string()
+}
+
+fn default_retrieval_top_k() -> usize {
+    5
+}
+
+fn default_embedding_cache() -> String {
+    "data/skill_embeddings.json".to_string()
+}
+
+fn default_retrieval_timeout() -> u64 {
+    30
+}
+
+impl Default for SkillRetrievalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            base_url: String::new(),
+            api_key: String::new(),
+            model: default_embedding_model(),
+            top_k: default_retrieval_top_k(),
+            cache_path: default_embedding_cache(),
+            timeout_secs: default_retrieval_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UploadConfig {
+    /// 是否接收文件（非图片的 document）；默认关闭，关闭时忽略这类消息
+    #[serde(default)]
+    pub enabled: bool,
+    /// 上传根目录（相对 executor.working_dir 或绝对路径），文件保存在 `<dir>/<chat_id>/` 下
+    #[serde(default = "default_upload_dir")]
+    pub dir: String,
+    /// 单个文件大小上限（MB）；Bot API 最多只能下载 20 MB
+    #[serde(default = "default_upload_max_mb")]
+    pub max_size_mb: u32,
+}
+
+fn default_upload_dir() -> String {
+    "uploads".to_string()
+}
+
+fn default_upload_max_mb() -> u32 {
+    20
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: default_upload_dir(),
+            max_size_mb: default_upload_max_mb(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SttConfig {
+    /// 是否转写语音 / 音频消息；关闭时忽略这类消息
+    #[serde(default)]
+    pub enabled: bool,
+    /// 接口地址，请求发往 `{base_url}/audio/transcriptions`
+    #[serde(default)]
+    pub base_url: String,
+    /// API Key，本地服务可留空（不发送 Authorization 头）
+    #[serde(default)]
+    pub api_key: String,
+    /// 模型名，如 whisper-1；whisper.cpp server 会忽略该字段
+    #[serde(default = "default_stt_model")]
+    pub model: String,
+    /// 语言提示（ISO-639-1，如 "zh"），留空自动识别
+    #[serde(default)]
+    pub language: Option<String>,
+    /// 单次转写请求超时（秒）
+    #[serde(default = "default_stt_timeout")]
+    pub timeout_secs: u64,
+    /// 总是先用 ffmpeg 转成 16 kHz 单声道 wav（whisper.cpp server 未开启 --convert 时需要）；
+    /// 关闭时仅对接口不支持的格式转码
+    #[serde(default)]
+    pub transcode: bool,
+    /// ffmpeg 可执行文件路径
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+}
+
+fn default_stt_model() -> String {
+    "whisper-1".to_string()
+}
+
+fn default_stt_timeout() -> u64 {
+    120
+}
+
+fn default_ffmpeg() -> String {
+    "ffmpeg".to_string()
+}
+
+impl Default for SttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            base_url: String::new(),
+            api_key: String::new(),
+            model: default_stt_model(),
+            language: None,
+            timeout_secs: default_stt_timeout(),
+            transcode: false,
+            ffmpeg: default_ffmpeg(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UsageConfig {
+    /// 是否把用量写入 JSONL 文件；关闭后只在内存中统计，重启清零
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 用量文件路径（相对启动目录或绝对路径）
+    #[serde(default = "default_usage_path")]
+    pub path: String,
+    /// 每个用户每日的 token 上限（按用户 ID 跨聊天统计），0 表示不限；admin 不受限
+    #[serde(default)]
+    pub daily_token_quota: u64,
+    /// 按用户覆盖每日 token 上限
+    #[serde(default)]
+    pub user_quotas: Vec<UserQuota>,
+    /// 模型单价（每百万 token），键为提供方配置中的模型名；未配置的模型不计费用
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    /// 费用显示的货币符号
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserQuota {
+    pub id: u64,
+    /// 该用户每日的 token 上限，0 表示不限
+    pub daily_tokens: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ModelPrice {
+    /// 输入（prompt）每百万 token 的价格
+    #[serde(default)]
+    pub prompt: f64,
+    /// 输出（completion）每百万 token 的价格
+    #[serde(default)]
+    pub completion: f64,
+}
+
+fn default_usage_path() -> String {
+    "data/usage.jsonl".to_string()
+}
+
+fn default_currency() -> String {
+    "$".to_string()
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: default_usage_path(),
+            daily_token_quota: 0,
+            user_quotas: Vec::new(),
+            prices: HashMap::new(),
+            currency: default_currency(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistoryConfig {
+    /// 是否把每个任务记录到 JSONL 文件，关闭后 /history、/task 不可用且任务 ID 重启后从 1 开始
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 历史文件路径（相对启动目录或绝对路径）
+    #[serde(default = "default_history_path")]
+    pub path: String,
+}
+
+fn default_history_path() -> String {
+    "data/history.jsonl".to_string()
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: default_history_path(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AgentConfig {
+    /// 开启后命令类消息走「观察-行动」循环，替代一次性执行计划
+    #[serde(default)]
+    pub enabled: bool,
+    /// 最多执行的命令步数
+    #[serde(default = "default_agent_max_steps")]
+    pub max_steps: u32,
+    /// 整个循环的最长耗时（秒），超出后要求 LLM 直接给出结论
+    #[serde(default = "default_agent_max_secs")]
+    pub max_secs: u64,
+    /// 反馈给 LLM 的每条 stdout/stderr 最大字节数
+    #[serde(default = "default_agent_output_chars")]
+    pub output_chars: usize,
+}
+
+fn default_agent_max_steps() -> u32 {
+    8
+}
+
+fn default_agent_max_secs() -> u64 {
+    600
+}
+
+fn default_agent_output_chars() -> usize {
+    2000
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_steps: default_agent_max_steps(),
+            max_secs: default_agent_max_secs(),
+            output_chars: default_agent_output_chars(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MemoryConfig {
+    /// 是否在分类时回放该聊天最近的对话
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 保留的最近轮数（一问一答为一轮），超出后较早的轮次会被 LLM 摘要
+    #[serde(default = "default_memory_max_turns")]
+    pub max_turns: usize,
+    /// 回放历史的估算 token 上限，超出后同样触发摘要
+    #[serde(default = "default_memory_max_tokens")]
+    pub max_tokens: usize,
+    /// 记录命令结果时每条 stdout/stderr 保留的最大字节数
+    #[serde(default = "default_memory_result_chars")]
+    pub result_chars: usize,
+}
+
+fn default_memory_max_turns() -> usize {
+    10
+}
+
+fn default_memory_max_tokens() -> usize {
+    3000
+}
+
+fn default_memory_result_chars() -> usize {
+    500
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_turns: default_memory_max_turns(),
+            max_tokens: default_memory_max_tokens(),
+            result_chars: default_memory_result_chars(),
+        }
+    }
+}
+
+/// 用户角色，权限依次递增
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// 无任何权限
+    None,
+    /// 只能提问
+    Viewer,
+    /// 可执行符合命令策略的命令
+    Operator,
+    /// 可执行任意命令（跳过命令策略）并管理 bot
+    Admin,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserRule {
+    /// Telegram 用户 ID（msg.from）
+    pub id: u64,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChannelAuthorRule {
+    /// 频道消息的署名（author_signature），频道消息没有 from 字段，只能按署名匹配
+    pub signature: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AuthConfig {
+    /// 用户 → 角色
+    #[serde(default)]
+    pub users: Vec<UserRule>,
+    /// 频道署名 → 角色
+    #[serde(default)]
+    pub channel_authors: Vec<ChannelAuthorRule>,
+    /// 未列出用户的角色。未配置 users / channel_authors 时默认 operator，否则默认 none
+    #[serde(default)]
+    pub default_role: Option<Role>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -25,22 +407,161 @@ pub struct TelegramConfig {
     /// Webhook 模式：本机监听地址，如 "0.0.0.0:8443"。需与 webhook_url 同时配置
     #[serde(default)]
     pub webhook_listen: Option<String>,
+    /// 管理通知（如技能加载错误）发送到的聊天 ID，留空则只写日志
+    #[serde(default)]
+    pub admin_chat_id: Option<i64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LlmConfig {
-    /// OpenAI 兼容 API 的 base URL
+    /// OpenAI 兼容 API 的 base URL；为空时只使用 `providers`
+    #[serde(default)]
     pub base_url: String,
     /// API Key
+    #[serde(default)]
     pub api_key: String,
     /// 模型名称
+    #[serde(default)]
     pub model: String,
+    /// 消息附带图片时使用的模型（需支持视觉输入），默认沿用 model
+    #[serde(default)]
+    pub vision_model: Option<String>,
     /// 系统提示词（可选，有默认值）
     #[serde(default)]
     pub system_prompt: Option<String>,
     /// 最大 token 数
     #[serde(default = "default_max_tokens")]
     pub max_tokens: u32,
+    /// 意图分类方式：prompt（提示模型输出 JSON）或 tools（原生 tool/function calling）
+    #[serde(default)]
+    pub classify_mode: ClassifyMode,
+    /// prompt 分类及 viewer 的纯问答请求 SSE 流式响应，问答内容边生成边刷新到状态消息；后端不支持时自动按普通响应处理。
+    /// tools 分类不使用流式，answer_question 的回答在完整返回后一次性显示
+    #[serde(default = "default_true")]
+    pub stream: bool,
+    /// 分类输出（prompt 模式的 JSON 或 tools 模式的 tool_calls）不是合法意图时，带上错误请求模型修复的最大次数；
+    /// 仍失败则把原始正文当作回答，正文为空时报错
+    #[serde(default = "default_repair_attempts")]
+    pub repair_attempts: u32,
+    /// 备用提供方，按顺序排在顶层 base_url/model 之后；前一个超时、5xx 或 429 时依次尝试下一个
+    #[serde(default)]
+    pub providers: Vec<ProviderConfig>,
+    /// 某个提供方连续失败多少次后熔断，熔断期间跳过它
+    #[serde(default = "default_circuit_failure_threshold")]
+    pub circuit_failure_threshold: u32,
+    /// 熔断持续时间（秒），之后放行一次试探请求
+    #[serde(default = "default_circuit_cooldown")]
+    pub circuit_cooldown_secs: u64,
+    /// 建立连接的超时（秒）
+    #[serde(default = "default_llm_connect_timeout")]
+    pub connect_timeout_secs: u64,
+    /// 单次请求（含读取响应）的超时（秒）
+    #[serde(default = "default_llm_request_timeout")]
+    pub request_timeout_secs: u64,
+    /// 超时、网络错误、5xx 或 429 时对同一提供方的最大重试次数，用尽后切换到下一个提供方
+    #[serde(default = "default_llm_max_retries")]
+    pub max_retries: u32,
+    /// 指数退避的初始间隔（毫秒），每次重试翻倍并加随机抖动
+    #[serde(default = "default_llm_retry_base_ms")]
+    pub retry_base_ms: u64,
+    /// 单次退避的最长等待（秒）；服务端 Retry-After 超过该值时不再等待，直接切换提供方
+    #[serde(default = "default_llm_retry_max_secs")]
+    pub retry_max_secs: u64,
+}
+
+/// 一个 OpenAI 兼容的 LLM 提供方
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProviderConfig {
+    /// 日志与任务历史中显示的名称，默认取模型名
+    #[serde(default)]
+    pub name: Option<String>,
+    pub base_url: String,
+    /// API Key，本地服务可留空
+    #[serde(default)]
+    pub api_key: String,
+    pub model: String,
+    /// 消息附带图片时使用的模型，默认沿用 model
+    #[serde(default)]
+    pub vision_model: Option<String>,
+    /// 最大 token 数，默认沿用 llm.max_tokens
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+}
+
+impl ProviderConfig {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.model)
+    }
+
+    /// 本次请求使用的模型：带图片时优先 vision_model
+    pub fn model_for(&self, images: bool) -> &str {
+        match &self.vision_model {
+            Some(vision) if images => vision,
+            _ => &self.model,
+        }
+    }
+}
+
+impl LlmConfig {
+    /// 按尝试顺序排列的全部提供方：顶层配置（若填写了 base_url）在前，其后是 `providers`。
+    pub fn provider_chain(&self) -> Vec<ProviderConfig> {
+        let mut chain = Vec::with_capacity(self.providers.len() + 1);
+        if !self.base_url.is_empty() {
+            chain.push(ProviderConfig {
+                name: None,
+                base_url: self.base_url.clone(),
+                api_key: self.api_key.clone(),
+                model: self.model.clone(),
+                vision_model: self.vision_model.clone(),
+                max_tokens: Some(self.max_tokens),
+            });
+        }
+        chain.extend(self.providers.iter().cloned());
+        chain
+    }
+}
+
+fn default_repair_attempts() -> u32 {
+    2
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    3
+}
+
+fn default_circuit_cooldown() -> u64 {
+    60
+}
+
+fn default_llm_connect_timeout() -> u64 {
+    10
+}
+
+fn default_llm_request_timeout() -> u64 {
+    60
+}
+
+fn default_llm_max_retries() -> u32 {
+    2
+}
+
+fn default_llm_retry_base_ms() -> u64 {
+    1000
+}
+
+fn default_llm_retry_max_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassifyMode {
+    /// 在系统提示中要求模型返回 JSON，再从文本中提取
+    #[default]
+    Prompt,
+    /// 声明 answer_question / run_shell_commands 及每个 skill 为 tools，解析 tool_calls；
+    /// 某个提供方以错误拒绝 tools 参数时，带 tools 的请求改走下一个提供方；都不支持时本次回退到 prompt
+    Tools,
 }
 
 fn default_max_tokens() -> u32 {
@@ -64,6 +585,78 @@ pub struct ExecutorConfig {
     /// 命令失败时向 LLM 询问修正并自动重试的最大次数，0 表示不重试仅展示建议
     #[serde(default = "default_max_fix_retries")]
     pub max_fix_retries: u32,
+    /// 长命令运行期间，每隔多少秒把最新输出刷新到状态消息，0 表示不刷新
+    #[serde(default = "default_stream_interval")]
+    pub stream_interval_secs: u64,
+    /// 执行报告超过该字符数时，消息中只发截断后的摘要，完整输出作为 .txt 文件发送；0 表示从不发文件，总是分条发送
+    #[serde(default = "default_report_file_threshold")]
+    pub report_file_threshold: usize,
+    /// 任务产物目录的根目录：每个任务在其下得到一个空目录（环境变量 BOT_ARTIFACTS），
+    /// 命令写入其中的文件在任务结束时发给用户，随后删除。默认系统临时目录下的 rust-bot-artifacts
+    #[serde(default)]
+    pub artifacts_dir: Option<String>,
+    /// 命令策略：执行前按规则判定允许 / 需审批 / 拒绝
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// 命令策略判定结果
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyVerdict {
+    Allow,
+    RequireApproval,
+    Deny,
+}
+
+/// `[executor.policy]`：规则为 glob（`*` 任意字符、`?` 单个字符），以 `re:` 开头则按正则匹配。
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicyConfig {
+    /// 是否启用内置危险命令规则（rm -rf /、mkfs、dd、shutdown、curl|sh 等）
+    #[serde(default = "default_true")]
+    pub builtin_rules: bool,
+    /// 允许规则：非空时，命令的每一段都须匹配其中一条，否则按 unmatched 处理
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// 拒绝规则：整条命令或任一段匹配即拒绝
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// 需审批规则：匹配的命令须经人工批准后执行
+    #[serde(default)]
+    pub require_approval: Vec<String>,
+    /// 额外禁止的可执行文件名（支持 glob，如 "mkfs*"）。`sh -c <脚本>` 中的命令同样检查，
+    /// 从标准输入交给 shell 的命令（如 `… | sh`）无法检查，非空时一律拒绝
+    #[serde(default)]
+    pub blocked_binaries: Vec<String>,
+    /// 是否限制命令中出现的路径（以及发送给用户的文件、可执行技能的入口程序）必须位于 working_dir 或 allowed_paths 之内；
+    /// skills 目录不在 working_dir 下时需加入 allowed_paths
+    #[serde(default)]
+    pub restrict_paths: bool,
+    /// restrict_paths 开启时额外允许访问的路径（绝对路径）
+    #[serde(default)]
+    pub allowed_paths: Vec<String>,
+    /// allow 非空且命令未匹配时的判定
+    #[serde(default = "default_unmatched_verdict")]
+    pub unmatched: PolicyVerdict,
+}
+
+fn default_unmatched_verdict() -> PolicyVerdict {
+    PolicyVerdict::Deny
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            builtin_rules: true,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            require_approval: Vec::new(),
+            blocked_binaries: Vec::new(),
+            restrict_paths: false,
+            allowed_paths: Vec::new(),
+            unmatched: default_unmatched_verdict(),
+        }
+    }
 }
 
 fn default_timeout() -> u64 {
@@ -78,6 +671,14 @@ fn default_max_fix_retries() -> u32 {
     10
 }
 
+fn default_stream_interval() -> u64 {
+    3
+}
+
+fn default_report_file_threshold() -> usize {
+    8000
+}
+
 impl Default for ExecutorConfig {
     fn default() -> Self {
         Self {
@@ -86,6 +687,37 @@ impl Default for ExecutorConfig {
             echo_result: true,
             activate_venv: None,
             max_fix_retries: default_max_fix_retries(),
+            stream_interval_secs: default_stream_interval(),
+            report_file_threshold: default_report_file_threshold(),
+            artifacts_dir: None,
+            policy: PolicyConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApprovalConfig {
+    /// 是否开启审批模式：开启后执行计划与每条 LLM 修正命令都需点击「批准」才会执行
+    #[serde(default)]
+    pub enabled: bool,
+    /// 等待审批的超时时间（秒），超时自动拒绝
+    #[serde(default = "default_approval_timeout")]
+    pub timeout_secs: u64,
+    /// 有权审批的 Telegram 用户 ID，留空则允许聊天中任何人审批
+    #[serde(default)]
+    pub approver_ids: Vec<u64>,
+}
+
+fn default_approval_timeout() -> u64 {
+    300
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: default_approval_timeout(),
+            approver_ids: Vec::new(),
         }
     }
 }
@@ -94,8 +726,18 @@ impl AppConfig {
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let content = std::fs::read_to_string(path.as_ref())
             .with_context(|| format!("无法读取配置文件: {}", path.as_ref().display()))?;
-        let config: AppConfig =
+        let mut config: AppConfig =
             toml::from_str(&content).with_context(|| "配置文件解析失败")?;
+        config.path = path.as_ref().to_path_buf();
+        if config.llm.provider_chain().is_empty() {
+            anyhow::bail!("配置文件中未配置 LLM：需填写 llm.base_url/model 或至少一个 [[llm.providers]]");
+        }
+        if config.stt.enabled && config.stt.base_url.is_empty() {
+            anyhow::bail!("已启用语音转写，但未配置 stt.base_url");
+        }
+        if config.skill_retrieval.enabled && config.skill_retrieval.base_url.is_empty() {
+            anyhow::bail!("已启用技能检索，但未配置 skill_retrieval.base_url");
+        }
         Ok(config)
     }
 }
@@ -0,0 +1,137 @@
+//! 对话记忆：按聊天保存最近的用户消息、bot 回答以及执行过的命令与截断后的结果，
+//! 在分类时作为历史消息回放，使「对 /var/log 也这样做」之类的追问有上下文。
+//!
+//! 超出轮数或 token 预算时，较早的轮次由 LLM 压缩成一段摘要，以 system 消息放在历史最前面。
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::config::MemoryConfig;
+use crate::executor::CommandResult;
+use crate::llm_client::ChatMessage;
+use crate::text::truncate_str;
+
+/// 一轮对话：用户消息与 bot 的回答（或执行结果描述）
+#[derive(Debug, Clone)]
+struct Turn {
+    user: String,
+    reply: String,
+}
+
+#[derive(Debug, Default)]
+struct Conversation {
+    summary: Option<String>,
+    turns: VecDeque<Turn>,
+}
+
+impl Conversation {
+    fn estimated_tokens(&self) -> usize {
+        self.summary.as_deref().map(estimate_tokens).unwrap_or(0)
+            + self
+                .turns
+                .iter()
+                .map(|t| estimate_tokens(&t.user) + estimate_tokens(&t.reply))
+                .sum::<usize>()
+    }
+}
+
+/// 需要摘要的较早轮次，由调用方交给 LLM 压缩后通过 `set_summary` 写回
+pub struct Overflow {
+    pub previous_summary: Option<String>,
+    pub messages: Vec<ChatMessage>,
+}
+
+pub struct ConversationStore {
+    config: MemoryConfig,
+    chats: Mutex<HashMap<i64, Conversation>>,
+}
+
+impl ConversationStore {
+    pub fn new(config: MemoryConfig) -> Self {
+        Self {
+            config,
+            chats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 该聊天的历史消息（摘要 + 最近轮次），未开启时返回空。
+    pub fn history(&self, chat_id: i64) -> Vec<ChatMessage> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+        let chats = self.chats.lock().unwrap();
+        let Some(conv) = chats.get(&chat_id) else { return Vec::new() };
+        let mut messages = Vec::with_capacity(conv.turns.len() * 2 + 1);
+        if let Some(summary) = &conv.summary {
+            messages.push(ChatMessage::system(format!("此前对话摘要：\n{summary}")));
+        }
+        for t in &conv.turns {
+            messages.push(ChatMessage::user(t.user.clone()));
+            messages.push(ChatMessage::assistant(t.reply.clone()));
+        }
+        messages
+    }
+
+    /// 记录一轮对话。超出预算时取出较早的一半轮次返回，供调用方摘要。
+    pub fn record(&self, chat_id: i64, user: &str, reply: &str) -> Option<Overflow> {
+        if !self.config.enabled {
+            return None;
+        }
+        let mut chats = self.chats.lock().unwrap();
+        let conv = chats.entry(chat_id).or_default();
+        conv.turns.push_back(Turn {
+            user: user.to_string(),
+            reply: reply.to_string(),
+        });
+
+        let over_budget = conv.turns.len() > self.config.max_turns
+            || conv.estimated_tokens() > self.config.max_tokens;
+        if !over_budget || conv.turns.len() < 2 {
+            return None;
+        }
+        let take = conv.turns.len() / 2;
+        let messages = conv
+            .turns
+            .drain(..take)
+            .flat_map(|t| [ChatMessage::user(t.user), ChatMessage::assistant(t.reply)])
+            .collect();
+        Some(Overflow {
+            previous_summary: conv.summary.clone(),
+            messages,
+        })
+    }
+
+    pub fn set_summary(&self, chat_id: i64, summary: String) {
+        let mut chats = self.chats.lock().unwrap();
+        chats.entry(chat_id).or_default().summary = Some(summary);
+    }
+
+    /// 清空该聊天的记忆（/reset）
+    pub fn reset(&self, chat_id: i64) {
+        self.chats.lock().unwrap().remove(&chat_id);
+    }
+
+    /// 把执行过的命令与截断后的结果整理成一段文字，作为该轮 bot 的回答记录。
+    pub fn describe_results(&self, results: &[CommandResult]) -> String {
+        let limit = self.config.result_chars;
+        let mut s = String::from("已执行命令：\n");
+        for r in results {
+            s.push_str(&format!("$ {}\n", r.command));
+            s.push_str(&format!("退出码: {:?}{}\n", r.exit_code, if r.success { "（成功）" } else { "（失败）" }));
+            if !r.stdout.is_empty() {
+                s.push_str(&format!("stdout:\n{}\n", truncate_str(r.stdout.trim_end(), limit)));
+            }
+            if !r.stderr.is_empty() {
+                s.push_str(&format!("stderr:\n{}\n", truncate_str(r.stderr.trim_end(), limit)));
+            }
+        }
+        s
+    }
+}
+
+/// 粗略估算 token 数：ASCII 约 4 字节一个 token，其余字符（中文等）按一个字符一个 token。
+fn estimate_tokens(s: &str) -> usize {
+    let ascii = s.bytes().filter(u8::is_ascii).count();
+    let other = s.chars().filter(|c| !c.is_ascii()).count();
+    ascii.div_ceil(4) + other
+}
@@ -0,0 +1,371 @@
+//! 任务历史：每个任务结束后以一行 JSON 追加到历史文件（JSONL），
+//! 记录发送者、原始消息、分类结果、执行计划、每次命令执行（含修正重试）、耗时与最终报告。
+//!
+//! 启动时扫描一次文件建立内存索引（tid、chat_id 与行偏移），任务 ID 在重启后继续递增；
+//! /history、/task 只按索引读取需要的行。
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::HistoryConfig;
+use crate::executor::{CommandResult, TaskCommand};
+use crate::llm_client::LlmIntent;
+use crate::text::truncate_str;
+
+/// 任务的最终结局
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskOutcome {
+    /// 问答类消息已回答
+    Answered,
+    /// 命令全部执行成功（或无需执行）
+    Completed,
+    /// 有命令最终失败
+    Failed,
+    Cancelled,
+    /// 执行计划未获审批
+    Rejected,
+    /// 角色无权执行命令
+    Refused,
+    /// LLM 调用等内部错误
+    Error,
+}
+
+impl TaskOutcome {
+    pub fn icon(self) -> &'static str {
+        match self {
+            TaskOutcome::Answered => "💬",
+            TaskOutcome::Completed => "✅",
+            TaskOutcome::Failed => "❌",
+            TaskOutcome::Cancelled => "🛑",
+            TaskOutcome::Rejected => "⛔",
+            TaskOutcome::Refused => "🚫",
+            TaskOutcome::Error => "⚠️",
+        }
+    }
+}
+
+/// 一次命令执行（计划中的命令、LLM 修正命令或 Agent 步骤）
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Attempt {
+    pub description: String,
+    pub started_at: DateTime<Local>,
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub result: CommandResult,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TaskRecord {
+    pub tid: u64,
+    pub chat_id: i64,
+    /// 发送者名字（频道消息为署名）
+    pub user: String,
+    pub user_id: Option<u64>,
+    pub text: String,
+    pub started_at: DateTime<Local>,
+    #[serde(default)]
+    pub elapsed_ms: u64,
+    #[serde(default)]
+    pub intent: Option<LlmIntent>,
+    /// 实际执行的计划（经审批修改后的版本）
+    #[serde(default)]
+    pub commands: Vec<TaskCommand>,
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+    #[serde(default)]
+    pub report: Option<String>,
+    #[serde(default)]
+    pub outcome: Option<TaskOutcome>,
+    /// 实际应答过的 LLM 提供方
+    #[serde(default)]
+    pub providers: Vec<String>,
+}
+
+impl TaskRecord {
+    pub fn new(tid: u64, chat_id: i64, user: String, user_id: Option<u64>, text: String) -> Self {
+        Self {
+            tid,
+            chat_id,
+            user,
+            user_id,
+            text,
+            started_at: Local::now(),
+            elapsed_ms: 0,
+            intent: None,
+            commands: Vec::new(),
+            attempts: Vec::new(),
+            report: None,
+            outcome: None,
+            providers: Vec::new(),
+        }
+    }
+
+    /// /history 列表中的一行
+    pub fn summary_line(&self) -> String {
+        let icon = self.outcome.map(TaskOutcome::icon).unwrap_or("❔");
+        let runs = if self.attempts.is_empty() {
+            String::new()
+        } else {
+            format!("{} 次执行，", self.attempts.len())
+        };
+        format!(
+            "#{} {} {icon} {}: {}（{runs}{:.1}s）",
+            self.tid,
+            self.started_at.format("%m-%d %H:%M"),
+            self.user,
+            truncate_str(self.text.trim(), 60),
+            self.elapsed_ms as f64 / 1000.0
+        )
+    }
+
+    /// /task 展示的详情
+    pub fn describe(&self) -> String {
+        let icon = self.outcome.map(TaskOutcome::icon).unwrap_or("❔");
+        let mut s = format!("📄 任务 #{} {icon}\n", self.tid);
+        s.push_str(&format!(
+            "时间: {}（耗时 {:.1}s）\n",
+            self.started_at.format("%Y-%m-%d %H:%M:%S"),
+            self.elapsed_ms as f64 / 1000.0
+        ));
+        s.push_str(&format!("发送者: {}\n", self.user));
+        s.push_str(&format!("消息: {}\n", truncate_str(self.text.trim(), 500)));
+        match &self.intent {
+            Some(LlmIntent::Question { .. }) => s.push_str("分类: 问答\n"),
+            Some(LlmIntent::Command { commands }) => s.push_str(&format!("分类: 命令（{} 条）\n", commands.len())),
+            Some(LlmIntent::Download { path }) => s.push_str(&format!("分类: 发送文件 {path}\n")),
+            Some(LlmIntent::Skill { id, .. }) => s.push_str(&format!("分类: 技能 {id}\n")),
+            None => {}
+        }
+        if !self.providers.is_empty() {
+            s.push_str(&format!("LLM: {}\n", self.providers.join(", ")));
+        }
+        if !self.attempts.is_empty() {
+            s.push_str("\n执行记录:\n");
+            for (i, a) in self.attempts.iter().enumerate() {
+                let r = &a.result;
+                let status = if r.success { "✅" } else { "❌" };
+                s.push_str(&format!(
+                    "{}. {status} {}（{:.1}s, 退出码 {:?}）\n   $ {}\n",
+                    i + 1,
+                    a.description,
+                    a.elapsed_ms as f64 / 1000.0,
+                    r.exit_code,
+                    truncate_str(&r.command, 200)
+                ));
+                if !r.stdout.is_empty() {
+                    s.push_str(&format!("   输出: {}\n", truncate_str(r.stdout.trim_end(), 300)));
+                }
+                if !r.stderr.is_empty() {
+                    s.push_str(&format!("   错误: {}\n", truncate_str(r.stderr.trim_end(), 200)));
+                }
+            }
+        }
+        if let Some(report) = &self.report {
+            s.push_str(&format!("\n报告:\n{}", truncate_str(report.trim(), 1000)));
+        }
+        s
+    }
+}
+
+/// 历史文件中一条记录的位置，查询时只读取需要的行
+struct IndexEntry {
+    tid: u64,
+    chat_id: i64,
+    offset: u64,
+    len: usize,
+}
+
+/// 建索引时只解析的字段
+#[derive(Deserialize)]
+struct RecordKey {
+    tid: u64,
+    chat_id: i64,
+}
+
+pub struct HistoryStore {
+    path: Option<PathBuf>,
+    /// 内存索引：启动时扫描一次文件，之后随追加更新；同时串行化追加写入，避免并发任务的行交错
+    index: Mutex<Vec<IndexEntry>>,
+}
+
+impl HistoryStore {
+    pub fn new(config: &HistoryConfig) -> Result<Self> {
+        let path = if config.enabled {
+            let path = PathBuf::from(&config.path);
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("无法创建历史目录: {}", dir.display()))?;
+            }
+            Some(path)
+        } else {
+            None
+        };
+        let index = match &path {
+            Some(p) => build_index(p).with_context(|| format!("读取历史文件失败: {}", p.display()))?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 追加一条任务记录
+    pub fn append(&self, record: &TaskRecord) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut line = serde_json::to_string(record).context("序列化任务记录失败")?;
+        line.push('\n');
+        let mut index = self.index.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("无法打开历史文件: {}", path.display()))?;
+        let offset = file
+            .metadata()
+            .with_context(|| format!("无法读取历史文件信息: {}", path.display()))?
+            .len();
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("写入历史文件失败: {}", path.display()))?;
+        index.push(IndexEntry {
+            tid: record.tid,
+            chat_id: record.chat_id,
+            offset,
+            len: line.len(),
+        });
+        Ok(())
+    }
+
+    /// 历史中最大的任务 ID，没有记录时为 0
+    pub fn max_tid(&self) -> u64 {
+        self.index.lock().unwrap().iter().map(|e| e.tid).max().unwrap_or(0)
+    }
+
+    /// 该聊天最近的 n 条任务，按时间倒序
+    pub fn recent(&self, chat_id: i64, n: usize) -> Vec<TaskRecord> {
+        let positions: Vec<(u64, usize)> = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| e.chat_id == chat_id)
+            .take(n)
+            .map(|e| (e.offset, e.len))
+            .collect();
+        positions.into_iter().filter_map(|(offset, len)| self.read_at(offset, len)).collect()
+    }
+
+    pub fn get(&self, tid: u64) -> Option<TaskRecord> {
+        let (offset, len) = self
+            .index
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|e| e.tid == tid)
+            .map(|e| (e.offset, e.len))?;
+        self.read_at(offset, len)
+    }
+
+    /// 按索引位置读取并解析一行
+    fn read_at(&self, offset: u64, len: usize) -> Option<TaskRecord> {
+        let mut file = std::fs::File::open(self.path.as_ref()?).ok()?;
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf).ok()?;
+        serde_json::from_slice(&buf).ok()
+    }
+}
+
+/// 逐行扫描历史文件，记录每条可解析记录的位置，跳过无法解析的行
+fn build_index(path: &Path) -> Result<Vec<IndexEntry>> {
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(file);
+    let mut index = Vec::new();
+    let mut offset = 0u64;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        if let Ok(key) = serde_json::from_slice::<RecordKey>(&line) {
+            index.push(IndexEntry {
+                tid: key.tid,
+                chat_id: key.chat_id,
+                offset,
+                len,
+            });
+        }
+        offset += len as u64;
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> (HistoryStore, PathBuf) {
+        let path = std::env::temp_dir().join(format!("rust-bot-history-{}-{name}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            enabled: true,
+            path: path.to_string_lossy().into_owned(),
+        };
+        (HistoryStore::new(&config).unwrap(), path)
+    }
+
+    #[test]
+    fn round_trip_and_reload_skip_corrupt_lines() {
+        let (history, path) = store("round-trip");
+        assert_eq!(history.max_tid(), 0);
+        history.append(&TaskRecord::new(1, 100, "alice".into(), Some(1), "第一条".into())).unwrap();
+        // 模拟写入中断留下的半行
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"tid\": 9, \"chat\n")
+            .unwrap();
+        history.append(&TaskRecord::new(2, 200, "bob".into(), None, "second".into())).unwrap();
+        history.append(&TaskRecord::new(3, 100, "alice".into(), Some(1), "第三条".into())).unwrap();
+
+        let check = |h: &HistoryStore| {
+            assert_eq!(h.max_tid(), 3);
+            let recent: Vec<u64> = h.recent(100, 10).iter().map(|r| r.tid).collect();
+            assert_eq!(recent, [3, 1]);
+            assert_eq!(h.recent(100, 1).len(), 1);
+            assert_eq!(h.get(2).unwrap().text, "second");
+            assert!(h.get(9).is_none());
+        };
+        check(&history);
+        // 重启后重新建索引，结果一致
+        let config = HistoryConfig {
+            enabled: true,
+            path: path.to_string_lossy().into_owned(),
+        };
+        check(&HistoryStore::new(&config).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,10 +1,16 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tracing::{debug, info};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
 
-use crate::config::LlmConfig;
+use crate::config::{ClassifyMode, LlmConfig, ProviderConfig};
+use crate::skills::{self, Skill};
+use crate::text::truncate_str;
+use crate::usage::{Usage, UsageStore};
 
 const FIX_FAILURE_SYSTEM_PROMPT: &str = r#"你是一个命令行故障排查助手。用户会提供一条执行失败的命令以及其错误输出（stderr），请分析原因并给出解决方式或替代命令建议。用简洁的中文回答，可以包含修正后的命令示例。只返回你的分析和建议内容，不要包含多余前缀或 markdown 代码块。"#;
 
@@ -12,6 +18,7 @@ const CLASSIFY_PROMPT: &str = r#"你是一个消息意图分类器。用户通
 
 1. "question" — 用户在提问、闲聊、咨询，不需要在服务器上执行任何操作
 2. "command" — 用户想要在服务器上执行某些操作（如查看文件、检查系统状态、部署、安装软件、截图等）
+3. "download" — 用户想要把服务器上的某个已有文件发给他（如「把 /var/log/app.log 发给我」）
 
 请返回一个 JSON 对象，格式如下：
 
@@ -21,14 +28,105 @@ const CLASSIFY_PROMPT: &str = r#"你是一个消息意图分类器。用户通
 如果是操作命令：
 {"type": "command", "commands": [{"command": "shell命令", "description": "说明"}]}
 
+如果是要服务器上的文件：
+{"type": "download", "path": "文件路径"}
+
 注意：
-- 如果用户要求截图或查看屏幕，使用 screencapture 命令（macOS）或 scrot/import 命令（Linux），将图片保存到 /tmp/ 目录
+- 需要发给用户的文件（截图、录屏、图表、导出的数据等）一律保存到 $BOT_ARTIFACTS 目录（如 $BOT_ARTIFACTS/screenshot.png），命令结束后该目录中的文件会自动发送给用户
+- 如果用户要求截图或查看屏幕，使用 screencapture 命令（macOS）或 scrot/import 命令（Linux），将图片保存到 $BOT_ARTIFACTS 目录
 - 只返回 JSON，不要包含其他文字或 markdown 代码块标记
-- 对于问题类型，content 字段中直接给出详细有用的回答"#;
+- 对于问题类型，content 字段中直接给出详细有用的回答
+- commands 至少包含一条命令；不需要执行任何命令时返回问题类型，在 content 中说明"#;
 
-const LLM_TIMEOUT_SECS: u64 = 60;
+const TOOLS_CLASSIFY_PROMPT: &str = r#"你是一个运行在服务器上的自动化任务执行代理。用户通过 Telegram 发来消息，你必须调用且只调用一个工具来处理：
 
-#[derive(Debug, Deserialize)]
+- 用户在提问、闲聊、咨询，不需要在服务器上执行任何操作时，调用 answer_question，content 中直接给出详细有用的回答
+- 用户想要在服务器上执行某些操作（如查看文件、检查系统状态、部署、安装软件、截图等）时，调用 run_shell_commands，或调用与需求匹配的 skill_ 开头的技能工具
+- 用户想要把服务器上的某个已有文件发给他时，调用 send_file
+- run_shell_commands 至少包含一条命令；不需要执行任何命令时调用 answer_question 说明
+
+注意：
+- 需要发给用户的文件（截图、录屏、图表、导出的数据等）一律保存到 $BOT_ARTIFACTS 目录（如 $BOT_ARTIFACTS/screenshot.png），命令结束后该目录中的文件会自动发送给用户
+- 如果用户要求截图或查看屏幕，使用 screencapture 命令（macOS）或 scrot/import 命令（Linux），将图片保存到 $BOT_ARTIFACTS 目录"#;
+
+const ANSWER_ONLY_PROMPT: &str = r#"你是一个运行在服务器上的运维助手，通过 Telegram 回答用户的问题。当前用户只有提问权限：不能在服务器上执行命令、调用技能或获取服务器上的文件。
+- 用户提问、闲聊、咨询时，直接给出详细有用的回答
+- 用户要求执行操作或发送文件时，说明其角色无权执行，可以给出由有权限的人手动完成的步骤
+- 直接输出回答正文，不要输出 JSON"#;
+
+const AGENT_PROMPT: &str = r#"你是一个在服务器上逐步完成任务的运维代理。每一轮你只能做一件事，并返回一个 JSON 对象：
+
+执行一条命令：
+{"type": "run", "command": "shell命令", "description": "说明"}
+
+任务已完成或无法继续时给出最终回答：
+{"type": "final", "answer": "用中文总结你的发现与结论"}
+
+注意：
+- 每条命令执行后你会收到它的退出码、stdout 和 stderr，请根据结果决定下一步
+- 命令失败时分析原因并换一种方式，不要重复执行同一条失败的命令
+- 需要发给用户的文件保存到 $BOT_ARTIFACTS 目录，任务结束后会自动发送
+- 只返回 JSON，不要包含其他文字或 markdown 代码块标记"#;
+
+const AGENT_FORCE_FINAL: &str = "步数或时间预算已用尽，不要再执行命令，请直接返回 {\"type\": \"final\", \"answer\": ...} 给出目前的结论。";
+
+const REPAIR_PROMPT: &str = r#"你上一条回复不是合法的意图 JSON。请根据下面的错误修正，只返回一个 JSON 对象，不要包含其他文字或 markdown 代码块标记：
+{"type": "question", "content": "回答内容"} 或 {"type": "command", "commands": [{"command": "shell命令", "description": "说明"}]} 或 {"type": "download", "path": "文件路径"} 或 {"type": "skill", "id": "技能 id", "args": {参数}}"#;
+
+/// 意图校验的上限：单次计划的命令条数与单条命令长度
+const MAX_INTENT_COMMANDS: usize = 20;
+const MAX_COMMAND_CHARS: usize = 4000;
+
+const SUMMARY_PROMPT: &str = r#"你是对话摘要助手。请把下面这段用户与运维 bot 的历史对话压缩成一段简洁的中文摘要，保留后续对话可能用到的关键信息：用户的目标、涉及的路径/文件/服务名、执行过的命令及其关键结果。若提供了此前的摘要，请将其与新对话合并为一份摘要。只输出摘要正文。"#;
+
+/// 发送给 chat completions 的一条消息
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: &'static str,
+    pub content: String,
+    /// 随消息发送的图片（data URL），非空时 content 按 text + image_url 多段格式发送
+    pub images: Vec<String>,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self { role: "system", content: content.into(), images: Vec::new() }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self { role: "user", content: content.into(), images: Vec::new() }
+    }
+
+    pub fn user_with_images(content: impl Into<String>, images: Vec<String>) -> Self {
+        Self { role: "user", content: content.into(), images }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self { role: "assistant", content: content.into(), images: Vec::new() }
+    }
+}
+
+impl Serialize for ChatMessage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("ChatMessage", 2)?;
+        s.serialize_field("role", self.role)?;
+        if self.images.is_empty() {
+            s.serialize_field("content", &self.content)?;
+        } else {
+            let mut parts = vec![json!({ "type": "text", "text": self.content })];
+            parts.extend(
+                self.images
+                    .iter()
+                    .map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+            );
+            s.serialize_field("content", &parts)?;
+        }
+        s.end()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum LlmIntent {
     #[serde(rename = "question")]
@@ -37,54 +135,367 @@ pub enum LlmIntent {
     Command {
         commands: Vec<CommandItem>,
     },
+    /// 把服务器上的文件发给用户
+    #[serde(rename = "download")]
+    Download { path: String },
+    /// 调用可执行技能，参数由 bot 按技能声明校验
+    #[serde(rename = "skill")]
+    Skill {
+        id: String,
+        #[serde(default)]
+        args: Map<String, Value>,
+    },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct CommandItem {
     pub command: String,
     #[serde(default)]
     pub description: String,
 }
 
+/// Agent 模式下 LLM 的每一步决定
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum AgentAction {
+    #[serde(rename = "run")]
+    Run {
+        command: String,
+        #[serde(default)]
+        description: String,
+    },
+    #[serde(rename = "final")]
+    Final { answer: String },
+}
+
+/// Agent 已执行的一步，回放给 LLM 作为观察结果
+pub struct AgentStep<'a> {
+    pub command: &'a str,
+    pub description: &'a str,
+    pub exit_code: Option<i32>,
+    pub stdout: &'a str,
+    pub stderr: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnswerArgs {
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SendFileArgs {
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandsArgs {
+    commands: Vec<CommandItem>,
+}
+
+const TOOL_ANSWER: &str = "answer_question";
+const TOOL_RUN_COMMANDS: &str = "run_shell_commands";
+const TOOL_SEND_FILE: &str = "send_file";
+const SKILL_TOOL_PREFIX: &str = "skill_";
+
+/// LLM API 返回的非成功 HTTP 状态，便于调用方按状态码决定回退或重试。
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+    /// 响应头 Retry-After 给出的等待时间
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LLM API 错误 {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// 流式响应中部分回答的推送方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Streaming {
+    /// 不请求流式响应
+    Off,
+    /// 提示词分类的 JSON：只推送 question 意图的 content
+    Intent,
+    /// 纯文本回答：原样推送
+    Text,
+}
+
+/// 带 tools 的请求没有可用的提供方：链上的提供方不支持 tools（或其余均失败），调用方应改用提示词分类
+#[derive(Debug)]
+struct ToolsUnsupported;
+
+impl std::fmt::Display for ToolsUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "没有可用的支持 tools 的 LLM 提供方")
+    }
+}
+
+impl std::error::Error for ToolsUnsupported {}
+
+/// 一次 LLM 请求失败后即将进行的重试
+#[derive(Debug, Clone)]
+pub struct RetryNotice {
+    pub provider: String,
+    /// 第几次重试，从 1 开始
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub delay: Duration,
+    /// 失败原因，如 `HTTP 503`、`超时`
+    pub reason: String,
+}
+
+/// 一次任务中各 LLM 调用共享的上下文：用量归属的聊天与用户（及是否受配额限制），
+/// 记录实际应答的提供方（供任务历史使用），并广播重试提示与流式生成中的部分回答（供状态消息显示）。
+#[derive(Debug)]
+pub struct CallContext {
+    chat_id: Option<i64>,
+    user_id: Option<u64>,
+    /// 不检查每日配额（admin）
+    quota_exempt: bool,
+    providers: Mutex<Vec<String>>,
+    retries: watch::Sender<Option<RetryNotice>>,
+    answer: watch::Sender<String>,
+}
+
+impl Default for CallContext {
+    fn default() -> Self {
+        Self {
+            chat_id: None,
+            user_id: None,
+            quota_exempt: false,
+            providers: Mutex::new(Vec::new()),
+            retries: watch::channel(None).0,
+            answer: watch::channel(String::new()).0,
+        }
+    }
+}
+
+impl CallContext {
+    pub fn new(chat_id: i64, user_id: Option<u64>, quota_exempt: bool) -> Self {
+        Self {
+            chat_id: Some(chat_id),
+            user_id,
+            quota_exempt,
+            ..Self::default()
+        }
+    }
+
+    pub fn chat_id(&self) -> Option<i64> {
+        self.chat_id
+    }
+
+    pub fn user_id(&self) -> Option<u64> {
+        self.user_id
+    }
+
+    /// 需要检查每日配额的用户；admin 与不属于任何用户的调用返回 None
+    pub fn quota_user(&self) -> Option<u64> {
+        self.user_id.filter(|_| !self.quota_exempt)
+    }
+
+    /// 订阅此后发生的重试提示
+    pub fn subscribe_retries(&self) -> watch::Receiver<Option<RetryNotice>> {
+        self.retries.subscribe()
+    }
+
+    /// 订阅流式分类时已生成的部分回答（仅问答意图）
+    pub fn subscribe_answer(&self) -> watch::Receiver<String> {
+        self.answer.subscribe()
+    }
+
+    fn set_answer(&self, partial: &str) {
+        self.answer.send_if_modified(|current| {
+            if current == partial {
+                return false;
+            }
+            partial.clone_into(current);
+            true
+        });
+    }
+
+    fn answered_by(&self, name: &str) {
+        let mut providers = self.providers.lock().unwrap();
+        if !providers.iter().any(|p| p == name) {
+            providers.push(name.to_string());
+        }
+    }
+
+    /// 应答过的提供方（去重，按首次应答顺序）
+    pub fn providers(&self) -> Vec<String> {
+        self.providers.lock().unwrap().clone()
+    }
+}
+
+/// 提供方的熔断状态：连续失败达到阈值后熔断，冷却期内跳过该提供方；冷却结束后只放行一次试探请求
+/// （放行时重新计时，试探未结束前其他请求仍跳过它），试探成功关闭熔断，失败则再熔断一个冷却期。
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    /// 本次请求能否使用该提供方；半开时放行的请求即为试探请求
+    fn admit(&mut self, now: Instant, cooldown: Duration) -> bool {
+        match self.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) => {
+                self.open_until = Some(now + cooldown);
+                true
+            }
+        }
+    }
+
+    fn record_success(&mut self) {
+        *self = Breaker::default();
+    }
+
+    /// 记录一次失败，返回是否（重新）熔断。熔断期间失败次数不清零，试探失败即再次熔断。
+    fn record_failure(&mut self, now: Instant, threshold: u32, cooldown: Duration) -> bool {
+        self.failures += 1;
+        if self.failures >= threshold.max(1) {
+            self.open_until = Some(now + cooldown);
+            return true;
+        }
+        false
+    }
+}
+
+struct Provider {
+    config: ProviderConfig,
+    breaker: Mutex<Breaker>,
+    /// 曾以错误拒绝 tools 参数，此后带 tools 的请求跳过该提供方
+    tools_unsupported: AtomicBool,
+}
+
+impl Provider {
+    fn name(&self) -> &str {
+        self.config.display_name()
+    }
+
+    fn supports_tools(&self) -> bool {
+        !self.tools_unsupported.load(Ordering::Relaxed)
+    }
+
+    fn admit(&self, cooldown: Duration) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+        let half_open = breaker.open_until.is_some();
+        let admitted = breaker.admit(Instant::now(), cooldown);
+        if admitted && half_open {
+            tlog!("LLM", "提供方 {} 熔断冷却结束，发送试探请求", self.name());
+        }
+        admitted
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.open_until.is_some() {
+            tlog!("LLM", "提供方 {} 已恢复，关闭熔断", self.name());
+        }
+        breaker.record_success();
+    }
+
+    fn record_failure(&self, threshold: u32, cooldown: Duration) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.record_failure(Instant::now(), threshold, cooldown) {
+            tlog!("LLM", "提供方 {} 连续失败 {} 次，熔断 {}s", self.name(), breaker.failures, cooldown.as_secs());
+        }
+    }
+}
+
 pub struct LlmClient {
     client: reqwest::Client,
     config: LlmConfig,
+    /// 每次成功调用的 token 用量记到这里，并据此检查每日配额
+    usage: Arc<UsageStore>,
+    /// 按尝试顺序排列的提供方
+    providers: Vec<Provider>,
 }
 
 impl LlmClient {
-    pub fn new(config: LlmConfig) -> Self {
+    pub fn new(config: LlmConfig, usage: Arc<UsageStore>) -> Self {
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(LLM_TIMEOUT_SECS))
-            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(config.request_timeout_secs))
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
             .build()
             .unwrap_or_else(|_| reqwest::Client::new());
 
-        Self { client, config }
-    }
+        let providers = config
+            .provider_chain()
+            .into_iter()
+            .map(|config| Provider {
+                config,
+                breaker: Mutex::new(Breaker::default()),
+                tools_unsupported: AtomicBool::new(false),
+            })
+            .collect();
 
-    /// 分类用户意图。`prompt_suffix` 可选，通常由 skills 模块生成，会追加到系统提示末尾。
-    pub async fn classify(&self, user_message: &str, prompt_suffix: Option<&str>) -> Result<LlmIntent> {
-        let mut system_prompt = self
-            .config
-            .system_prompt
-            .as_deref()
-            .unwrap_or(CLASSIFY_PROMPT)
-            .to_string();
-        if let Some(suffix) = prompt_suffix {
-            if !suffix.is_empty() {
-                system_prompt.push_str(suffix);
-            }
+        Self {
+            client,
+            config,
+            usage,
+            providers,
         }
+    }
 
-        tlog!("LLM", ">>> 用户消息: {}", user_message);
-        let raw = self.call_api(&system_prompt, user_message).await?;
-        tlog!("LLM", "<<< 原始响应 ({} 字符): {}", raw.len(), raw);
+    /// 分类用户意图。`prompt_suffix` 可选，通常由 skills 模块生成，会追加到系统提示末尾；
+    /// `history` 为该聊天此前的对话，放在系统提示与本条消息之间。
+    /// `skills` 在 tools 模式下各自声明为一个工具。`user_message` 附带图片时改用提供方的 vision_model。
+    pub async fn classify(
+        &self,
+        ctx: &CallContext,
+        user_message: &ChatMessage,
+        prompt_suffix: Option<&str>,
+        history: &[ChatMessage],
+        skills: &[Skill],
+    ) -> Result<LlmIntent> {
+        let build_messages = |default_prompt: &str| {
+            let mut system_prompt = self
+                .config
+                .system_prompt
+                .as_deref()
+                .unwrap_or(default_prompt)
+                .to_string();
+            if let Some(suffix) = prompt_suffix {
+                if !suffix.is_empty() {
+                    system_prompt.push_str(suffix);
+                }
+            }
+            let mut messages = Vec::with_capacity(history.len() + 2);
+            messages.push(ChatMessage::system(system_prompt));
+            messages.extend_from_slice(history);
+            messages.push(user_message.clone());
+            messages
+        };
 
-        let json_text = extract_json_object(&raw);
-        tlog!("LLM", "解析 JSON: {}", json_text);
+        tlog!("LLM", ">>> 用户消息: {}", user_message.content);
+        if !user_message.images.is_empty() {
+            tlog!("LLM", "附带 {} 张图片", user_message.images.len());
+        }
+        if !history.is_empty() {
+            tlog!("LLM", "附带 {} 条历史消息", history.len());
+        }
 
-        let intent = serde_json::from_str::<LlmIntent>(&json_text)
-            .with_context(|| format!("无法解析 LLM 意图响应: {raw}"))?;
+        let use_tools = self.config.classify_mode == ClassifyMode::Tools
+            && self.providers.iter().any(Provider::supports_tools);
+        let intent = if use_tools {
+            match self.classify_with_tools(ctx, &build_messages(TOOLS_CLASSIFY_PROMPT), skills).await {
+                Ok(intent) => intent,
+                Err(e) if e.downcast_ref::<ToolsUnsupported>().is_some() => {
+                    tlog!("LLM", "没有可用的支持 tools 的提供方，本次回退到提示词分类: {:#}", e);
+                    self.classify_with_prompt(ctx, &build_messages(CLASSIFY_PROMPT)).await?
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            self.classify_with_prompt(ctx, &build_messages(CLASSIFY_PROMPT)).await?
+        };
 
         match &intent {
             LlmIntent::Question { content } => {
@@ -96,30 +507,174 @@ impl LlmClient {
                     tlog!("LLM", "  {}. [{}] {}", i + 1, c.description, c.command);
                 }
             }
+            LlmIntent::Download { path } => {
+                tlog!("LLM", "意图: 发送文件 → {}", path);
+            }
+            LlmIntent::Skill { id, args } => {
+                tlog!("LLM", "意图: 技能 {} → {}", id, Value::Object(args.clone()));
+            }
         }
 
         Ok(intent)
     }
 
+    /// 只能提问的角色（viewer）使用：不做意图分类、不声明技能，直接生成回答，
+    /// 不会为注定被拒绝的命令或技能意图消耗 token。
+    pub async fn answer(&self, ctx: &CallContext, user_message: &ChatMessage, history: &[ChatMessage]) -> Result<String> {
+        tlog!("LLM", ">>> 用户消息（仅问答）: {}", user_message.content);
+        let mut messages = Vec::with_capacity(history.len() + 2);
+        messages.push(ChatMessage::system(ANSWER_ONLY_PROMPT));
+        messages.extend_from_slice(history);
+        messages.push(user_message.clone());
+        let message = self.request(&messages, None, ctx, self.streaming(Streaming::Text)).await?;
+        let content = message_content(&message);
+        if content.trim().is_empty() {
+            anyhow::bail!("LLM 返回了空响应");
+        }
+        tlog!("LLM", "意图: 问答 → {}", truncate_str(&content, 200));
+        Ok(content)
+    }
+
+    /// 提示词分类：模型在回复文本中给出 JSON 意图。开启 `stream` 时边生成边把问答内容推送到 `ctx`。
+    async fn classify_with_prompt(&self, ctx: &CallContext, messages: &[ChatMessage]) -> Result<LlmIntent> {
+        let message = self.request(messages, None, ctx, self.streaming(Streaming::Intent)).await?;
+        let raw = message_content(&message);
+        tlog!("LLM", "<<< 原始响应 ({} 字符): {}", raw.len(), raw);
+
+        match parse_intent(&raw) {
+            Ok(intent) => Ok(intent),
+            Err(error) => self.repair_intent(ctx, messages, raw.clone(), error, raw).await,
+        }
+    }
+
+    /// 修复往返：把上一次的输出 `last` 与校验错误发回给模型，要求按提示词格式重新给出合法 JSON；
+    /// 全部失败时把 `fallback`（模型的原始正文）作为回答，正文为空则返回错误。
+    async fn repair_intent(
+        &self,
+        ctx: &CallContext,
+        messages: &[ChatMessage],
+        mut last: String,
+        mut error: String,
+        fallback: String,
+    ) -> Result<LlmIntent> {
+        let mut conversation = messages.to_vec();
+        for attempt in 1..=self.config.repair_attempts {
+            tlog!("LLM", "意图响应不合法 ({})，第 {}/{} 次请求修复", error, attempt, self.config.repair_attempts);
+            conversation.push(ChatMessage::assistant(last));
+            conversation.push(ChatMessage::user(format!("{REPAIR_PROMPT}\n\n错误：{error}")));
+            let message = self.request(&conversation, None, ctx, Streaming::Off).await?;
+            last = message_content(&message);
+            tlog!("LLM", "<<< 修复响应 ({} 字符): {}", last.len(), last);
+            match parse_intent(&last) {
+                Ok(intent) => return Ok(intent),
+                Err(e) => error = e,
+            }
+        }
+        if fallback.trim().is_empty() {
+            anyhow::bail!("LLM 返回了空响应（意图不合法: {error}）");
+        }
+        tlog!("LLM", "修复失败 ({})，把原始响应作为回答", error);
+        Ok(LlmIntent::Question { content: fallback })
+    }
+
+    /// tools 分类（不使用流式响应）：解析 tool_calls；模型未调用工具时按提示词格式解析正文。tool_calls 或正文不合法时
+    /// 与提示词分类一样走修复往返，仍失败则把正文当作回答（正文为空时报错）。
+    async fn classify_with_tools(&self, ctx: &CallContext, messages: &[ChatMessage], skills: &[Skill]) -> Result<LlmIntent> {
+        let tools = build_tools(skills);
+        tlog!("LLM", "tools 模式，声明 {} 个工具", tools.len());
+        let extra = json!({ "tools": tools, "tool_choice": "auto" });
+        let message = self.request(messages, Some(extra), ctx, Streaming::Off).await?;
+
+        let content = message
+            .get("content")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        if let Some(calls) = message.get("tool_calls").and_then(|v| v.as_array()) {
+            if !calls.is_empty() {
+                let calls_text = Value::Array(calls.clone()).to_string();
+                tlog!("LLM", "<<< tool_calls: {}", calls_text);
+                let error = match intent_from_tool_calls(calls, skills) {
+                    Ok(intent) => match validate_intent(&intent) {
+                        Ok(()) => return Ok(intent),
+                        Err(e) => format!("tool_calls 给出的意图不合法: {e}"),
+                    },
+                    Err(e) => format!("{e:#}"),
+                };
+                // 修复请求不再声明工具，把这次的工具调用以文本形式回放给模型
+                return self.repair_intent(ctx, messages, calls_text, error, content).await;
+            }
+        }
+        tlog!("LLM", "<<< 未调用工具，正文 ({} 字符): {}", content.len(), content);
+        match parse_intent(&content) {
+            Ok(intent) => Ok(intent),
+            Err(error) => self.repair_intent(ctx, messages, content.clone(), error, content).await,
+        }
+    }
+
+    /// Agent 模式的一步：回放已执行命令及其结果，返回下一条命令或最终回答。
+    /// `force_final` 为 true 时（预算用尽）要求模型直接给出结论。
+    #[allow(clippy::too_many_arguments)]
+    pub async fn agent_step(
+        &self,
+        ctx: &CallContext,
+        user_message: &ChatMessage,
+        prompt_suffix: Option<&str>,
+        history: &[ChatMessage],
+        steps: &[AgentStep<'_>],
+        output_chars: usize,
+        force_final: bool,
+    ) -> Result<AgentAction> {
+        let mut system_prompt = AGENT_PROMPT.to_string();
+        if let Some(suffix) = prompt_suffix {
+            system_prompt.push_str(suffix);
+        }
+        let mut messages = Vec::with_capacity(history.len() + steps.len() * 2 + 3);
+        messages.push(ChatMessage::system(system_prompt));
+        messages.extend_from_slice(history);
+        messages.push(user_message.clone());
+        for step in steps {
+            let action = json!({ "type": "run", "command": step.command, "description": step.description });
+            messages.push(ChatMessage::assistant(action.to_string()));
+            messages.push(ChatMessage::user(format!(
+                "命令执行结果：\n退出码: {:?}\nstdout:\n{}\nstderr:\n{}",
+                step.exit_code,
+                truncate_str(step.stdout, output_chars),
+                truncate_str(step.stderr, output_chars),
+            )));
+        }
+        if force_final {
+            messages.push(ChatMessage::user(AGENT_FORCE_FINAL));
+        }
+
+        tlog!("LLM", "Agent 第 {} 步{}", steps.len() + 1, if force_final { "（要求给出结论）" } else { "" });
+        let raw = self.call_api_messages(ctx, &messages).await?;
+        tlog!("LLM", "<<< Agent 响应: {}", truncate_str(&raw, 500));
+        let action = match serde_json::from_str::<AgentAction>(&extract_json_object(&raw)) {
+            Ok(AgentAction::Run { command, .. }) if force_final => AgentAction::Final {
+                answer: format!("预算已用尽，未能得出结论（模型仍想执行: {command}）"),
+            },
+            Ok(action) => action,
+            Err(e) => {
+                tlog!("LLM", "Agent 响应不是合法 JSON，视为最终回答: {}", e);
+                AgentAction::Final { answer: raw }
+            }
+        };
+        Ok(action)
+    }
+
     /// 根据命令执行失败信息向 LLM 询问解决方式，返回建议内容。
     /// `skill_context` 可选，为相关 skill 的 prompt_hint 等，用于让修正建议与已安装技能一致。
     pub async fn ask_fix_for_failure(
         &self,
+        ctx: &CallContext,
         command: &str,
         exit_code: Option<i32>,
         stderr: &str,
         skill_context: Option<&str>,
     ) -> Result<String> {
         const MAX_STDERR: usize = 3500;
-        let stderr_trim = if stderr.len() > MAX_STDERR {
-            let mut end = MAX_STDERR;
-            while end > 0 && !stderr.is_char_boundary(end) {
-                end -= 1;
-            }
-            format!("{}...(截断)", &stderr[..end])
-        } else {
-            stderr.to_string()
-        };
+        let stderr_trim = truncate_str(stderr, MAX_STDERR);
         let user_message = format!(
             "执行失败的命令：\n{}\n\n退出码：{:?}\n\n错误输出（stderr）：\n{}",
             command,
@@ -138,50 +693,219 @@ impl LlmClient {
         if skill_context.map(|s| !s.is_empty()).unwrap_or(false) {
             tlog!("LLM", "已注入相关 skill 上下文 ({} 字符)", skill_context.unwrap().len());
         }
-        self.call_api(&system_prompt, &user_message).await
+        self.call_api(ctx, &system_prompt, &user_message).await
     }
 
-    /// 根据讲稿内容生成乔布斯风竖屏 HTML 演示稿（不依赖 Python 模块）。只返回完整 HTML 字符串。
-    pub async fn generate_ppt_html(&self, script: &str) -> Result<String> {
+    /// 根据标题与讲稿内容生成乔布斯风竖屏 HTML 演示稿（不依赖 Python 模块）。只返回完整 HTML 字符串。
+    pub async fn generate_ppt_html(&self, ctx: &CallContext, title: &str, script: &str) -> Result<String> {
         const PPT_SYSTEM: &str = r#"你是乔布斯风极简科技感演示稿生成器。根据用户给的讲稿，直接输出一份完整的、可单独在浏览器打开的 HTML 文件。
 要求：单文件、竖屏 9:16、背景 #0a0a0a 或 #000、主文字 #fff、极简、留白多、一屏一事。使用 TailwindCSS（CDN）和 Vue3（CDN），支持键盘左右键翻页、底部进度条、平滑切换。
 只输出完整 HTML 代码，不要任何解释、不要 markdown 代码块包裹；若必须用代码块则仅用 ```html 与 ``` 包裹，我会自动提取。"#;
-        let user_msg = format!("请根据以下讲稿生成一份乔布斯风竖屏 HTML 演示稿：\n\n{}", script);
-        let raw = self.call_api(PPT_SYSTEM, &user_msg).await?;
+        let user_msg = format!(
+            "请根据以下讲稿生成一份乔布斯风竖屏 HTML 演示稿，标题为「{title}」（用作封面标题与 HTML 的 <title>）：\n\n{script}"
+        );
+        let raw = self.call_api(ctx, PPT_SYSTEM, &user_msg).await?;
         let html = extract_html_from_response(&raw);
         Ok(html)
     }
 
-    async fn call_api(&self, system_prompt: &str, user_message: &str) -> Result<String> {
+    /// 将较早的对话压缩为摘要；`previous` 为已有摘要，会与新对话合并。
+    pub async fn summarize_conversation(&self, ctx: &CallContext, previous: Option<&str>, turns: &[ChatMessage]) -> Result<String> {
+        let mut transcript = String::new();
+        if let Some(prev) = previous {
+            transcript.push_str(&format!("此前的摘要：\n{prev}\n\n"));
+        }
+        transcript.push_str("需要压缩的对话：\n");
+        for m in turns {
+            let who = if m.role == "user" { "用户" } else { "bot" };
+            transcript.push_str(&format!("{who}: {}\n", m.content));
+        }
+        tlog!("LLM", "摘要 {} 条历史消息", turns.len());
+        let summary = self.call_api(ctx, SUMMARY_PROMPT, &transcript).await?;
+        Ok(summary.trim().to_string())
+    }
+
+    async fn call_api(&self, ctx: &CallContext, system_prompt: &str, user_message: &str) -> Result<String> {
+        self.call_api_messages(ctx, &[ChatMessage::system(system_prompt), ChatMessage::user(user_message)])
+            .await
+    }
+
+    /// 配置开启 `stream` 时返回 `kind`，否则不流式
+    fn streaming(&self, kind: Streaming) -> Streaming {
+        if self.config.stream { kind } else { Streaming::Off }
+    }
+
+    async fn call_api_messages(&self, ctx: &CallContext, messages: &[ChatMessage]) -> Result<String> {
+        let message = self.request(messages, None, ctx, Streaming::Off).await?;
+        Ok(message_content(&message))
+    }
+
+    /// 按顺序向各提供方发送 chat completions 请求，返回第一个成功的 `choices[0].message`。
+    /// 超时、网络错误、5xx 与 429 先按指数退避重试同一提供方，用尽后切换到下一个；
+    /// 处于熔断中的提供方被跳过，冷却结束后放行一次试探请求。`extra` 中的字段（如 tools）会合并进请求体。
+    /// `streaming` 不为 Off 时请求 SSE 流式响应，后端不支持时按普通响应处理。
+    async fn request(&self, messages: &[ChatMessage], extra: Option<Value>, ctx: &CallContext, streaming: Streaming) -> Result<Value> {
+        if let Some(user_id) = ctx.quota_user() {
+            self.usage.check_quota(user_id)?;
+        }
+        let cooldown = Duration::from_secs(self.config.circuit_cooldown_secs);
+        let wants_tools = extra.as_ref().is_some_and(|e| e.get("tools").is_some());
+        let mut skipped_for_tools = false;
+        let mut last_err = None;
+        for provider in &self.providers {
+            if wants_tools && !provider.supports_tools() {
+                tlog!("LLM", "跳过不支持 tools 的提供方 {}", provider.name());
+                skipped_for_tools = true;
+                continue;
+            }
+            // 逐个判定：前面的提供方成功时，后面半开的提供方不会被占用试探机会
+            if !provider.admit(cooldown) {
+                tlog!("LLM", "跳过熔断中的提供方 {}", provider.name());
+                continue;
+            }
+            match self.request_with_retry(provider, messages, &extra, ctx, streaming).await {
+                Ok((message, usage)) => {
+                    provider.record_success();
+                    tlog!("LLM", "由提供方 {} 应答", provider.name());
+                    info!(provider = provider.name(), "LLM 应答");
+                    ctx.answered_by(provider.name());
+                    if let Some(usage) = usage {
+                        let model = provider.config.model_for(has_images(messages));
+                        if let Err(e) = self.usage.record(ctx.chat_id, ctx.user_id, model, usage) {
+                            tlog!("LLM", "记录用量失败: {}", e);
+                        }
+                    }
+                    return Ok(message);
+                }
+                Err(e) if wants_tools && is_tools_unsupported(&e) => {
+                    // 提供方能正常应答，只是不接受 tools：不计入熔断，之后带 tools 的请求跳过它
+                    tlog!("LLM", "提供方 {} 不支持 tools，尝试下一个: {:#}", provider.name(), e);
+                    provider.tools_unsupported.store(true, Ordering::Relaxed);
+                    skipped_for_tools = true;
+                    last_err = Some(e.context(format!("提供方 {}", provider.name())));
+                }
+                Err(e) if is_transient_error(&e) => {
+                    tlog!("LLM", "提供方 {} 失败，尝试下一个: {:#}", provider.name(), e);
+                    warn!(provider = provider.name(), error = %e, "LLM 提供方失败");
+                    provider.record_failure(self.config.circuit_failure_threshold, cooldown);
+                    last_err = Some(e.context(format!("提供方 {}", provider.name())));
+                }
+                Err(e) => return Err(e.context(format!("提供方 {}", provider.name()))),
+            }
+        }
+        // 有提供方因不支持 tools 被跳过时，交给调用方改用提示词分类（不带 tools 时整条链都可用）
+        if skipped_for_tools {
+            let err = last_err.unwrap_or_else(|| anyhow::anyhow!(ToolsUnsupported));
+            return Err(match err.downcast_ref::<ToolsUnsupported>() {
+                Some(_) => err,
+                None => err.context(ToolsUnsupported),
+            });
+        }
+        let err = match last_err {
+            Some(e) => e,
+            None if self.providers.is_empty() => anyhow::anyhow!("未配置 LLM 提供方"),
+            None => anyhow::anyhow!("所有提供方都处于熔断中，{}s 内将放行试探请求", cooldown.as_secs()),
+        };
+        Err(err.context("所有 LLM 提供方均失败"))
+    }
+
+    /// 对单个提供方发送请求，临时性错误按退避间隔重试，最多 `max_retries` 次。
+    async fn request_with_retry(
+        &self,
+        provider: &Provider,
+        messages: &[ChatMessage],
+        extra: &Option<Value>,
+        ctx: &CallContext,
+        streaming: Streaming,
+    ) -> Result<(Value, Option<Usage>)> {
+        let max_retries = self.config.max_retries;
+        let max_delay = Duration::from_secs(self.config.retry_max_secs);
+        let mut attempt = 0;
+        loop {
+            let err = match self.request_provider(&provider.config, messages, extra.clone(), ctx, streaming).await {
+                Ok(message) => return Ok(message),
+                Err(e) => e,
+            };
+            // 失败前可能已推送了部分回答，下一次请求从头生成
+            ctx.set_answer("");
+            if attempt >= max_retries || !is_transient_error(&err) {
+                return Err(err);
+            }
+            attempt += 1;
+            let retry_after = err.downcast_ref::<ApiError>().and_then(|api| api.retry_after);
+            let delay = match retry_after {
+                Some(d) if d > max_delay => {
+                    tlog!("LLM", "Retry-After {}s 超过上限 {}s，放弃重试", d.as_secs(), max_delay.as_secs());
+                    return Err(err);
+                }
+                Some(d) => d,
+                None => backoff_delay(attempt, Duration::from_millis(self.config.retry_base_ms), max_delay),
+            };
+            let reason = failure_reason(&err);
+            tlog!(
+                "LLM",
+                "提供方 {} {}，{:.1}s 后重试 ({}/{})",
+                provider.name(),
+                reason,
+                delay.as_secs_f64(),
+                attempt,
+                max_retries
+            );
+            ctx.retries.send_replace(Some(RetryNotice {
+                provider: provider.name().to_string(),
+                attempt,
+                max_retries,
+                delay,
+                reason,
+            }));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// 向单个提供方发送 chat completions 请求并返回 `choices[0].message` 与 token 用量。
+    async fn request_provider(
+        &self,
+        provider: &ProviderConfig,
+        messages: &[ChatMessage],
+        extra: Option<Value>,
+        ctx: &CallContext,
+        streaming: Streaming,
+    ) -> Result<(Value, Option<Usage>)> {
         let url = format!(
             "{}/chat/completions",
-            self.config.base_url.trim_end_matches('/')
+            provider.base_url.trim_end_matches('/')
         );
 
-        let body = json!({
-            "model": self.config.model,
-            "max_tokens": self.config.max_tokens,
-            "messages": [
-                { "role": "system", "content": system_prompt },
-                { "role": "user", "content": user_message },
-            ]
+        let model = provider.model_for(has_images(messages));
+        let mut body = json!({
+            "model": model,
+            "max_tokens": provider.max_tokens.unwrap_or(self.config.max_tokens),
+            "messages": messages,
         });
+        if let (Some(Value::Object(extra)), Some(obj)) = (extra, body.as_object_mut()) {
+            obj.extend(extra);
+        }
+        if streaming != Streaming::Off {
+            body["stream"] = json!(true);
+            body["stream_options"] = json!({ "include_usage": true });
+        }
 
-        tlog!("LLM", "模型: {}", self.config.model);
+        tlog!("LLM", "提供方: {} / 模型: {}", provider.display_name(), model);
         tlog!("LLM", "URL: {}", url);
-        tlog!("LLM", "超时: {}s", LLM_TIMEOUT_SECS);
-        tlog!("LLM", "请求体: {}", serde_json::to_string_pretty(&body).unwrap_or_default());
-        info!(model = %self.config.model, "调用 LLM");
-        debug!(url = %url, body = %body, "LLM 请求");
+        tlog!("LLM", "超时: {}s", self.config.request_timeout_secs);
+        let logged_body = redact_images(&body);
+        tlog!("LLM", "请求体: {}", serde_json::to_string_pretty(&logged_body).unwrap_or_default());
+        info!(provider = provider.display_name(), model = %model, "调用 LLM");
+        debug!(url = %url, body = %logged_body, "LLM 请求");
 
         let start = Instant::now();
         tlog!("LLM", "发送请求...");
 
-        let resp = self
-            .client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .header("Content-Type", "application/json")
+        let mut req = self.client.post(&url).header("Content-Type", "application/json");
+        if !provider.api_key.is_empty() {
+            req = req.header("Authorization", format!("Bearer {}", provider.api_key));
+        }
+        let resp = req
             .json(&body)
             .send()
             .await
@@ -194,27 +918,51 @@ impl LlmClient {
         tlog!("LLM", "响应头: {}", headers);
 
         if !status.is_success() {
+            let retry_after = resp
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
             let text = resp.text().await.unwrap_or_default();
             tlog!("LLM", "错误响应体: {}", text);
-            anyhow::bail!("LLM API 错误 {status}: {text}");
+            return Err(ApiError {
+                status,
+                body: text,
+                retry_after,
+            }
+            .into());
         }
 
-        tlog!("LLM", "读取响应体...");
-        let raw_text = resp.text().await.context("读取 LLM 响应体失败")?;
-        let body_elapsed = start.elapsed();
-        tlog!("LLM", "响应体大小: {} 字节 (总耗时 {:.2}s)", raw_text.len(), body_elapsed.as_secs_f64());
-        tlog!("LLM", "完整响应: {}", truncate_str(&raw_text, 2000));
+        let is_event_stream = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        let result = if streaming != Streaming::Off && is_event_stream {
+            tlog!("LLM", "读取流式响应...");
+            let result = read_event_stream(resp, ctx, streaming).await?;
+            tlog!("LLM", "流式响应结束 (总耗时 {:.2}s)", start.elapsed().as_secs_f64());
+            result
+        } else {
+            if streaming != Streaming::Off {
+                tlog!("LLM", "后端未返回 SSE，按普通响应处理");
+            }
+            tlog!("LLM", "读取响应体...");
+            let raw_text = resp.text().await.context("读取 LLM 响应体失败")?;
+            let body_elapsed = start.elapsed();
+            tlog!("LLM", "响应体大小: {} 字节 (总耗时 {:.2}s)", raw_text.len(), body_elapsed.as_secs_f64());
+            tlog!("LLM", "完整响应: {}", truncate_str(&raw_text, 2000));
+            serde_json::from_str::<Value>(&raw_text).context("LLM 响应 JSON 解析失败")?
+        };
 
-        let result: Value = serde_json::from_str(&raw_text).context("LLM 响应 JSON 解析失败")?;
+        let message = result
+            .pointer("/choices/0/message")
+            .cloned()
+            .unwrap_or(Value::Null);
 
-        let content = result
-            .pointer("/choices/0/message/content")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-
-        if let Some(u) = result.pointer("/usage") {
-            tlog!("LLM", "Token 用量: {}", u);
+        let usage = result.pointer("/usage").and_then(Usage::from_json);
+        if let Some(u) = &usage {
+            tlog!("LLM", "Token 用量: 输入 {} / 输出 {} / 合计 {}", u.prompt_tokens, u.completion_tokens, u.total_tokens);
         }
         if let Some(model) = result.pointer("/model").and_then(|v| v.as_str()) {
             tlog!("LLM", "实际模型: {}", model);
@@ -223,20 +971,334 @@ impl LlmClient {
         let total = start.elapsed();
         tlog!("LLM", "总耗时: {:.2}s", total.as_secs_f64());
 
-        Ok(content)
+        Ok((message, usage))
     }
 }
 
-/// 按字节截断到 max，保证在 UTF-8 字符边界处切断，避免 panic。
-fn truncate_str(s: &str, max: usize) -> String {
-    if s.len() <= max {
-        return s.to_string();
+fn has_images(messages: &[ChatMessage]) -> bool {
+    messages.iter().any(|m| !m.images.is_empty())
+}
+
+/// 日志用的请求体副本：把图片的 data URL 替换为长度说明，避免 base64 刷屏
+fn redact_images(body: &Value) -> Value {
+    let mut body = body.clone();
+    if let Some(messages) = body.get_mut("messages").and_then(|v| v.as_array_mut()) {
+        for part in messages
+            .iter_mut()
+            .filter_map(|m| m.get_mut("content").and_then(|c| c.as_array_mut()))
+            .flatten()
+        {
+            if let Some(url) = part.pointer_mut("/image_url/url") {
+                let len = url.as_str().map(str::len).unwrap_or(0);
+                *url = json!(format!("<图片 {len} 字节>"));
+            }
+        }
     }
-    let mut end = max;
-    while end > 0 && !s.is_char_boundary(end) {
-        end -= 1;
+    body
+}
+
+fn message_content(message: &Value) -> String {
+    message
+        .get("content")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// 读取 SSE 流式响应，拼接各 `delta.content`，并组装成与普通响应相同结构的 JSON
+/// （`choices[0].message`、`usage`、`model`）。生成过程中按 `streaming` 把部分回答推送到 `ctx`。
+/// 服务端关闭连接时最后一行没有换行的，同样按一行处理。
+async fn read_event_stream(mut resp: reqwest::Response, ctx: &CallContext, streaming: Streaming) -> Result<Value> {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut content = String::new();
+    let mut result = json!({});
+    let mut done = false;
+    'read: while let Some(chunk) = resp.chunk().await.context("读取 LLM 流式响应失败")? {
+        pending.extend_from_slice(&chunk);
+        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=pos).collect();
+            if handle_sse_line(&String::from_utf8_lossy(&line), &mut content, &mut result, ctx, streaming)? {
+                done = true;
+                break 'read;
+            }
+        }
+    }
+    if !done && !pending.is_empty() {
+        handle_sse_line(&String::from_utf8_lossy(&pending), &mut content, &mut result, ctx, streaming)?;
+    }
+    tlog!("LLM", "流式响应内容 ({} 字符): {}", content.len(), truncate_str(&content, 2000));
+    result["choices"] = json!([{ "message": { "role": "assistant", "content": content } }]);
+    Ok(result)
+}
+
+/// 处理 SSE 流中的一行：`data:` 事件的增量内容追加到 `content`，`usage` 与 `model` 写入 `result`。
+/// 收到 `[DONE]` 时返回 true。
+fn handle_sse_line(
+    line: &str,
+    content: &mut String,
+    result: &mut Value,
+    ctx: &CallContext,
+    streaming: Streaming,
+) -> Result<bool> {
+    let Some(data) = line.trim().strip_prefix("data:") else { return Ok(false) };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(true);
+    }
+    let Ok(event) = serde_json::from_str::<Value>(data) else {
+        tlog!("LLM", "忽略无法解析的 SSE 事件: {}", truncate_str(data, 200));
+        return Ok(false);
+    };
+    if let Some(err) = event.get("error") {
+        anyhow::bail!("LLM 流式响应返回错误: {}", err);
+    }
+    if let Some(delta) = event.pointer("/choices/0/delta/content").and_then(|v| v.as_str()) {
+        content.push_str(delta);
+        match streaming {
+            Streaming::Text => ctx.set_answer(content),
+            Streaming::Intent => {
+                if let Some(partial) = partial_answer(content) {
+                    ctx.set_answer(&partial);
+                }
+            }
+            Streaming::Off => {}
+        }
+    }
+    for key in ["usage", "model"] {
+        if let Some(v) = event.get(key).filter(|v| !v.is_null()) {
+            result[key] = v.clone();
+        }
+    }
+    Ok(false)
+}
+
+/// 从仍在生成中的提示词分类 JSON 里取出问答内容：`type` 已确定为 question 时，
+/// 解码 `content` 字段中已到达的部分（未闭合的字符串也返回），其他情况返回 None。
+fn partial_answer(raw: &str) -> Option<String> {
+    let type_at = raw.find("\"type\"")?;
+    let after_type = raw[type_at + 6..].trim_start().strip_prefix(':')?.trim_start();
+    if !after_type.starts_with("\"question\"") {
+        return None;
+    }
+    let content_at = raw.find("\"content\"")?;
+    let value = raw[content_at + 9..].trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                let Some(esc) = chars.next() else { break };
+                match esc {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    'b' | 'f' => {}
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(ch) if hex.len() == 4 => out.push(ch),
+                            // 代理对或尚未到齐的转义，跳过
+                            _ => {}
+                        }
+                    }
+                    other => out.push(other),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// 值得重试或切换提供方的临时性错误：超时、网络错误、5xx（501 表示不支持该功能，除外）与 429。
+fn is_transient_error(e: &anyhow::Error) -> bool {
+    if let Some(api) = e.downcast_ref::<ApiError>() {
+        let status = api.status;
+        return status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || (status.is_server_error() && status != reqwest::StatusCode::NOT_IMPLEMENTED);
+    }
+    e.chain()
+        .filter_map(|c| c.downcast_ref::<reqwest::Error>())
+        .any(|r| r.is_timeout() || r.is_connect() || r.is_request() || r.is_body())
+}
+
+/// 日志与重试提示中的简短失败原因
+fn failure_reason(e: &anyhow::Error) -> String {
+    if let Some(api) = e.downcast_ref::<ApiError>() {
+        return format!("HTTP {}", api.status.as_u16());
+    }
+    let timed_out = e
+        .chain()
+        .filter_map(|c| c.downcast_ref::<reqwest::Error>())
+        .any(|r| r.is_timeout());
+    if timed_out {
+        "超时".to_string()
+    } else {
+        "网络错误".to_string()
+    }
+}
+
+/// 解析 Retry-After：秒数或 HTTP 日期。
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = at.signed_duration_since(chrono::Utc::now());
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// 第 `attempt` 次重试的等待时间：`base * 2^(attempt-1)`，不超过 `max`，并在 [50%, 100%] 间随机抖动，
+/// 避免多个任务同时重试。
+fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let exp = base.saturating_mul(1u32 << (attempt - 1).min(16)).min(max);
+    exp.mul_f64(0.5 + 0.5 * jitter())
+}
+
+/// [0, 1) 内的随机数。RandomState 每次创建都使用新的随机键，足够用于退避抖动。
+fn jitter() -> f64 {
+    use std::hash::BuildHasher;
+    let bits = std::collections::hash_map::RandomState::new().hash_one(()) >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+/// 后端拒绝 tools 参数时通常返回 400 / 404 / 422 / 501，且错误信息提到 tools 或 function calling；
+/// 只看状态码会把上下文过长、图片过大等普通 400 也当成不支持 tools。
+fn is_tools_unsupported(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ApiError>().is_some_and(|api| {
+        let body = api.body.to_ascii_lowercase();
+        matches!(api.status.as_u16(), 400 | 404 | 422 | 501) && (body.contains("tool") || body.contains("function"))
+    })
+}
+
+/// skill id 转为合法的工具名（仅字母数字、下划线、连字符，最长 64）。
+fn skill_tool_name(id: &str) -> String {
+    let name: String = format!("{SKILL_TOOL_PREFIX}{id}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    name.chars().take(64).collect()
+}
+
+fn commands_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "commands": {
+                "type": "array",
+                "description": "按顺序执行的 shell 命令",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "command": { "type": "string", "description": "shell 命令" },
+                        "description": { "type": "string", "description": "说明" }
+                    },
+                    "required": ["command"]
+                }
+            }
+        },
+        "required": ["commands"]
+    })
+}
+
+fn function_tool(name: &str, description: &str, parameters: Value) -> Value {
+    json!({
+        "type": "function",
+        "function": { "name": name, "description": description, "parameters": parameters }
+    })
+}
+
+/// 分类用的工具列表：回答问题、执行命令、发送文件，以及每个 skill 一个工具
+/// （可执行技能的参数为其声明的参数，其余技能的参数同执行命令）。
+fn build_tools(skills: &[Skill]) -> Vec<Value> {
+    let mut tools = vec![
+        function_tool(
+            TOOL_ANSWER,
+            "用户在提问或闲聊，不需要在服务器上执行操作时调用，直接给出完整回答",
+            json!({
+                "type": "object",
+                "properties": { "content": { "type": "string", "description": "给用户的完整回答" } },
+                "required": ["content"]
+            }),
+        ),
+        function_tool(
+            TOOL_RUN_COMMANDS,
+            "用户需要在服务器上执行操作时调用，给出要执行的 shell 命令",
+            commands_schema(),
+        ),
+        function_tool(
+            TOOL_SEND_FILE,
+            "用户想要服务器上的某个已有文件时调用，把该文件发送给用户",
+            json!({
+                "type": "object",
+                "properties": { "path": { "type": "string", "description": "文件路径（绝对路径或相对工作目录）" } },
+                "required": ["path"]
+            }),
+        ),
+    ];
+    for sk in skills {
+        let description = if sk.prompt_hint.is_empty() {
+            format!("技能「{}」：{}", sk.name, sk.description)
+        } else {
+            format!("技能「{}」：{}。{}", sk.name, sk.description, sk.prompt_hint)
+        };
+        let parameters = if sk.is_executable() { skills::args_schema(sk) } else { commands_schema() };
+        tools.push(function_tool(&skill_tool_name(&sk.id), &description, parameters));
+    }
+    tools
+}
+
+/// 将 tool_calls 转为意图：所有命令类调用的命令合并执行；没有命令时依次取可执行技能调用、
+/// send_file 的文件、answer_question 的回答。
+fn intent_from_tool_calls(calls: &[Value], skills: &[Skill]) -> Result<LlmIntent> {
+    let mut commands = Vec::new();
+    let mut commands_called = false;
+    let mut answer = None;
+    let mut download = None;
+    let mut skill_call = None;
+    for call in calls {
+        let name = call.pointer("/function/name").and_then(|v| v.as_str()).unwrap_or("");
+        let args = call
+            .pointer("/function/arguments")
+            .and_then(|v| v.as_str())
+            .unwrap_or("{}");
+        if name == TOOL_ANSWER {
+            let a: AnswerArgs = serde_json::from_str(args)
+                .with_context(|| format!("无法解析 {name} 参数: {args}"))?;
+            answer.get_or_insert(a.content);
+        } else if name == TOOL_SEND_FILE {
+            let a: SendFileArgs = serde_json::from_str(args)
+                .with_context(|| format!("无法解析 {name} 参数: {args}"))?;
+            download.get_or_insert(a.path);
+        } else if let Some(sk) = skills.iter().find(|sk| sk.is_executable() && skill_tool_name(&sk.id) == name) {
+            let args: Map<String, Value> = serde_json::from_str(args)
+                .with_context(|| format!("无法解析 {name} 参数: {args}"))?;
+            skill_call.get_or_insert(LlmIntent::Skill { id: sk.id.clone(), args });
+        } else if name == TOOL_RUN_COMMANDS || name.starts_with(SKILL_TOOL_PREFIX) {
+            let a: CommandsArgs = serde_json::from_str(args)
+                .with_context(|| format!("无法解析 {name} 参数: {args}"))?;
+            commands.extend(a.commands);
+            commands_called = true;
+        } else {
+            tlog!("LLM", "忽略未知工具调用: {}", name);
+        }
+    }
+    if !commands.is_empty() {
+        return Ok(LlmIntent::Command { commands });
+    }
+    if let Some(intent) = skill_call {
+        return Ok(intent);
+    }
+    match (download, answer) {
+        (Some(path), _) => Ok(LlmIntent::Download { path }),
+        (None, Some(content)) => Ok(LlmIntent::Question { content }),
+        // 调用了命令工具但命令列表为空，交给校验报告具体错误
+        (None, None) if commands_called => Ok(LlmIntent::Command { commands }),
+        (None, None) => anyhow::bail!("tool_calls 中没有可用的意图"),
     }
-    format!("{}...", &s[..end])
 }
 
 fn extract_html_from_response(text: &str) -> String {
@@ -263,19 +1325,381 @@ fn extract_html_from_response(text: &str) -> String {
     text.to_string()
 }
 
+/// 从模型输出中取出 JSON 对象：跳过前后说明文字与代码块标记，按括号配对（忽略字符串内的括号）
+/// 找到完整的对象，优先返回第一个能解析的；只有未闭合的对象（输出被截断）时返回从 `{` 到结尾的部分。
 fn extract_json_object(text: &str) -> String {
-    if let Some(start) = text.find("```") {
-        let after_backticks = &text[start + 3..];
-        let content_start = after_backticks.find('\n').map(|i| i + 1).unwrap_or(0);
-        let content = &after_backticks[content_start..];
-        if let Some(end) = content.find("```") {
-            return content[..end].trim().to_string();
+    let mut first_balanced = None;
+    for (start, _) in text.match_indices('{') {
+        let Some(end) = matching_brace(&text[start..]) else { continue };
+        let candidate = &text[start..start + end + 1];
+        if serde_json::from_str::<Value>(candidate).is_ok_and(|v| v.is_object()) {
+            return candidate.to_string();
+        }
+        first_balanced.get_or_insert(candidate);
+    }
+    match (first_balanced, text.find('{')) {
+        (Some(candidate), _) => candidate.to_string(),
+        (None, Some(start)) => text[start..].trim_end().trim_end_matches("```").trim_end().to_string(),
+        (None, None) => text.trim().to_string(),
+    }
+}
+
+/// `s` 以 `{` 开头，返回与之配对的 `}` 的字节位置。
+fn matching_brace(s: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// 解析并校验提示词分类的输出，失败时返回发给模型修复用的错误说明。
+fn parse_intent(raw: &str) -> std::result::Result<LlmIntent, String> {
+    let json_text = extract_json_object(raw);
+    if !json_text.starts_with('{') {
+        return Err("回复中没有 JSON 对象".to_string());
+    }
+    let intent = serde_json::from_str::<LlmIntent>(&json_text).map_err(|e| format!("JSON 不符合意图格式: {e}"))?;
+    validate_intent(&intent)?;
+    Ok(intent)
+}
+
+/// 检查意图内容是否合理：回答、文件路径与技能 id 非空，命令列表非空、每条命令非空且长度在上限内。
+fn validate_intent(intent: &LlmIntent) -> std::result::Result<(), String> {
+    match intent {
+        LlmIntent::Question { content } if content.trim().is_empty() => Err("content 为空".to_string()),
+        LlmIntent::Question { .. } => Ok(()),
+        LlmIntent::Download { path } if path.trim().is_empty() => Err("path 为空".to_string()),
+        LlmIntent::Download { .. } => Ok(()),
+        LlmIntent::Skill { id, .. } if id.trim().is_empty() => Err("技能 id 为空".to_string()),
+        LlmIntent::Skill { .. } => Ok(()),
+        LlmIntent::Command { commands } => {
+            if commands.is_empty() {
+                return Err("commands 为空；不需要执行命令时请返回 question 类型的回答".to_string());
+            }
+            if commands.len() > MAX_INTENT_COMMANDS {
+                return Err(format!("commands 共 {} 条，超过上限 {MAX_INTENT_COMMANDS}", commands.len()));
+            }
+            for (i, c) in commands.iter().enumerate() {
+                if c.command.trim().is_empty() {
+                    return Err(format!("第 {} 条 command 为空", i + 1));
+                }
+                if c.command.len() > MAX_COMMAND_CHARS {
+                    return Err(format!("第 {} 条 command 长度 {} 超过上限 {MAX_COMMAND_CHARS}", i + 1, c.command.len()));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{stub_server, StubRequest};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    #[test]
+    fn extract_json_object_handles_malformed_outputs() {
+        let cases: &[(&str, &str)] = &[
+            // 纯 JSON
+            (r#"{"type":"question","content":"hi"}"#, r#"{"type":"question","content":"hi"}"#),
+            // 前后有说明文字
+            (
+                "好的，结果如下：\n{\"type\":\"question\",\"content\":\"hi\"}\n希望有帮助 :)",
+                r#"{"type":"question","content":"hi"}"#,
+            ),
+            // ```json 代码块
+            (
+                "```json\n{\"type\":\"command\",\"commands\":[]}\n```",
+                r#"{"type":"command","commands":[]}"#,
+            ),
+            // 无语言标记的代码块，块后还有文字
+            (
+                "```\n{\"type\":\"question\",\"content\":\"a\"}\n```\n以上。",
+                r#"{"type":"question","content":"a"}"#,
+            ),
+            // 字符串内含括号
+            (
+                r#"{"type":"question","content":"用 {} 和 } 占位"}"#,
+                r#"{"type":"question","content":"用 {} 和 } 占位"}"#,
+            ),
+            // 字符串内含转义引号与反斜杠
+            (
+                r#"{"type":"command","commands":[{"command":"echo \"}\" \\","description":"x"}]}"#,
+                r#"{"type":"command","commands":[{"command":"echo \"}\" \\","description":"x"}]}"#,
+            ),
+            // 结尾说明中带右括号
+            (
+                "{\"type\":\"question\",\"content\":\"hi\"} (注意: 这是 JSON})",
+                r#"{"type":"question","content":"hi"}"#,
+            ),
+            // 说明文字里先出现了非 JSON 的花括号
+            (
+                "格式为 {type, content}：{\"type\":\"question\",\"content\":\"hi\"}",
+                r#"{"type":"question","content":"hi"}"#,
+            ),
+            // 两个对象，取第一个合法的
+            (
+                "{\"type\":\"question\",\"content\":\"1\"}\n{\"type\":\"question\",\"content\":\"2\"}",
+                r#"{"type":"question","content":"1"}"#,
+            ),
+            // 被截断的输出：返回从 { 到结尾
+            (
+                "```json\n{\"type\":\"question\",\"content\":\"未完",
+                "{\"type\":\"question\",\"content\":\"未完",
+            ),
+            // 配对完整但不是合法 JSON（尾逗号），原样返回以便报告错误
+            (
+                "{\"type\":\"question\",\"content\":\"hi\",}",
+                "{\"type\":\"question\",\"content\":\"hi\",}",
+            ),
+            // 没有 JSON
+            ("  我不确定你的意思  ", "我不确定你的意思"),
+            ("", ""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(&extract_json_object(input), expected, "input: {input:?}");
         }
     }
-    if let Some(start) = text.find('{') {
-        if let Some(end) = text.rfind('}') {
-            return text[start..=end].to_string();
+
+    #[test]
+    fn parse_intent_validates_schema() {
+        assert!(matches!(
+            parse_intent("```json\n{\"type\":\"command\",\"commands\":[{\"command\":\"ls\"}]}\n```"),
+            Ok(LlmIntent::Command { commands }) if commands.len() == 1
+        ));
+        assert!(matches!(
+            parse_intent(r#"{"type":"download","path":"uploads/1/data.csv"}"#),
+            Ok(LlmIntent::Download { path }) if path == "uploads/1/data.csv"
+        ));
+        let errors = [
+            "纯文本回答",
+            r#"{"type":"shell","commands":[{"command":"ls"}]}"#,
+            r#"{"type":"command","commands":[]}"#,
+            r#"{"type":"command","commands":[{"command":"  "}]}"#,
+            r#"{"type":"question","content":""}"#,
+            r#"{"type":"question"}"#,
+            r#"{"type":"download","path":" "}"#,
+        ];
+        for raw in errors {
+            assert!(parse_intent(raw).is_err(), "应当校验失败: {raw}");
         }
+        let long = format!(r#"{{"type":"command","commands":[{{"command":"{}"}}]}}"#, "x".repeat(MAX_COMMAND_CHARS + 1));
+        assert!(parse_intent(&long).is_err());
+    }
+
+    /// 依次以 200 回答 `bodies.len()` 个请求的 JSON 桩
+    async fn ok_server(bodies: Vec<String>) -> (String, UnboundedReceiver<StubRequest>) {
+        stub_server("application/json", bodies.into_iter().map(|b| ("200 OK", b)).collect()).await
+    }
+
+    fn client(base_url: &str, classify_mode: &str) -> LlmClient {
+        let config: LlmConfig = toml::from_str(&format!(
+            "base_url = \"{base_url}\"\nmodel = \"m\"\nclassify_mode = \"{classify_mode}\"\nstream = false\nmax_retries = 0"
+        ))
+        .unwrap();
+        let usage = UsageStore::new(&crate::config::UsageConfig { enabled: false, ..Default::default() }).unwrap();
+        LlmClient::new(config, Arc::new(usage))
+    }
+
+    fn completion(message: Value) -> String {
+        json!({ "choices": [{ "message": message }] }).to_string()
+    }
+
+    #[tokio::test]
+    async fn invalid_tool_call_is_repaired() {
+        let empty_plan = json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": TOOL_RUN_COMMANDS, "arguments": "{\"commands\":[]}" }
+            }]
+        });
+        let repaired = json!({ "role": "assistant", "content": "{\"type\":\"question\",\"content\":\"无需执行命令\"}" });
+        let (base_url, mut requests) = ok_server(vec![completion(empty_plan), completion(repaired)]).await;
+        let llm = client(&base_url, "tools");
+
+        let intent = llm.classify(&CallContext::default(), &ChatMessage::user("你好"), None, &[], &[]).await.unwrap();
+        assert!(matches!(intent, LlmIntent::Question { content } if content == "无需执行命令"));
+        let first: Value = requests.recv().await.unwrap().json();
+        assert!(first.get("tools").is_some());
+        let repair: Value = requests.recv().await.unwrap().json();
+        assert!(repair.get("tools").is_none(), "修复请求不应再声明工具");
+        let last = repair["messages"].as_array().unwrap().last().unwrap()["content"].as_str().unwrap().to_string();
+        assert!(last.contains("commands 为空"), "{last}");
+    }
+
+    #[tokio::test]
+    async fn unrepairable_tool_call_without_content_fails() {
+        let bad = json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": TOOL_ANSWER, "arguments": "{\"content\":\"\"}" }
+            }]
+        });
+        let still_bad = json!({ "role": "assistant", "content": "" });
+        let bodies = vec![completion(bad), completion(still_bad.clone()), completion(still_bad)];
+        let (base_url, _requests) = ok_server(bodies).await;
+        let llm = client(&base_url, "tools");
+
+        let err = llm.classify(&CallContext::default(), &ChatMessage::user("你好"), None, &[], &[]).await.unwrap_err();
+        assert!(err.to_string().contains("空响应"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn tools_rejection_is_tracked_per_provider() {
+        let answer = || completion(json!({ "role": "assistant", "content": "{\"type\":\"question\",\"content\":\"好的\"}" }));
+        let responses = vec![
+            ("400 Bad Request", r#"{"error":{"message":"tools is not supported by this model"}}"#.to_string()),
+            ("200 OK", answer()),
+            ("200 OK", answer()),
+        ];
+        let (base_url, mut requests) = stub_server("application/json", responses).await;
+        let config: LlmConfig = toml::from_str(&format!(
+            "base_url = \"{base_url}\"\nmodel = \"a\"\nclassify_mode = \"tools\"\nstream = false\nmax_retries = 0\n\
+             [[providers]]\nbase_url = \"{base_url}\"\nmodel = \"b\""
+        ))
+        .unwrap();
+        let usage = UsageStore::new(&crate::config::UsageConfig { enabled: false, ..Default::default() }).unwrap();
+        let llm = LlmClient::new(config, Arc::new(usage));
+
+        // 主提供方拒绝 tools：切换到下一个提供方，仍以 tools 模式分类
+        for _ in 0..2 {
+            let intent = llm.classify(&CallContext::default(), &ChatMessage::user("你好"), None, &[], &[]).await.unwrap();
+            assert!(matches!(intent, LlmIntent::Question { .. }));
+        }
+        let models: Vec<String> = std::iter::from_fn(|| requests.try_recv().ok())
+            .map(|request| {
+                let body = request.json();
+                assert!(body.get("tools").is_some());
+                body["model"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(models, ["a", "b", "b"], "之后的请求应跳过拒绝过 tools 的提供方");
+        assert!(!llm.providers[0].supports_tools());
+        assert!(llm.providers[1].supports_tools());
+    }
+
+    #[tokio::test]
+    async fn unrelated_bad_request_does_not_disable_tools() {
+        let responses = vec![("400 Bad Request", r#"{"error":{"message":"maximum context length exceeded"}}"#.to_string())];
+        let (base_url, _requests) = stub_server("application/json", responses).await;
+        let llm = client(&base_url, "tools");
+
+        assert!(llm.classify(&CallContext::default(), &ChatMessage::user("你好"), None, &[], &[]).await.is_err());
+        assert!(llm.providers[0].supports_tools());
+    }
+
+    #[tokio::test]
+    async fn ppt_request_includes_title() {
+        let html = json!({ "role": "assistant", "content": "```html\n<html></html>\n```" });
+        let (base_url, mut requests) = ok_server(vec![completion(html)]).await;
+        let llm = client(&base_url, "prompt");
+
+        let out = llm.generate_ppt_html(&CallContext::default(), "年度回顾", "讲稿正文").await.unwrap();
+        assert_eq!(out, "<html></html>");
+        let request = requests.recv().await.unwrap().json();
+        let prompt = request["messages"][1]["content"].as_str().unwrap();
+        assert!(prompt.contains("「年度回顾」") && prompt.contains("讲稿正文"), "{prompt}");
+    }
+
+    #[test]
+    fn breaker_opens_then_allows_one_probe_after_cooldown() {
+        let cooldown = Duration::from_secs(60);
+        let t0 = Instant::now();
+        let mut b = Breaker::default();
+
+        // 关闭状态：未达阈值的失败不熔断
+        assert!(b.admit(t0, cooldown));
+        assert!(!b.record_failure(t0, 3, cooldown));
+        assert!(!b.record_failure(t0, 3, cooldown));
+        assert!(b.admit(t0, cooldown));
+        assert!(b.record_failure(t0, 3, cooldown));
+
+        // 熔断：冷却期内跳过
+        assert!(!b.admit(t0, cooldown));
+        assert!(!b.admit(t0 + Duration::from_secs(59), cooldown));
+
+        // 半开：冷却结束只放行一次试探，试探进行中其他请求仍跳过
+        let t1 = t0 + Duration::from_secs(60);
+        assert!(b.admit(t1, cooldown));
+        assert!(!b.admit(t1, cooldown));
+        assert!(!b.admit(t1 + Duration::from_secs(30), cooldown));
+
+        // 试探失败：立即再熔断一个冷却期
+        let t2 = t1 + Duration::from_secs(5);
+        assert!(b.record_failure(t2, 3, cooldown));
+        assert!(!b.admit(t2 + Duration::from_secs(59), cooldown));
+
+        // 试探被放弃（如任务取消）时，再过一个冷却期放行下一次试探；试探成功关闭熔断
+        let t3 = t2 + Duration::from_secs(60);
+        assert!(b.admit(t3, cooldown));
+        let t4 = t3 + Duration::from_secs(60);
+        assert!(b.admit(t4, cooldown));
+        b.record_success();
+        assert!(b.admit(t4, cooldown));
+        assert!(b.admit(t4, cooldown));
+        assert!(!b.record_failure(t4, 3, cooldown), "恢复后失败次数重新计算");
+    }
+
+    #[tokio::test]
+    async fn event_stream_keeps_final_line_without_newline() {
+        let event = |delta: &str| json!({ "choices": [{ "delta": { "content": delta } }] }).to_string();
+        let body = format!(
+            "data: {}\n\ndata: {}\n\ndata: {}",
+            event("{\"type\":\"question\","),
+            event("\"content\":\"你"),
+            event("好\"}"),
+        );
+        let (base_url, _requests) = stub_server("text/event-stream", vec![("200 OK", body)]).await;
+        let llm = client(&base_url, "prompt");
+        let ctx = CallContext::default();
+        let message = llm.request(&[ChatMessage::user("hi")], None, &ctx, Streaming::Intent).await.unwrap();
+        assert_eq!(message_content(&message), r#"{"type":"question","content":"你好"}"#);
+    }
+
+    #[tokio::test]
+    async fn answer_streams_plain_text() {
+        let event = |delta: &str| json!({ "choices": [{ "delta": { "content": delta } }] }).to_string();
+        let body = format!("data: {}\n\ndata: {}\n\ndata: [DONE]\n\n", event("**你"), event("好**"));
+        let (base_url, mut requests) = stub_server("text/event-stream", vec![("200 OK", body)]).await;
+        let config: LlmConfig =
+            toml::from_str(&format!("base_url = \"{base_url}\"\nmodel = \"m\"\nstream = true\nmax_retries = 0")).unwrap();
+        let usage = UsageStore::new(&crate::config::UsageConfig { enabled: false, ..Default::default() }).unwrap();
+        let llm = LlmClient::new(config, Arc::new(usage));
+        let ctx = CallContext::default();
+        let answers = ctx.subscribe_answer();
+
+        let content = llm.answer(&ctx, &ChatMessage::user("hi"), &[]).await.unwrap();
+        assert_eq!(content, "**你好**");
+        assert_eq!(*answers.borrow(), "**你好**", "纯文本回答应原样推送给状态消息");
+        let request: Value = requests.recv().await.unwrap().json();
+        assert_eq!(request["stream"], json!(true));
     }
-    text.trim().to_string()
 }
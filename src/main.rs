@@ -1,10 +1,24 @@
 #[macro_use]
 mod log;
+mod approval;
+mod artifacts;
+mod auth;
 mod bot;
 mod config;
+mod conversation;
 mod executor;
+mod history;
 mod llm_client;
+mod native_skills;
+mod skill_retrieval;
 mod skills;
+mod stt;
+mod tasks;
+#[cfg(test)]
+mod test_support;
+mod text;
+mod uploads;
+mod usage;
 
 use anyhow::Result;
 use tracing::info;
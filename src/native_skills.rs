@@ -0,0 +1,272 @@
+//! 内置技能：用 Rust 实现、无需技能目录的能力，启动时登记到 [`NativeRegistry`]。
+//!
+//! - `ppt-generator`：由 LLM 根据讲稿直接生成竖屏 HTML 演示稿
+//! - `screen_record`：录屏；macOS 先列出 avfoundation 设备，找到屏幕对应的设备号再录制
+//!
+//! 内置技能启动的子进程经 [`Executor::run_program`](crate::executor::Executor::run_program) 执行，
+//! 与普通命令一样受命令策略（deny、blocked_binaries、restrict_paths）约束。
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::executor::CommandResult;
+use crate::skills::{
+    ArgType, ArgsVia, NativeContext, NativeRegistry, NativeSkill, SkillArg, SkillArgs, SkillFuture, SkillManifest,
+    SkillOutput, SkillTriggers,
+};
+
+/// 录屏时长上限（秒）
+const MAX_RECORD_SECS: u32 = 600;
+const DEFAULT_VIDEO_SIZE: &str = "1280x720";
+
+/// 登记所有内置技能
+pub fn builtins() -> NativeRegistry {
+    let mut registry = NativeRegistry::default();
+    registry.register(PptGenerator);
+    registry.register(ScreenRecord);
+    registry
+}
+
+fn manifest(
+    name: &str,
+    description: &str,
+    prompt_hint: &str,
+    install: &str,
+    keywords: &[&str],
+    args: Vec<SkillArg>,
+) -> SkillManifest {
+    SkillManifest {
+        id: String::new(),
+        name: name.to_string(),
+        description: description.to_string(),
+        prompt_hint: prompt_hint.to_string(),
+        install: install.to_string(),
+        triggers: SkillTriggers {
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            ..SkillTriggers::default()
+        },
+        entrypoint: None,
+        args,
+        args_via: ArgsVia::default(),
+    }
+}
+
+/// 由 LLM 直接生成乔布斯风竖屏 HTML 演示稿（不依赖 Python 模块），保存为产物目录中的 slides.html
+struct PptGenerator;
+
+impl NativeSkill for PptGenerator {
+    fn id(&self) -> &'static str {
+        "ppt-generator"
+    }
+
+    fn manifest(&self) -> SkillManifest {
+        manifest(
+            "PPT 生成",
+            "将讲稿一键生成乔布斯风极简科技感竖屏 HTML 演示稿",
+            "当用户要求生成 PPT、演示稿、幻灯片时调用：title 为标题，content 为讲稿正文（用户给出的讲稿原文，或按用户要求撰写的讲稿）。",
+            "无需安装。PPT 由 LLM 根据讲稿直接生成 HTML（slides.html），由 bot 以文档形式发送。只需在 Telegram 中发送「生成关于 xxx 的 PPT」或「把以下内容做成演示稿」并附上讲稿即可。",
+            &["ppt", "演示稿", "演示文稿", "幻灯片", "slides", "slide", "讲稿"],
+            vec![
+                SkillArg::new("title", ArgType::String, true, "演示稿标题"),
+                SkillArg::new("content", ArgType::String, true, "讲稿正文"),
+            ],
+        )
+    }
+
+    fn run<'a>(&'a self, ctx: &'a NativeContext<'a>, args: &'a SkillArgs) -> SkillFuture<'a> {
+        Box::pin(async move {
+            let title = args.get("title").map(String::as_str).unwrap_or_default();
+            let content = args.get("content").map(String::as_str).unwrap_or_default();
+            let dir = ctx.artifacts.context("产物目录不可用")?;
+            tlog!(ctx.tag, "使用 LLM 直接生成 PPT HTML（不依赖 Python 模块）");
+            let html = ctx.llm.generate_ppt_html(ctx.llm_calls, title, content).await?;
+            let path = dir.join("slides.html");
+            std::fs::write(&path, &html).with_context(|| format!("写入文件失败: {}", path.display()))?;
+            tlog!(ctx.tag, "已保存到 {}", path.display());
+            Ok(SkillOutput {
+                text: format!("已生成「{title}」演示稿并保存到 {}", path.display()),
+                artifacts: vec![path],
+            })
+        })
+    }
+}
+
+/// 录屏为 mp4（H.264 baseline，兼容手机播放）。macOS 用 avfoundation，其他系统用 X11 的 x11grab。
+struct ScreenRecord;
+
+impl NativeSkill for ScreenRecord {
+    fn id(&self) -> &'static str {
+        "screen_record"
+    }
+
+    fn manifest(&self) -> SkillManifest {
+        manifest(
+            "录屏",
+            "录制屏幕，输出 mp4 视频",
+            "当用户要求录屏、录制屏幕时调用，seconds 为录制秒数（用户未说明时用 5）。",
+            "macOS:\n  - 安装 ffmpeg: brew install ffmpeg\n  - 首次录屏需在「系统设置 → 隐私与安全性 → 屏幕录制」中授权运行 bot 的终端\n\nLinux (X11):\n  - 安装: sudo apt install ffmpeg\n  - 使用环境变量 DISPLAY 指定的显示器（默认 :0.0）",
+            &["录屏", "录制屏幕", "录个屏", "屏幕录制", "screen record", "record screen", "screencast"],
+            vec![
+                SkillArg::new("seconds", ArgType::Integer, true, &format!("录制秒数，1 到 {MAX_RECORD_SECS}")),
+                SkillArg::new("video_size", ArgType::String, false, &format!("分辨率，如 {DEFAULT_VIDEO_SIZE}（默认）")),
+            ],
+        )
+    }
+
+    fn run<'a>(&'a self, ctx: &'a NativeContext<'a>, args: &'a SkillArgs) -> SkillFuture<'a> {
+        Box::pin(async move {
+            let seconds = args
+                .get("seconds")
+                .and_then(|s| s.parse::<u32>().ok())
+                .filter(|s| (1..=MAX_RECORD_SECS).contains(s))
+                .with_context(|| format!("录制秒数须在 1 到 {MAX_RECORD_SECS} 之间"))?;
+            let video_size = args.get("video_size").map(String::as_str).unwrap_or(DEFAULT_VIDEO_SIZE);
+            if !is_video_size(video_size) {
+                bail!("分辨率格式应为 宽x高，如 {DEFAULT_VIDEO_SIZE}: {video_size}");
+            }
+            let dir = ctx.artifacts.context("产物目录不可用")?;
+            let output = dir.join("screen_record.mp4");
+
+            let input: Vec<String> = if cfg!(target_os = "macos") {
+                let index = avfoundation_screen_index(ctx).await?;
+                ["-f", "avfoundation", "-video_size", video_size, "-r", "30", "-i", &format!("{index}:0")]
+                    .map(String::from)
+                    .to_vec()
+            } else {
+                let display = std::env::var("DISPLAY").unwrap_or_else(|_| ":0.0".to_string());
+                ["-f", "x11grab", "-framerate", "30", "-video_size", video_size, "-i", &display]
+                    .map(String::from)
+                    .to_vec()
+            };
+            let mut ffmpeg_args = vec!["-nostdin".to_string(), "-y".to_string()];
+            ffmpeg_args.extend(input);
+            ffmpeg_args.extend(
+                [
+                    "-t", &seconds.to_string(),
+                    "-c:v", "libx264", "-pix_fmt", "yuv420p", "-profile:v", "baseline", "-level", "3.0",
+                    "-coder", "0", "-tune", "fastdecode", "-movflags", "+faststart",
+                    "-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+                ]
+                .map(String::from),
+            );
+            ffmpeg_args.push(output.display().to_string());
+
+            let label = format!("ffmpeg {}", ffmpeg_args.join(" "));
+            tlog!(ctx.tag, "录屏 {} 秒: {}", seconds, label);
+            let r = run_ffmpeg(ctx, &ffmpeg_args, &label).await?;
+            if !r.success {
+                // ffmpeg 的错误原因在输出末尾
+                let stderr = r.stderr.trim();
+                let mut start = stderr.len().saturating_sub(1000);
+                while !stderr.is_char_boundary(start) {
+                    start += 1;
+                }
+                bail!("ffmpeg 录屏失败（退出码 {:?}）: {}", r.exit_code, &stderr[start..]);
+            }
+            Ok(SkillOutput {
+                text: format!("已录制 {seconds} 秒屏幕，保存到 {}", output.display()),
+                artifacts: vec![output],
+            })
+        })
+    }
+}
+
+fn is_video_size(s: &str) -> bool {
+    matches!(s.split_once('x'), Some((w, h))
+        if !w.is_empty() && !h.is_empty() && w.chars().all(|c| c.is_ascii_digit()) && h.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// 经命令策略执行 ffmpeg；被策略拒绝时返回错误而不是失败的命令结果
+async fn run_ffmpeg(ctx: &NativeContext<'_>, args: &[String], label: &str) -> Result<CommandResult> {
+    let r = ctx.executor.run_program(Path::new("ffmpeg"), args, &[], label, ctx.run_options).await?;
+    check_policy_denial(&r)?;
+    Ok(r)
+}
+
+fn check_policy_denial(r: &CommandResult) -> Result<()> {
+    match &r.policy_rule {
+        Some(rule) => bail!("ffmpeg 被命令策略拒绝（{rule}）"),
+        None => Ok(()),
+    }
+}
+
+/// 运行 `ffmpeg -list_devices` 找到屏幕对应的 avfoundation 设备号，找不到时使用 1（常见的第一块屏幕）。
+/// 仅在 ffmpeg 被命令策略拒绝时返回错误。
+async fn avfoundation_screen_index(ctx: &NativeContext<'_>) -> Result<u32> {
+    tlog!(ctx.tag, "录屏前先列出 avfoundation 设备...");
+    let args = ["-nostdin", "-hide_banner", "-f", "avfoundation", "-list_devices", "true", "-i", ""].map(String::from);
+    let label = "ffmpeg -f avfoundation -list_devices true -i \"\"";
+    let r = match ctx.executor.run_program(Path::new("ffmpeg"), &args, &[], label, ctx.run_options).await {
+        Ok(r) => r,
+        Err(e) => {
+            tlog!(ctx.tag, "列出设备失败，使用设备 1: {}", e);
+            return Ok(1);
+        }
+    };
+    check_policy_denial(&r)?;
+    // 设备列表输出在 stderr，且该命令总以非零状态退出
+    match parse_avfoundation_screen_index(&format!("{}\n{}", r.stdout, r.stderr)) {
+        Some(index) => {
+            tlog!(ctx.tag, "解析到屏幕设备索引: {}", index);
+            Ok(index)
+        }
+        None => {
+            tlog!(ctx.tag, "未解析到 Capture screen 索引，使用设备 1");
+            Ok(1)
+        }
+    }
+}
+
+/// 从 ffmpeg -list_devices 的输出中解析第一个「Capture screen」对应的设备索引。
+/// 格式示例: [AVFoundation indev @ 0x...] [1] Capture screen 0
+fn parse_avfoundation_screen_index(output: &str) -> Option<u32> {
+    for line in output.lines() {
+        let Some(p) = line.find("Capture screen") else { continue };
+        let before_cap = &line[..p];
+        let mut idx = before_cap.len();
+        while idx > 0 {
+            let Some(close) = before_cap[..idx].rfind(']') else { break };
+            let Some(open) = before_cap[..close].rfind('[') else { break };
+            let between = before_cap[open + 1..close].trim();
+            if !between.is_empty() && between.chars().all(|c| c.is_ascii_digit()) {
+                if let Ok(index) = between.parse() {
+                    return Some(index);
+                }
+            }
+            idx = close;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_size_accepts_only_width_x_height() {
+        for ok in ["1280x720", "1x1", "3840x2160"] {
+            assert!(is_video_size(ok), "{ok}");
+        }
+        for bad in ["", "x", "1280x", "x720", "1280X720", "1280x720x1", "-1x720", "12 80x720", "1280x72a"] {
+            assert!(!is_video_size(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn avfoundation_screen_index_from_device_list() {
+        let output = "\
+[AVFoundation indev @ 0x7f8] AVFoundation video devices:
+[AVFoundation indev @ 0x7f8] [0] FaceTime HD Camera
+[AVFoundation indev @ 0x7f8] [2] Capture screen 0
+[AVFoundation indev @ 0x7f8] [3] Capture screen 1
+[AVFoundation indev @ 0x7f8] AVFoundation audio devices:
+[AVFoundation indev @ 0x7f8] [0] MacBook Pro Microphone";
+        assert_eq!(parse_avfoundation_screen_index(output), Some(2));
+        // 地址部分不是数字，跳过后继续向前找设备号
+        assert_eq!(parse_avfoundation_screen_index("[indev @ 0x1] [12] [abc] Capture screen 0"), Some(12));
+        assert_eq!(parse_avfoundation_screen_index("[AVFoundation indev @ 0x7f8] [0] FaceTime HD Camera"), None);
+        assert_eq!(parse_avfoundation_screen_index("Capture screen 0"), None);
+        assert_eq!(parse_avfoundation_screen_index(""), None);
+    }
+}
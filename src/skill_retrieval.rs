@@ -0,0 +1,222 @@
+//! 技能检索：用 OpenAI 兼容的 `/embeddings` 接口为每个技能的名称、描述与提示计算向量，
+//! 按与用户消息的余弦相似度只注入最相关的 top-k 个技能，技能很多时保持提示精简。
+//!
+//! 技能向量缓存在磁盘上，以「模型 + 文本」的 FNV-1a 哈希为键：技能内容不变时重启与重新加载
+//! 都不会重复请求，修改过的技能会自动重新计算。
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::SkillRetrievalConfig;
+use crate::llm_client::CallContext;
+use crate::skills::Skill;
+use crate::usage::{Usage, UsageStore};
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    #[serde(default)]
+    usage: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+pub struct SkillRetriever {
+    client: reqwest::Client,
+    config: SkillRetrievalConfig,
+    usage: Arc<UsageStore>,
+    cache_path: PathBuf,
+    /// 内容哈希 → 向量
+    cache: Mutex<HashMap<String, Arc<Vec<f32>>>>,
+}
+
+impl SkillRetriever {
+    pub fn new(config: SkillRetrievalConfig, usage: Arc<UsageStore>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        let cache_path = PathBuf::from(&config.cache_path);
+        let cache = if config.enabled { load_cache(&cache_path) } else { HashMap::new() };
+        Self {
+            client,
+            config,
+            usage,
+            cache_path,
+            cache: Mutex::new(cache),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// 选出与消息最相关的技能：相似度最高的 top_k 个，加上触发关键词命中的技能，按相似度降序。
+    /// 技能数不超过 top_k 时全部返回，不发请求。消息向量的用量记在 `calls` 的聊天与用户名下，
+    /// 请求前先检查该用户的每日配额。
+    pub async fn select(&self, skills: &[Skill], message: &str, calls: &CallContext, tag: &str) -> Result<Vec<Skill>> {
+        if skills.len() <= self.config.top_k {
+            tlog!(tag, "技能数 {} 不超过 top_k，全部注入", skills.len());
+            return Ok(skills.to_vec());
+        }
+        if let Some(user_id) = calls.quota_user() {
+            self.usage.check_quota(user_id)?;
+        }
+        let vectors = self.skill_vectors(skills).await?;
+        let query = self
+            .embed(&[message.to_string()], calls.chat_id(), calls.user_id())
+            .await?
+            .pop()
+            .context("embedding 接口未返回消息向量")?;
+
+        let mut ranked: Vec<(usize, f32)> = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i, cosine(&query, v)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut selected = Vec::new();
+        let mut lines = Vec::new();
+        for (rank, &(i, score)) in ranked.iter().enumerate() {
+            let skill = &skills[i];
+            let by_keyword = skill.triggers.has_keywords() && skill.relevant_to_message(message);
+            let chosen = rank < self.config.top_k || by_keyword;
+            if chosen {
+                selected.push(skill.clone());
+            }
+            let mark = match (rank < self.config.top_k, by_keyword) {
+                (true, _) => "✓",
+                (false, true) => "✓ 关键词",
+                _ => "",
+            };
+            lines.push(format!("{score:.3} {} {mark}", skill.id));
+        }
+        tlog!(tag, "技能检索: 选中 {}/{} 个\n{}", selected.len(), skills.len(), lines.join("\n"));
+        Ok(selected)
+    }
+
+    /// 各技能的向量（与 `skills` 一一对应）；缓存中没有的批量请求后写回缓存文件
+    async fn skill_vectors(&self, skills: &[Skill]) -> Result<Vec<Arc<Vec<f32>>>> {
+        let texts: Vec<String> = skills.iter().map(skill_text).collect();
+        let keys: Vec<String> = texts.iter().map(|t| self.cache_key(t)).collect();
+        let missing: Vec<usize> = {
+            let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            (0..keys.len()).filter(|&i| !cache.contains_key(&keys[i])).collect()
+        };
+        if !missing.is_empty() {
+            tlog!("技能检索", "计算 {} 个技能的 embedding", missing.len());
+            let inputs: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+            let vectors = self.embed(&inputs, None, None).await?;
+            let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            for (&i, v) in missing.iter().zip(vectors) {
+                cache.insert(keys[i].clone(), Arc::new(v));
+            }
+            // 只保留当前技能的向量，删除或修改过的技能的旧向量随之清理
+            cache.retain(|k, _| keys.contains(k));
+            if let Err(e) = save_cache(&self.cache_path, &cache) {
+                tlog!("技能检索", "写入 embedding 缓存失败: {:#}", e);
+            }
+        }
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        keys.iter()
+            .map(|k| cache.get(k).cloned().context("embedding 缓存缺少技能向量"))
+            .collect()
+    }
+
+    fn cache_key(&self, text: &str) -> String {
+        format!("{:016x}", fnv1a(&[self.config.model.as_bytes(), b"\0", text.as_bytes()]))
+    }
+
+    /// 请求 `{base_url}/embeddings`，按输入顺序返回向量
+    async fn embed(&self, inputs: &[String], chat_id: Option<i64>, user_id: Option<u64>) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/embeddings", self.config.base_url.trim_end_matches('/'));
+        let body = serde_json::json!({ "model": self.config.model, "input": inputs });
+        let mut req = self.client.post(&url).json(&body);
+        if !self.config.api_key.is_empty() {
+            req = req.header("Authorization", format!("Bearer {}", self.config.api_key));
+        }
+        let resp = req.send().await.context("embedding 请求失败（可能超时或网络问题）")?;
+        let status = resp.status();
+        let text = resp.text().await.context("读取 embedding 响应失败")?;
+        if !status.is_success() {
+            anyhow::bail!("embedding 接口错误 {status}: {text}");
+        }
+        let mut parsed: EmbeddingResponse =
+            serde_json::from_str(&text).with_context(|| format!("无法解析 embedding 响应: {text}"))?;
+        if parsed.data.len() != inputs.len() {
+            anyhow::bail!("embedding 接口返回 {} 个向量，应为 {}", parsed.data.len(), inputs.len());
+        }
+        if let Some(usage) = parsed.usage.as_ref().and_then(Usage::from_json) {
+            if let Err(e) = self.usage.record(chat_id, user_id, &self.config.model, usage) {
+                tlog!("技能检索", "记录用量失败: {}", e);
+            }
+        }
+        parsed.data.sort_by_key(|d| d.index);
+        Ok(parsed.data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+/// 参与 embedding 的技能文本
+fn skill_text(skill: &Skill) -> String {
+    format!("{}\n{}\n{}", skill.name, skill.description, skill.prompt_hint)
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let (mut dot, mut na, mut nb) = (0f32, 0f32, 0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        0.0
+    } else {
+        dot / (na.sqrt() * nb.sqrt())
+    }
+}
+
+/// 64 位 FNV-1a，跨进程、跨版本稳定（std 的 DefaultHasher 不保证），适合作为持久化缓存的键
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in parts.iter().flat_map(|p| p.iter()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn load_cache(path: &std::path::Path) -> HashMap<String, Arc<Vec<f32>>> {
+    let Ok(content) = std::fs::read_to_string(path) else { return HashMap::new() };
+    match serde_json::from_str::<HashMap<String, Vec<f32>>>(&content) {
+        Ok(map) => map.into_iter().map(|(k, v)| (k, Arc::new(v))).collect(),
+        Err(e) => {
+            tlog!("技能检索", "embedding 缓存无法解析，将重新计算: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// 先写临时文件再改名，避免写到一半时崩溃留下损坏的缓存
+fn save_cache(path: &std::path::Path, cache: &HashMap<String, Arc<Vec<f32>>>) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("无法创建缓存目录: {}", dir.display()))?;
+    }
+    let map: HashMap<&String, &Vec<f32>> = cache.iter().map(|(k, v)| (k, v.as_ref())).collect();
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&map)?).with_context(|| format!("无法写入 {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("无法写入 {}", path.display()))?;
+    Ok(())
+}
@@ -3,11 +3,29 @@
 //! 每个 skill 是一个子目录，支持两种清单格式：
 //! - `skill.toml`：TOML 格式，含 id / name / description / prompt_hint / install
 //! - `SKILL.md`：Markdown + YAML frontmatter（--- 内 name、description 等），无 prompt_hint 时用 description
+//!
+//! `skill.toml` 还可以声明 `entrypoint`（技能目录内的可执行文件）与带类型的参数表 `[[args]]`，
+//! 成为可执行技能：LLM 返回 `{"type":"skill","id":...,"args":{...}}`，参数按声明校验后
+//! 以 argv 或环境变量传给入口程序，不经过 shell。
+//!
+//! 内置能力（如生成演示稿、录屏）以 [`NativeSkill`] 实现并登记到 [`NativeRegistry`]，
+//! 与目录技能一起加载，经同一条 `{"type":"skill"}` 路径调用。
 
+use regex::Regex;
 use serde::Deserialize;
-use std::path::Path;
+use serde_json::{Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
 use tracing::{debug, info, warn};
 
+use crate::executor::{command_binaries, program_command, Executor, RunOptions};
+use crate::llm_client::{CallContext, LlmClient};
+
 const DEFAULT_SKILLS_DIR: &str = "skills";
 const SKILL_MANIFEST: &str = "skill.toml";
 const SKILL_MD: &str = "SKILL.md";
@@ -27,6 +45,227 @@ pub struct SkillManifest {
     /// 安装方式说明（依赖、命令、权限等），用于回复「怎么安装 xx」
     #[serde(default)]
     pub install: String,
+    /// 相关性触发条件：决定何时把本技能注入分类提示与修正上下文
+    #[serde(default)]
+    pub triggers: SkillTriggers,
+    /// 入口程序（相对技能目录），设置后成为可执行技能
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    /// 入口程序的参数声明
+    #[serde(default)]
+    pub args: Vec<SkillArg>,
+    /// 参数的传递方式
+    #[serde(default)]
+    pub args_via: ArgsVia,
+}
+
+/// `[triggers]`：技能与当前消息或失败命令是否相关
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SkillTriggers {
+    /// 用户消息包含任一关键词（不区分大小写）时注入分类提示；未声明时总是注入
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// 失败命令匹配任一正则时注入修正上下文
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// 失败命令调用了其中任一可执行文件时注入修正上下文
+    #[serde(default)]
+    pub binaries: Vec<String>,
+}
+
+/// 编译后的触发条件
+#[derive(Debug, Clone, Default)]
+pub struct Triggers {
+    /// 小写的关键词
+    keywords: Vec<String>,
+    commands: Vec<Regex>,
+    binaries: Vec<String>,
+}
+
+impl Triggers {
+    fn compile(triggers: &SkillTriggers) -> Result<Self, String> {
+        let commands = triggers
+            .commands
+            .iter()
+            .map(|re| Regex::new(re).map_err(|e| format!("触发正则 {re:?} 无效: {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            keywords: triggers
+                .keywords
+                .iter()
+                .map(|k| k.trim().to_lowercase())
+                .filter(|k| !k.is_empty())
+                .collect(),
+            commands,
+            binaries: triggers.binaries.clone(),
+        })
+    }
+
+    pub fn has_keywords(&self) -> bool {
+        !self.keywords.is_empty()
+    }
+
+    fn matches_message(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keywords.iter().any(|k| text.contains(k.as_str()))
+    }
+
+    fn matches_command(&self, cmd: &str) -> bool {
+        self.commands.iter().any(|re| re.is_match(cmd))
+            || (!self.binaries.is_empty()
+                && command_binaries(cmd).iter().any(|bin| self.binaries.iter().any(|b| b == bin)))
+    }
+}
+
+/// 可执行技能的一个参数
+#[derive(Debug, Deserialize, Clone)]
+pub struct SkillArg {
+    /// 参数名（字母、数字、下划线），env 方式下对应环境变量 `SKILL_ARG_<大写参数名>`
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: ArgType,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl SkillArg {
+    pub fn new(name: &str, kind: ArgType, required: bool, description: &str) -> Self {
+        Self { name: name.to_string(), kind, required, description: description.to_string() }
+    }
+}
+
+impl ArgType {
+    /// JSON Schema 中的类型名
+    pub fn schema_type(self) -> &'static str {
+        match self {
+            ArgType::String => "string",
+            ArgType::Integer => "integer",
+            ArgType::Number => "number",
+            ArgType::Boolean => "boolean",
+        }
+    }
+}
+
+/// 参数传给入口程序的方式
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgsVia {
+    /// 按声明顺序作为位置参数，未提供的可选参数传空字符串
+    #[default]
+    Argv,
+    /// 作为环境变量 `SKILL_ARG_<NAME>`，未提供的可选参数不设置
+    Env,
+}
+
+/// 校验后的参数值（参数名 → 字符串形式的值），只含调用时提供了的参数
+pub type SkillArgs = BTreeMap<String, String>;
+
+/// 内置技能的执行结果
+#[derive(Debug, Default)]
+pub struct SkillOutput {
+    /// 展示给用户的结果说明
+    pub text: String,
+    /// 生成的文件；不在任务产物目录中的会被复制进去，随后一并发送
+    pub artifacts: Vec<PathBuf>,
+}
+
+/// 内置技能执行时可用的资源
+pub struct NativeContext<'a> {
+    pub llm: &'a LlmClient,
+    pub llm_calls: &'a CallContext,
+    pub executor: &'a Executor,
+    /// 执行子进程时使用的选项（实时输出、取消信号）
+    pub run_options: &'a RunOptions,
+    /// 任务产物目录，None 表示不可用
+    pub artifacts: Option<&'a Path>,
+    /// 日志标签
+    pub tag: &'a str,
+}
+
+pub type SkillFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<SkillOutput>> + Send + 'a>>;
+
+/// 用 Rust 实现的内置技能。清单中的参数声明与目录技能相同，`run` 收到的参数已按声明校验过。
+pub trait NativeSkill: Send + Sync {
+    fn id(&self) -> &'static str;
+    /// 技能清单；`id` 与 `entrypoint` 字段会被忽略
+    fn manifest(&self) -> SkillManifest;
+    fn run<'a>(&'a self, ctx: &'a NativeContext<'a>, args: &'a SkillArgs) -> SkillFuture<'a>;
+}
+
+/// 内置技能登记表
+#[derive(Default)]
+pub struct NativeRegistry {
+    skills: Vec<Arc<dyn NativeSkill>>,
+}
+
+impl NativeRegistry {
+    pub fn register(&mut self, skill: impl NativeSkill + 'static) {
+        if self.skills.iter().any(|s| s.id() == skill.id()) {
+            warn!(id = skill.id(), "内置技能重复登记，已忽略");
+            return;
+        }
+        self.skills.push(Arc::new(skill));
+    }
+
+    fn to_skills(&self, errors: &mut Vec<String>) -> Vec<Skill> {
+        let mut skills = Vec::new();
+        for native in &self.skills {
+            let manifest = native.manifest();
+            let triggers = match check_args(&manifest.args).and_then(|_| Triggers::compile(&manifest.triggers)) {
+                Ok(triggers) => triggers,
+                Err(e) => {
+                    warn!(id = native.id(), err = %e, "内置技能声明无效，跳过");
+                    errors.push(format!("内置技能 {}: 声明无效: {e}", native.id()));
+                    continue;
+                }
+            };
+            skills.push(Skill {
+                id: native.id().to_string(),
+                name: manifest.name,
+                description: manifest.description,
+                prompt_hint: manifest.prompt_hint,
+                install: manifest.install,
+                triggers,
+                runner: SkillRunner::Native(native.clone()),
+                args: manifest.args,
+                args_via: manifest.args_via,
+            });
+        }
+        skills
+    }
+}
+
+/// 技能的执行方式
+#[derive(Clone)]
+pub enum SkillRunner {
+    /// 仅提示词，由 LLM 据此生成 shell 命令
+    Prompt,
+    /// 技能目录内的入口程序（绝对路径）
+    Entrypoint(PathBuf),
+    /// 内置技能
+    Native(Arc<dyn NativeSkill>),
+}
+
+impl std::fmt::Debug for SkillRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkillRunner::Prompt => f.write_str("Prompt"),
+            SkillRunner::Entrypoint(path) => f.debug_tuple("Entrypoint").field(path).finish(),
+            SkillRunner::Native(native) => f.debug_tuple("Native").field(&native.id()).finish(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,6 +275,127 @@ pub struct Skill {
     pub description: String,
     pub prompt_hint: String,
     pub install: String,
+    pub triggers: Triggers,
+    pub runner: SkillRunner,
+    pub args: Vec<SkillArg>,
+    pub args_via: ArgsVia,
+}
+
+/// 校验通过、可以直接执行的技能调用
+pub struct SkillCall {
+    pub target: SkillTarget,
+    /// 用于执行计划、报告与日志的展示文本
+    pub label: String,
+}
+
+impl SkillCall {
+    /// 命令策略判定用的等价命令行：入口程序为程序路径加 argv，内置技能为展示文本
+    /// （内置技能启动的子进程在执行时另行判定）
+    pub fn policy_command(&self) -> String {
+        match &self.target {
+            SkillTarget::Program { program, args, env } => program_command(program, args, env),
+            SkillTarget::Native { .. } => self.label.clone(),
+        }
+    }
+}
+
+pub enum SkillTarget {
+    /// 不经 shell 执行的入口程序
+    Program {
+        program: PathBuf,
+        args: Vec<String>,
+        env: Vec<(String, String)>,
+    },
+    Native {
+        skill: Arc<dyn NativeSkill>,
+        args: SkillArgs,
+    },
+}
+
+impl Skill {
+    pub fn is_executable(&self) -> bool {
+        !matches!(self.runner, SkillRunner::Prompt)
+    }
+
+    /// 是否与用户消息相关：未声明关键词的技能无法判断，视为相关
+    pub fn relevant_to_message(&self, text: &str) -> bool {
+        !self.triggers.has_keywords() || self.triggers.matches_message(text)
+    }
+
+    /// 是否与（失败的）命令相关：匹配命令触发条件，或就是本技能的调用（展示文本以技能 id 开头）
+    pub fn relevant_to_command(&self, cmd: &str) -> bool {
+        self.triggers.matches_command(cmd) || cmd.split_whitespace().next() == Some(self.id.as_str())
+    }
+
+    /// 按参数声明校验 LLM 给出的参数并生成调用：拒绝未声明的参数、缺少必填参数与类型不符的值。
+    /// 整数、小数与布尔值也接受可解析的字符串形式（模型常把数字写成字符串）。
+    pub fn prepare(&self, args: &Map<String, Value>) -> Result<SkillCall, String> {
+        if !self.is_executable() {
+            return Err(format!("技能「{}」不是可执行技能", self.name));
+        }
+        if let Some(unknown) = args.keys().find(|k| !self.args.iter().any(|a| &a.name == *k)) {
+            return Err(format!("未声明的参数 {unknown}"));
+        }
+        let mut values = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            let value = match args.get(&arg.name).filter(|v| !v.is_null()) {
+                Some(v) => Some(arg_value(arg, v)?),
+                None if arg.required => return Err(format!("缺少必填参数 {}", arg.name)),
+                None => None,
+            };
+            values.push((arg, value));
+        }
+
+        let label = std::iter::once(self.id.clone())
+            .chain(values.iter().filter_map(|(arg, v)| v.as_ref().map(|v| format!("{}={v:?}", arg.name))))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let target = match &self.runner {
+            SkillRunner::Native(skill) => SkillTarget::Native {
+                skill: skill.clone(),
+                args: values
+                    .into_iter()
+                    .filter_map(|(arg, v)| v.map(|v| (arg.name.clone(), v)))
+                    .collect(),
+            },
+            SkillRunner::Entrypoint(program) => {
+                let (args, env) = match self.args_via {
+                    ArgsVia::Argv => (values.into_iter().map(|(_, v)| v.unwrap_or_default()).collect(), Vec::new()),
+                    ArgsVia::Env => (
+                        Vec::new(),
+                        values
+                            .into_iter()
+                            .filter_map(|(arg, v)| v.map(|v| (format!("SKILL_ARG_{}", arg.name.to_ascii_uppercase()), v)))
+                            .collect(),
+                    ),
+                };
+                SkillTarget::Program { program: program.clone(), args, env }
+            }
+            SkillRunner::Prompt => unreachable!("已在上面排除仅提示词的技能"),
+        };
+        Ok(SkillCall { target, label })
+    }
+}
+
+/// 把一个参数值转为传给程序的字符串
+fn arg_value(arg: &SkillArg, value: &Value) -> Result<String, String> {
+    let invalid = || format!("参数 {} 应为 {}，实际为 {value}", arg.name, arg.kind.schema_type());
+    let text = match (arg.kind, value) {
+        (ArgType::String, Value::String(s)) => s.clone(),
+        (ArgType::Integer, Value::Number(n)) if n.is_i64() || n.is_u64() => n.to_string(),
+        (ArgType::Integer, Value::String(s)) => s.trim().parse::<i64>().map_err(|_| invalid())?.to_string(),
+        (ArgType::Number, Value::Number(n)) => n.to_string(),
+        (ArgType::Number, Value::String(s)) => {
+            s.trim().parse::<f64>().ok().filter(|n| n.is_finite()).ok_or_else(invalid)?.to_string()
+        }
+        (ArgType::Boolean, Value::Bool(b)) => b.to_string(),
+        (ArgType::Boolean, Value::String(s)) if matches!(s.trim(), "true" | "false") => s.trim().to_string(),
+        _ => return Err(invalid()),
+    };
+    if text.contains('\0') {
+        return Err(format!("参数 {} 含有 NUL 字符", arg.name));
+    }
+    Ok(text)
 }
 
 /// 解析 SKILL.md：提取 frontmatter（--- 之间的 name/description/prompt_hint/install），
@@ -47,6 +407,7 @@ fn parse_skill_md(content: &str, dir_name: &std::ffi::OsStr) -> Result<Skill, St
     let mut description = String::new();
     let mut prompt_hint = String::new();
     let mut install = String::new();
+    let mut triggers = SkillTriggers::default();
 
     for line in front.lines() {
         let line = line.trim();
@@ -61,6 +422,10 @@ fn parse_skill_md(content: &str, dir_name: &std::ffi::OsStr) -> Result<Skill, St
             "description" => description = v.clone(),
             "prompt_hint" => prompt_hint = v,
             "install" => install = v,
+            "keywords" => triggers.keywords.extend(parse_md_list(&v)),
+            "binaries" => triggers.binaries.extend(parse_md_list(&v)),
+            // 正则中可能有逗号，每行一条
+            "commands" => triggers.commands.push(unquote(&v).to_string()),
             _ => {}
         }
     }
@@ -87,9 +452,60 @@ fn parse_skill_md(content: &str, dir_name: &std::ffi::OsStr) -> Result<Skill, St
         description,
         prompt_hint,
         install,
+        triggers: Triggers::compile(&triggers)?,
+        runner: SkillRunner::Prompt,
+        args: Vec::new(),
+        args_via: ArgsVia::default(),
     })
 }
 
+/// frontmatter 中的列表：`[a, "b"]` 或 `a, b`
+fn parse_md_list(v: &str) -> Vec<String> {
+    let v = v.trim();
+    let v = v.strip_prefix('[').and_then(|v| v.strip_suffix(']')).unwrap_or(v);
+    v.split(',').map(unquote).filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+fn unquote(v: &str) -> &str {
+    let v = v.trim();
+    v.strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(v)
+}
+
+/// 检查入口程序位于技能目录内且存在，参数名合法且不重复；返回入口程序的绝对路径。
+fn resolve_entrypoint(skill_dir: &Path, entrypoint: &str, args: &[SkillArg]) -> Result<PathBuf, String> {
+    let dir = skill_dir
+        .canonicalize()
+        .map_err(|e| format!("无法解析技能目录 {}: {e}", skill_dir.display()))?;
+    let path = dir
+        .join(entrypoint)
+        .canonicalize()
+        .map_err(|e| format!("入口程序 {entrypoint} 不存在: {e}"))?;
+    if !path.starts_with(&dir) {
+        return Err(format!("入口程序 {entrypoint} 不在技能目录内"));
+    }
+    if !path.is_file() {
+        return Err(format!("入口程序 {entrypoint} 不是文件"));
+    }
+    check_args(args)?;
+    Ok(path)
+}
+
+/// 参数名须合法且不重复
+fn check_args(args: &[SkillArg]) -> Result<(), String> {
+    for (i, arg) in args.iter().enumerate() {
+        if arg.name.is_empty() || !arg.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("参数名 {:?} 只能包含字母、数字与下划线", arg.name));
+        }
+        if args[..i].iter().any(|a| a.name == arg.name) {
+            return Err(format!("参数 {} 重复声明", arg.name));
+        }
+    }
+    Ok(())
+}
+
 fn split_frontmatter(content: &str) -> (&str, &str) {
     let content = content.trim_start();
     if !content.starts_with("---") {
@@ -125,78 +541,187 @@ fn extract_md_section(body: &str, title: &str) -> String {
     lines.join("\n").trim().to_string()
 }
 
-/// 从目录加载所有 skills，目录不存在或为空时返回空列表。
-pub fn load_skills(dir: Option<&str>) -> Vec<Skill> {
+/// 一次加载的结果
+pub struct SkillLoad {
+    pub skills: Vec<Skill>,
+    /// 被跳过的技能及原因，供报告给管理员
+    pub errors: Vec<String>,
+}
+
+/// 加载内置技能与目录中的所有 skills；目录不存在时只有内置技能。与内置技能同 id 的目录技能会被跳过。
+pub fn load_skills(dir: Option<&str>, natives: &NativeRegistry) -> SkillLoad {
+    let mut errors = Vec::new();
+    let mut skills = natives.to_skills(&mut errors);
+    let builtin = skills.len();
     let dir = dir.unwrap_or(DEFAULT_SKILLS_DIR);
     let path = Path::new(dir);
     if !path.is_dir() {
         debug!(dir = %dir, "skills 目录不存在，跳过加载");
-        return Vec::new();
+        return SkillLoad { skills, errors };
     }
 
-    let mut skills = Vec::new();
-    let read_dir = match std::fs::read_dir(path) {
-        Ok(d) => d,
+    let mut entries: Vec<_> = match std::fs::read_dir(path) {
+        Ok(d) => d.filter_map(Result::ok).map(|e| e.file_name()).collect(),
         Err(e) => {
             warn!(dir = %dir, err = %e, "读取 skills 目录失败");
-            return skills;
+            errors.push(format!("{dir}: 读取 skills 目录失败: {e}"));
+            return SkillLoad { skills, errors };
         }
     };
+    entries.sort();
 
-    for entry in read_dir.filter_map(Result::ok) {
-        let dir_name = entry.file_name();
+    for dir_name in entries {
         let sub = path.join(&dir_name);
         if !sub.is_dir() {
             continue;
         }
         let manifest_path = sub.join(SKILL_MANIFEST);
         let skill_md_path = sub.join(SKILL_MD);
+        let mut skip = |file: &Path, reason: &str, err: &dyn std::fmt::Display| {
+            warn!(path = %file.display(), err = %err, "{reason}");
+            errors.push(format!("{}: {reason}: {}", file.display(), err.to_string().trim()));
+        };
 
-        if manifest_path.is_file() {
+        let skill = if manifest_path.is_file() {
             let content = match std::fs::read_to_string(&manifest_path) {
                 Ok(c) => c,
                 Err(e) => {
-                    warn!(path = %manifest_path.display(), err = %e, "读取 skill 配置失败");
+                    skip(&manifest_path, "读取 skill 配置失败", &e);
                     continue;
                 }
             };
             let manifest: SkillManifest = match toml::from_str(&content) {
                 Ok(m) => m,
                 Err(e) => {
-                    warn!(path = %manifest_path.display(), err = %e, "解析 skill.toml 失败");
+                    skip(&manifest_path, "解析 skill.toml 失败", &e);
                     continue;
                 }
             };
-            skills.push(Skill {
+            let triggers = match Triggers::compile(&manifest.triggers) {
+                Ok(triggers) => triggers,
+                Err(e) => {
+                    skip(&manifest_path, "触发条件无效，跳过", &e);
+                    continue;
+                }
+            };
+            let runner = match manifest.entrypoint.as_deref().map(|e| resolve_entrypoint(&sub, e, &manifest.args)) {
+                None => SkillRunner::Prompt,
+                Some(Ok(path)) => SkillRunner::Entrypoint(path),
+                Some(Err(e)) => {
+                    skip(&manifest_path, "可执行技能配置无效，跳过", &e);
+                    continue;
+                }
+            };
+            Skill {
                 id: manifest.id,
                 name: manifest.name,
                 description: manifest.description,
                 prompt_hint: manifest.prompt_hint,
                 install: manifest.install,
-            });
+                triggers,
+                runner,
+                args: manifest.args,
+                args_via: manifest.args_via,
+            }
         } else if skill_md_path.is_file() {
             let content = match std::fs::read_to_string(&skill_md_path) {
                 Ok(c) => c,
                 Err(e) => {
-                    warn!(path = %skill_md_path.display(), err = %e, "读取 SKILL.md 失败");
+                    skip(&skill_md_path, "读取 SKILL.md 失败", &e);
                     continue;
                 }
             };
             match parse_skill_md(&content, &dir_name) {
-                Ok(skill) => skills.push(skill),
+                Ok(skill) => skill,
                 Err(e) => {
-                    warn!(path = %skill_md_path.display(), err = %e, "解析 SKILL.md 失败");
+                    skip(&skill_md_path, "解析 SKILL.md 失败", &e);
+                    continue;
                 }
             }
         } else {
             debug!(?dir_name, "无 skill.toml 且无 SKILL.md，跳过");
+            continue;
+        };
+        if let Some(prev) = skills.iter().find(|s| s.id == skill.id) {
+            let reason = if skills[..builtin].iter().any(|s| s.id == skill.id) { "与内置技能同 id，跳过" } else { "与其他技能同 id，跳过" };
+            skip(&sub, reason, &prev.id);
+            continue;
         }
+        skills.push(skill);
     }
 
-    if !skills.is_empty() {
-        info!(dir = %dir, count = skills.len(), "已加载 skills: {:?}", skills.iter().map(|s| s.id.as_str()).collect::<Vec<_>>());
+    if skills.len() > builtin {
+        info!(dir = %dir, count = skills.len() - builtin, "已加载 skills: {:?}", skills[builtin..].iter().map(|s| s.id.as_str()).collect::<Vec<_>>());
+    }
+    SkillLoad { skills, errors }
+}
+
+/// 当前生效的技能集合。读取时取得快照，重新加载时整体替换：进行中的任务继续使用旧快照，
+/// 不会看到加载到一半的状态。
+pub struct SkillSet {
+    dir: Option<String>,
+    natives: NativeRegistry,
+    current: RwLock<Arc<Vec<Skill>>>,
+    /// 上次加载时技能目录的指纹，用于轮询时判断是否有变化
+    fingerprint: Mutex<u64>,
+}
+
+impl SkillSet {
+    /// 加载技能，返回集合与加载错误
+    pub fn load(dir: Option<String>, natives: NativeRegistry) -> (Self, Vec<String>) {
+        let set = Self {
+            dir,
+            natives,
+            current: RwLock::new(Arc::new(Vec::new())),
+            fingerprint: Mutex::new(0),
+        };
+        let load = set.reload();
+        (set, load.errors)
+    }
+
+    /// 当前技能的快照
+    pub fn get(&self) -> Arc<Vec<Skill>> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn dir(&self) -> &str {
+        self.dir.as_deref().unwrap_or(DEFAULT_SKILLS_DIR)
+    }
+
+    /// 重新解析技能目录并替换当前集合；返回的 `skills` 为新集合
+    pub fn reload(&self) -> SkillLoad {
+        let fingerprint = dir_fingerprint(Path::new(self.dir()));
+        let load = load_skills(self.dir.as_deref(), &self.natives);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(load.skills.clone());
+        *self.fingerprint.lock().unwrap_or_else(|e| e.into_inner()) = fingerprint;
+        load
+    }
+
+    /// 技能目录自上次加载后是否有变化（新增、删除或修改了清单文件）
+    pub fn changed(&self) -> bool {
+        dir_fingerprint(Path::new(self.dir())) != *self.fingerprint.lock().unwrap_or_else(|e| e.into_inner())
     }
-    skills
+}
+
+/// 技能目录的指纹：各子目录名及其清单文件的大小与修改时间。只看清单而不递归，
+/// 轮询开销很小，不依赖 inotify 等文件系统通知（OpenWrt 等环境上未必可用）。
+fn dir_fingerprint(dir: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let Ok(read_dir) = std::fs::read_dir(dir) else { return 0 };
+    let mut subs: Vec<_> = read_dir.filter_map(Result::ok).map(|e| e.path()).filter(|p| p.is_dir()).collect();
+    subs.sort();
+    for sub in subs {
+        sub.hash(&mut hasher);
+        for name in [SKILL_MANIFEST, SKILL_MD] {
+            if let Ok(meta) = std::fs::metadata(sub.join(name)) {
+                name.hash(&mut hasher);
+                meta.len().hash(&mut hasher);
+                meta.modified().ok().hash(&mut hasher);
+            }
+        }
+    }
+    // 区分「目录不存在」与「空目录」
+    hasher.finish() | 1
 }
 
 /// 生成要追加到分类系统提示的段落。无 skills 时返回空字符串。
@@ -205,15 +730,48 @@ pub fn build_prompt_section(skills: &[Skill]) -> String {
         return String::new();
     }
     let mut s = String::from("\n\n你还可以参考以下已安装的技能，在适当时生成对应命令：\n");
-    for sk in skills {
+    for sk in skills.iter().filter(|sk| !sk.is_executable()) {
         if sk.prompt_hint.is_empty() {
             continue;
         }
         s.push_str(&format!("- [{}] {}\n", sk.name, sk.prompt_hint));
     }
+    let executable: Vec<&Skill> = skills.iter().filter(|sk| sk.is_executable()).collect();
+    if !executable.is_empty() {
+        s.push_str("\n以下技能可以直接调用：需求匹配时不要生成 shell 命令，而是返回 {\"type\": \"skill\", \"id\": \"技能 id\", \"args\": {参数}}：\n");
+        for sk in executable {
+            let hint = if sk.prompt_hint.is_empty() { &sk.description } else { &sk.prompt_hint };
+            s.push_str(&format!("- id={} [{}] {}\n", sk.id, sk.name, hint));
+            for arg in &sk.args {
+                s.push_str(&format!(
+                    "  - {}（{}，{}）{}\n",
+                    arg.name,
+                    arg.kind.schema_type(),
+                    if arg.required { "必填" } else { "可选" },
+                    arg.description
+                ));
+            }
+        }
+    }
     s
 }
 
+/// 可执行技能参数的 JSON Schema，用于 tools 模式的工具声明
+pub fn args_schema(skill: &Skill) -> Value {
+    let properties: Map<String, Value> = skill
+        .args
+        .iter()
+        .map(|a| {
+            (
+                a.name.clone(),
+                serde_json::json!({ "type": a.kind.schema_type(), "description": a.description }),
+            )
+        })
+        .collect();
+    let required: Vec<&str> = skill.args.iter().filter(|a| a.required).map(|a| a.name.as_str()).collect();
+    serde_json::json!({ "type": "object", "properties": properties, "required": required })
+}
+
 /// 列出所有 skill 的摘要（id, name, description），用于回复「有哪些 skill」。
 pub fn list_skills_summary(skills: &[Skill]) -> String {
     if skills.is_empty() {
@@ -221,30 +779,28 @@ pub fn list_skills_summary(skills: &[Skill]) -> String {
     }
     let mut s = format!("已安装 {} 个 skill：\n\n", skills.len());
     for sk in skills {
-        s.push_str(&format!("• **{}** ({}) — {}\n", sk.name, sk.id, sk.description));
+        let marker = if sk.is_executable() { " [可执行]" } else { "" };
+        s.push_str(&format!("• **{}** ({}){marker} — {}\n", sk.name, sk.id, sk.description));
     }
     s.push_str("\n回复「怎么安装 <技能名>」可查看安装方式。");
     s
 }
 
-/// 根据失败命令内容匹配相关 skill，返回其 prompt_hint 拼接成的上下文，供「询问解决方式」时注入 LLM。
+/// 根据失败命令匹配相关 skill（见 [`Skill::relevant_to_command`]），返回其 prompt_hint 拼接成的上下文，
+/// 供「询问解决方式」时注入 LLM。
 pub fn build_relevant_context_for_fix(skills: &[Skill], failed_command: &str) -> String {
-    let cmd_lower = failed_command.to_lowercase();
-    let mut hints = Vec::new();
-    for sk in skills {
-        if sk.prompt_hint.is_empty() {
-            continue;
-        }
-        let relevant = match sk.id.as_str() {
-            "screen_record" => cmd_lower.contains("ffmpeg") || cmd_lower.contains("avfoundation"),
-            "screenshot" => cmd_lower.contains("screencapture") || cmd_lower.contains("scrot") || cmd_lower.contains("import"),
-            _ => false,
-        };
-        if relevant {
-            hints.push(format!("[{}] {}", sk.name, sk.prompt_hint));
-        }
-    }
-    hints.join("\n\n")
+    skills
+        .iter()
+        .filter(|sk| !sk.prompt_hint.is_empty() && sk.relevant_to_command(failed_command))
+        .map(|sk| format!("[{}] {}", sk.name, sk.prompt_hint))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// 选出与用户消息相关的 skill（见 [`Skill::relevant_to_message`]），只把它们注入分类提示与工具声明，
+/// 技能增多时提示不随之膨胀。
+pub fn select_for_message(skills: &[Skill], text: &str) -> Vec<Skill> {
+    skills.iter().filter(|sk| sk.relevant_to_message(text)).cloned().collect()
 }
 
 /// 根据 id 或 name 查找 skill 并返回其安装说明。
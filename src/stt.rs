@@ -0,0 +1,202 @@
+//! 语音转写：把语音消息 / 音频文件发给 OpenAI 兼容的 `/audio/transcriptions` 接口
+//! （OpenAI、Groq，或以 `--inference-path /v1/audio/transcriptions` 启动的本地 whisper.cpp server）。
+//!
+//! 接口不支持的格式、或配置了 `transcode` 时，先用 ffmpeg 转成 16 kHz 单声道 wav；
+//! ffmpeg 经执行器运行，受命令策略约束，任务取消时一并终止。
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::SttConfig;
+use crate::executor::{Executor, RunOptions};
+
+/// `/audio/transcriptions` 直接接受的扩展名
+const SUPPORTED_EXTENSIONS: &[&str] = &["flac", "mp3", "mp4", "mpeg", "mpga", "m4a", "ogg", "wav", "webm"];
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+pub struct SttClient {
+    client: reqwest::Client,
+    config: SttConfig,
+}
+
+impl SttClient {
+    pub fn new(config: SttConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        Self { client, config }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// 转写一段音频，`file_name` 用于判断格式（Telegram 语音消息为 ogg/opus）。
+    /// 需要转码时 ffmpeg 以 `opts` 经 `executor` 执行。
+    pub async fn transcribe(
+        &self,
+        audio: Vec<u8>,
+        file_name: &str,
+        executor: &Executor,
+        opts: &RunOptions,
+    ) -> Result<String> {
+        let ext = Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        let (audio, file_name) = if self.config.transcode || !SUPPORTED_EXTENSIONS.contains(&ext.as_str()) {
+            tlog!("STT", "ffmpeg 转码 {} → wav", file_name);
+            (transcode_to_wav(executor, opts, &self.config.ffmpeg, &audio, &ext).await?, "audio.wav".to_string())
+        } else {
+            (audio, file_name.to_string())
+        };
+
+        let url = format!("{}/audio/transcriptions", self.config.base_url.trim_end_matches('/'));
+        tlog!("STT", "发送 {} ({} 字节) 到 {}", file_name, audio.len(), url);
+        let part = reqwest::multipart::Part::bytes(audio).file_name(file_name);
+        let mut form = reqwest::multipart::Form::new()
+            .part("file", part)
+            .text("model", self.config.model.clone())
+            .text("response_format", "json");
+        if let Some(language) = &self.config.language {
+            form = form.text("language", language.clone());
+        }
+
+        let mut req = self.client.post(&url).multipart(form);
+        if !self.config.api_key.is_empty() {
+            req = req.header("Authorization", format!("Bearer {}", self.config.api_key));
+        }
+        let resp = req.send().await.context("语音转写请求失败（可能超时或网络问题）")?;
+        let status = resp.status();
+        let body = resp.text().await.context("读取语音转写响应失败")?;
+        if !status.is_success() {
+            anyhow::bail!("语音转写接口错误 {status}: {body}");
+        }
+        let parsed: TranscriptionResponse =
+            serde_json::from_str(&body).with_context(|| format!("无法解析语音转写响应: {body}"))?;
+        let text = parsed.text.trim().to_string();
+        tlog!("STT", "转写结果 ({} 字符): {}", text.len(), text);
+        Ok(text)
+    }
+}
+
+/// 用 ffmpeg 把音频转成 16 kHz 单声道 wav（whisper 的原生输入格式）
+async fn transcode_to_wav(executor: &Executor, opts: &RunOptions, ffmpeg: &str, audio: &[u8], ext: &str) -> Result<Vec<u8>> {
+    let dir = std::env::temp_dir();
+    let stem = format!("rust-bot-stt-{}-{}", std::process::id(), chrono::Local::now().timestamp_nanos_opt().unwrap_or(0));
+    let input = dir.join(format!("{stem}.{}", if ext.is_empty() { "bin" } else { ext }));
+    let output = dir.join(format!("{stem}.wav"));
+    tokio::fs::write(&input, audio)
+        .await
+        .with_context(|| format!("无法写入临时文件: {}", input.display()))?;
+    let mut args: Vec<String> = ["-nostdin", "-hide_banner", "-loglevel", "error", "-y", "-i"].map(String::from).to_vec();
+    args.push(input.display().to_string());
+    args.extend(["-ar", "16000", "-ac", "1", "-f", "wav"].map(String::from));
+    args.push(output.display().to_string());
+    let label = format!("{ffmpeg} -i {} {}", input.display(), output.display());
+    let result = executor.run_program(Path::new(ffmpeg), &args, &[], &label, opts).await;
+    let _ = tokio::fs::remove_file(&input).await;
+    let out = result.with_context(|| format!("无法运行 ffmpeg（{ffmpeg}），请安装或配置 stt.ffmpeg"))?;
+    if !out.success {
+        let _ = tokio::fs::remove_file(&output).await;
+        match &out.policy_rule {
+            Some(rule) => anyhow::bail!("ffmpeg 被命令策略拒绝（{rule}）"),
+            None => anyhow::bail!("ffmpeg 转码失败: {}", out.stderr.trim()),
+        }
+    }
+    let wav = tokio::fs::read(&output).await.context("读取转码结果失败");
+    let _ = tokio::fs::remove_file(&output).await;
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExecutorConfig;
+    use crate::test_support::stub_server;
+
+    async fn transcribe(client: &SttClient, audio: &[u8], file_name: &str) -> Result<String> {
+        let executor = Executor::new(ExecutorConfig::default(), Path::new("config.toml")).unwrap();
+        client.transcribe(audio.to_vec(), file_name, &executor, &RunOptions::default()).await
+    }
+
+    fn config(base_url: String) -> SttConfig {
+        SttConfig {
+            enabled: true,
+            base_url,
+            api_key: "secret".to_string(),
+            model: "whisper-1".to_string(),
+            language: Some("zh".to_string()),
+            ..SttConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transcribe_posts_multipart_and_returns_text() {
+        let (base_url, mut requests) = stub_server("application/json", vec![("200 OK", r#"{"text": " 查看磁盘空间 "}"#.into())]).await;
+        let client = SttClient::new(config(base_url));
+
+        let text = transcribe(&client, b"OggS-fake-audio", "voice.ogg").await.unwrap();
+        assert_eq!(text, "查看磁盘空间");
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.head.starts_with("POST /v1/audio/transcriptions "));
+        assert!(request.head.to_ascii_lowercase().contains("authorization: bearer secret"));
+        assert!(request.body.contains("filename=\"voice.ogg\""));
+        assert!(request.body.contains("OggS-fake-audio"));
+        assert!(request.body.contains("name=\"model\"\r\n\r\nwhisper-1"));
+        assert!(request.body.contains("name=\"language\"\r\n\r\nzh"));
+    }
+
+    #[tokio::test]
+    async fn transcribe_reports_http_errors() {
+        let (base_url, _requests) = stub_server("application/json", vec![("500 Internal Server Error", r#"{"error": "model not loaded"}"#.into())]).await;
+        let client = SttClient::new(config(base_url));
+
+        let err = transcribe(&client, b"audio", "voice.ogg").await.unwrap_err();
+        assert!(err.to_string().contains("500"), "{err}");
+        assert!(err.to_string().contains("model not loaded"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn transcribe_rejects_unexpected_body() {
+        let (base_url, _requests) = stub_server("application/json", vec![("200 OK", r#"{"segments": []}"#.into())]).await;
+        let client = SttClient::new(config(base_url));
+
+        assert!(transcribe(&client, b"audio", "voice.mp3").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_format_without_ffmpeg_fails_before_request() {
+        let mut config = config("http://127.0.0.1:9/v1".to_string());
+        config.ffmpeg = "/nonexistent/ffmpeg".to_string();
+        let client = SttClient::new(config);
+
+        let err = transcribe(&client, b"audio", "voice.amr").await.unwrap_err();
+        assert!(err.to_string().contains("ffmpeg"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn transcoding_follows_command_policy() {
+        let client = SttClient::new(config("http://127.0.0.1:9/v1".to_string()));
+        let executor = Executor::new(
+            ExecutorConfig {
+                policy: crate::config::PolicyConfig { blocked_binaries: vec!["ffmpeg".into()], ..Default::default() },
+                ..ExecutorConfig::default()
+            },
+            Path::new("config.toml"),
+        )
+        .unwrap();
+
+        let err = client.transcribe(b"audio".to_vec(), "voice.amr", &executor, &RunOptions::default()).await.unwrap_err();
+        assert!(err.to_string().contains("命令策略"), "{err}");
+    }
+}
@@ -0,0 +1,113 @@
+//! 任务表：登记正在处理的任务（`tid`）及其当前命令所在的进程组，供 `/cancel` 与取消按钮使用。
+//!
+//! 取消只是置位信号；正在执行的命令由 executor 收到信号后对整个进程组先 SIGTERM、再 SIGKILL，
+//! 任务流程在各检查点（下一条命令、修正重试、审批等待、Agent 下一步）看到信号后停止。
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup};
+use tokio::sync::watch;
+
+const CALLBACK_PREFIX: &str = "cancel";
+
+#[derive(Debug)]
+pub struct TaskHandle {
+    pub tid: u64,
+    pub chat_id: ChatId,
+    cancel: watch::Sender<bool>,
+    /// 当前正在执行的命令的进程组 ID
+    pgid: Mutex<Option<u32>>,
+}
+
+impl TaskHandle {
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancel.borrow()
+    }
+
+    /// 等待取消信号
+    pub async fn cancelled(&self) {
+        let mut rx = self.cancel.subscribe();
+        // sender 由 self 持有，不会提前关闭
+        let _ = rx.wait_for(|c| *c).await;
+    }
+
+    pub fn set_process_group(&self, pgid: Option<u32>) {
+        *self.pgid.lock().unwrap() = pgid;
+    }
+
+    pub fn process_group(&self) -> Option<u32> {
+        *self.pgid.lock().unwrap()
+    }
+}
+
+#[derive(Default)]
+pub struct TaskTable {
+    tasks: Mutex<HashMap<u64, Arc<TaskHandle>>>,
+}
+
+impl TaskTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, tid: u64, chat_id: ChatId) -> Arc<TaskHandle> {
+        let handle = Arc::new(TaskHandle {
+            tid,
+            chat_id,
+            cancel: watch::channel(false).0,
+            pgid: Mutex::new(None),
+        });
+        self.tasks.lock().unwrap().insert(tid, handle.clone());
+        handle
+    }
+
+    pub fn remove(&self, tid: u64) {
+        self.tasks.lock().unwrap().remove(&tid);
+    }
+
+    pub fn get(&self, tid: u64) -> Option<Arc<TaskHandle>> {
+        self.tasks.lock().unwrap().get(&tid).cloned()
+    }
+
+    /// 该聊天中仍在处理的任务，按 tid 升序
+    pub fn running_in(&self, chat_id: ChatId) -> Vec<Arc<TaskHandle>> {
+        let mut tasks: Vec<_> = self
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|t| t.chat_id == chat_id)
+            .cloned()
+            .collect();
+        tasks.sort_by_key(|t| t.tid);
+        tasks
+    }
+}
+
+/// 状态消息上的取消按钮
+pub fn cancel_keyboard(tid: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🛑 取消",
+        format!("{CALLBACK_PREFIX}:{tid}"),
+    )]])
+}
+
+/// 解析取消按钮的回调数据，非取消回调返回 None。
+pub fn parse_callback_data(data: &str) -> Option<u64> {
+    data.strip_prefix(CALLBACK_PREFIX)?.strip_prefix(':')?.parse().ok()
+}
+
+/// 解析 `/cancel` 的参数，接受 `12` 或 `#12`。
+pub fn parse_tid(arg: &str) -> Option<u64> {
+    arg.trim().trim_start_matches('#').parse().ok()
+}
+
+/// 对整个进程组发送信号；进程组已不存在时返回 false。
+pub fn signal_process_group(pgid: u32, signal: i32) -> bool {
+    // SAFETY: kill(2) 只读取参数，负的 pid 表示进程组
+    unsafe { libc::kill(-(pgid as i32), signal) == 0 }
+}
@@ -0,0 +1,64 @@
+//! 测试共用的辅助工具。
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// HTTP 桩收到的一个请求
+pub struct StubRequest {
+    /// 请求行与请求头
+    pub head: String,
+    pub body: String,
+}
+
+impl StubRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// 本地 HTTP 桩：依次以 (状态行, 响应体) 应答每个请求，收到的请求经返回的通道交给测试。
+/// 返回 `http://<地址>/v1` 形式的 base URL。
+pub async fn stub_server(
+    content_type: &'static str,
+    responses: Vec<(&'static str, String)>,
+) -> (String, mpsc::UnboundedReceiver<StubRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for (status, body) in responses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 8192];
+            let header_end = loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let length = text[..header_end]
+                        .lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + length {
+                        break header_end;
+                    }
+                }
+                if n == 0 {
+                    break request.len();
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            let _ = tx.send(StubRequest {
+                head: String::from_utf8_lossy(&request[..header_end]).into_owned(),
+                body: String::from_utf8_lossy(&request[(header_end + 4).min(request.len())..]).into_owned(),
+            });
+        }
+    });
+    (format!("http://{addr}/v1"), rx)
+}
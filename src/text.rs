@@ -0,0 +1,26 @@
+//! 文本工具。
+
+/// 按字节截断到 max，保证在 UTF-8 字符边界处切断，避免 panic。
+pub(crate) fn truncate_str(s: &str, max: usize) -> String {
+    if s.len() <= max {
+        return s.to_string();
+    }
+    let mut end = max;
+    while end > 0 && !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...(截断)", &s[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_str_respects_char_boundary() {
+        assert_eq!(truncate_str("abc", 3), "abc");
+        // "你" 占 3 字节，max=4 时应回退到第一个字符末尾
+        assert_eq!(truncate_str("你好", 4), "你...(截断)");
+        assert_eq!(truncate_str("你好", 1), "...(截断)");
+    }
+}
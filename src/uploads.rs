@@ -0,0 +1,165 @@
+//! 用户上传的文件：保存到 `<working_dir>/<uploads.dir>/<chat_id>/`，文件名经过清理，
+//! 重名时追加序号而不覆盖已有文件。
+
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::UploadConfig;
+
+/// 清理后文件名的最大字节数
+const MAX_FILE_NAME_BYTES: usize = 200;
+
+pub struct UploadStore {
+    enabled: bool,
+    root: PathBuf,
+    max_bytes: u32,
+}
+
+impl UploadStore {
+    /// `working_dir` 为命令执行的工作目录，相对的 `uploads.dir` 以它为基准
+    pub fn new(config: &UploadConfig, working_dir: &Path) -> Self {
+        Self {
+            enabled: config.enabled,
+            root: working_dir.join(&config.dir),
+            max_bytes: config.max_size_mb.saturating_mul(1024 * 1024),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn max_bytes(&self) -> u32 {
+        self.max_bytes
+    }
+
+    /// 保存文件并返回其路径
+    pub fn save(&self, chat_id: i64, file_name: &str, bytes: &[u8]) -> Result<PathBuf> {
+        let dir = self.root.join(chat_id.to_string());
+        std::fs::create_dir_all(&dir).with_context(|| format!("无法创建上传目录: {}", dir.display()))?;
+        let name = sanitize_file_name(file_name);
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{ext}")),
+            _ => (name.clone(), String::new()),
+        };
+        for n in 0.. {
+            let path = match n {
+                0 => dir.join(&name),
+                n => dir.join(format!("{stem}-{n}{ext}")),
+            };
+            // create_new 保证不覆盖已有文件，并发保存同名文件时各得一个序号
+            let mut file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(|| format!("无法创建文件: {}", path.display())),
+            };
+            file.write_all(bytes)
+                .with_context(|| format!("写入文件失败: {}", path.display()))?;
+            return Ok(path);
+        }
+        unreachable!()
+    }
+}
+
+/// 只保留文件名本身：去掉目录部分、控制字符与常见文件系统的保留字符，
+/// 去掉首尾的点和空白（避免隐藏文件与 `..`），并限制长度（保留扩展名）。
+pub fn sanitize_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .map(|c| if c.is_control() || "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+    let cleaned = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if cleaned.is_empty() {
+        return "file".to_string();
+    }
+    if cleaned.len() <= MAX_FILE_NAME_BYTES {
+        return cleaned.to_string();
+    }
+    let ext = cleaned
+        .rsplit_once('.')
+        .map(|(_, ext)| format!(".{ext}"))
+        .filter(|ext| ext.len() <= 16)
+        .unwrap_or_default();
+    let mut end = MAX_FILE_NAME_BYTES - ext.len();
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{ext}", &cleaned[..end])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_strips_directories_and_unsafe_characters() {
+        let cases = [
+            ("report.csv", "report.csv"),
+            ("../x", "x"),
+            ("a/../../b", "b"),
+            ("..\\..\\windows\\evil.exe", "evil.exe"),
+            ("/etc/passwd", "passwd"),
+            (".", "file"),
+            ("..", "file"),
+            ("dir/", "file"),
+            ("", "file"),
+            ("   ", "file"),
+            ("...hidden", "hidden"),
+            ("name.", "name"),
+            ("a\0b.txt", "a_b.txt"),
+            ("line\nbreak\t.txt", "line_break_.txt"),
+            ("what?<>:\"|*.txt", "what_______.txt"),
+            ("数据 报表.xlsx", "数据 报表.xlsx"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitize_file_name(input), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn sanitize_limits_length_and_keeps_extension() {
+        let long = format!("{}.pdf", "a".repeat(500));
+        let name = sanitize_file_name(&long);
+        assert_eq!(name.len(), MAX_FILE_NAME_BYTES);
+        assert!(name.ends_with(".pdf"));
+
+        // 截断落在多字节字符中间时退到字符边界
+        let name = sanitize_file_name(&format!("{}.txt", "文".repeat(100)));
+        assert!(name.len() <= MAX_FILE_NAME_BYTES);
+        assert!(name.ends_with(".txt"));
+        assert!(name.trim_end_matches(".txt").chars().all(|c| c == '文'));
+
+        // 过长的「扩展名」不保留
+        let name = sanitize_file_name(&format!("x.{}", "e".repeat(300)));
+        assert_eq!(name, format!("x.{}", "e".repeat(MAX_FILE_NAME_BYTES - 2)));
+    }
+
+    #[test]
+    fn save_appends_suffix_instead_of_overwriting() {
+        let dir = std::env::temp_dir().join(format!("uploads-test-{}", std::process::id()));
+        let store = UploadStore::new(&UploadConfig { enabled: true, ..UploadConfig::default() }, &dir);
+
+        let first = store.save(42, "data.csv", b"one").unwrap();
+        let second = store.save(42, "../data.csv", b"two").unwrap();
+        let third = store.save(42, "data.csv", b"three").unwrap();
+        let bare = store.save(42, "README", b"a").unwrap();
+        let bare_again = store.save(42, "README", b"b").unwrap();
+
+        let chat_dir = store.root().join("42");
+        assert_eq!(first, chat_dir.join("data.csv"));
+        assert_eq!(second, chat_dir.join("data-1.csv"));
+        assert_eq!(third, chat_dir.join("data-2.csv"));
+        assert_eq!(bare_again, chat_dir.join("README-1"));
+        assert_eq!(std::fs::read(&first).unwrap(), b"one");
+        assert_eq!(std::fs::read(&second).unwrap(), b"two");
+        assert_eq!(std::fs::read(&bare).unwrap(), b"a");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
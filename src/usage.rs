@@ -0,0 +1,308 @@
+//! Token 用量统计：每次 LLM 调用的 prompt / completion / total tokens 以一行 JSON 追加到用量文件（JSONL），
+//! 启动时读回并按 日期 × 聊天 × 用户 × 模型 汇总在内存中，供 `/usage` 查询与按用户的每日配额检查。
+//!
+//! 费用不落盘，查询时按 `[usage.prices]` 中的单价计算，修改价格表后历史用量按新价格展示。
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::{ModelPrice, UsageConfig};
+
+/// 一次（或累计的）token 用量
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    /// 解析响应中的 `usage` 字段；缺少 total_tokens 时按两者之和计算。
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let mut usage: Usage = serde_json::from_value(value.clone()).ok()?;
+        if usage.total_tokens == 0 {
+            usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+        }
+        Some(usage)
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// 用量文件中的一行
+#[derive(Debug, Deserialize, Serialize)]
+struct UsageEntry {
+    day: NaiveDate,
+    #[serde(default)]
+    chat_id: Option<i64>,
+    #[serde(default)]
+    user_id: Option<u64>,
+    model: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct UsageKey {
+    day: NaiveDate,
+    chat_id: Option<i64>,
+    user_id: Option<u64>,
+    model: String,
+}
+
+/// 超出每日 token 配额时 LLM 调用返回的错误
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub used: u64,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "今日 token 配额已用完（已用 {} / 上限 {}）", self.used, self.limit)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+pub struct UsageStore {
+    config: UsageConfig,
+    path: Option<PathBuf>,
+    totals: Mutex<HashMap<UsageKey, Usage>>,
+    /// 串行化追加写入，避免并发任务的行交错；与 totals 分开，写文件时不阻塞配额检查与查询
+    write_lock: Mutex<()>,
+}
+
+impl UsageStore {
+    pub fn new(config: &UsageConfig) -> Result<Self> {
+        let path = if config.enabled {
+            let path = PathBuf::from(&config.path);
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("无法创建用量目录: {}", dir.display()))?;
+            }
+            Some(path)
+        } else {
+            None
+        };
+        let store = Self {
+            config: config.clone(),
+            path,
+            totals: Mutex::new(HashMap::new()),
+            write_lock: Mutex::new(()),
+        };
+        store.load();
+        Ok(store)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 读回用量文件并汇总，跳过无法解析的行
+    fn load(&self) {
+        let Some(file) = self.path.as_ref().and_then(|p| std::fs::File::open(p).ok()) else { return };
+        let mut totals = self.totals.lock().unwrap();
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let Ok(entry) = serde_json::from_str::<UsageEntry>(&line) else { continue };
+            let key = UsageKey {
+                day: entry.day,
+                chat_id: entry.chat_id,
+                user_id: entry.user_id,
+                model: entry.model,
+            };
+            totals.entry(key).or_default().add(&entry.usage);
+        }
+    }
+
+    /// 记录一次调用的用量：累加到内存汇总并追加到用量文件
+    pub fn record(&self, chat_id: Option<i64>, user_id: Option<u64>, model: &str, usage: Usage) -> Result<()> {
+        let entry = UsageEntry {
+            day: Local::now().date_naive(),
+            chat_id,
+            user_id,
+            model: model.to_string(),
+            usage,
+        };
+        let key = UsageKey {
+            day: entry.day,
+            chat_id,
+            user_id,
+            model: entry.model.clone(),
+        };
+        self.totals.lock().unwrap().entry(key).or_default().add(&usage);
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut line = serde_json::to_string(&entry).context("序列化用量记录失败")?;
+        line.push('\n');
+        let _guard = self.write_lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("无法打开用量文件: {}", path.display()))?;
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("写入用量文件失败: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// 用户的每日 token 配额；未配置或为 0 表示不限
+    pub fn daily_quota(&self, user_id: u64) -> Option<u64> {
+        let limit = self
+            .config
+            .user_quotas
+            .iter()
+            .find(|q| q.id == user_id)
+            .map(|q| q.daily_tokens)
+            .unwrap_or(self.config.daily_token_quota);
+        (limit > 0).then_some(limit)
+    }
+
+    /// 用户今日（本地时间）已用的 token 总数，跨所有聊天与模型
+    pub fn used_today(&self, user_id: u64) -> u64 {
+        let today = Local::now().date_naive();
+        self.totals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, _)| k.day == today && k.user_id == Some(user_id))
+            .map(|(_, u)| u.total_tokens)
+            .sum()
+    }
+
+    /// 已超出配额时返回错误
+    pub fn check_quota(&self, user_id: u64) -> std::result::Result<(), QuotaExceeded> {
+        let Some(limit) = self.daily_quota(user_id) else { return Ok(()) };
+        let used = self.used_today(user_id);
+        if used >= limit {
+            return Err(QuotaExceeded { used, limit });
+        }
+        Ok(())
+    }
+
+    /// 指定日期范围内按模型汇总的用量；`chat_id` 为 None 时统计所有聊天
+    fn by_model(&self, chat_id: Option<i64>, from: NaiveDate, to: NaiveDate) -> BTreeMap<String, Usage> {
+        let mut models: BTreeMap<String, Usage> = BTreeMap::new();
+        for (k, u) in self.totals.lock().unwrap().iter() {
+            if k.day < from || k.day > to || chat_id.is_some_and(|c| k.chat_id != Some(c)) {
+                continue;
+            }
+            models.entry(k.model.clone()).or_default().add(u);
+        }
+        models
+    }
+
+    fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        let ModelPrice { prompt, completion } = self.config.prices.get(model)?;
+        Some((usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion) / 1_000_000.0)
+    }
+
+    /// `/usage` 的文本：今日与本月按模型的 token 数与费用；`chat_id` 为 None 时统计所有聊天。
+    pub fn report(&self, chat_id: Option<i64>, user_id: Option<u64>) -> String {
+        let today = Local::now().date_naive();
+        let month_start = today.with_day(1).unwrap_or(today);
+        let scope = if chat_id.is_some() { "本聊天" } else { "所有聊天" };
+        let mut s = format!("📊 LLM 用量（{scope}）\n");
+        for (title, from) in [(format!("今日 {}", today.format("%Y-%m-%d")), today), (format!("本月 {}", today.format("%Y-%m")), month_start)] {
+            s.push_str(&format!("\n{title}:\n"));
+            let models = self.by_model(chat_id, from, today);
+            if models.is_empty() {
+                s.push_str("  无\n");
+                continue;
+            }
+            let mut total = Usage::default();
+            let mut total_cost = 0.0;
+            for (model, usage) in &models {
+                total.add(usage);
+                let cost = self.cost(model, usage);
+                total_cost += cost.unwrap_or(0.0);
+                s.push_str(&format!(
+                    "  {model}: 输入 {} / 输出 {} / 合计 {} tokens{}\n",
+                    usage.prompt_tokens,
+                    usage.completion_tokens,
+                    usage.total_tokens,
+                    cost.map(|c| format!("，{}", self.format_cost(c))).unwrap_or_default()
+                ));
+            }
+            if models.len() > 1 {
+                s.push_str(&format!("  合计: {} tokens，{}\n", total.total_tokens, self.format_cost(total_cost)));
+            }
+        }
+        if let Some(user_id) = user_id {
+            let used = self.used_today(user_id);
+            match self.daily_quota(user_id) {
+                Some(limit) => s.push_str(&format!("\n你今日已用 {used} / 配额 {limit} tokens")),
+                None => s.push_str(&format!("\n你今日已用 {used} tokens")),
+            }
+        }
+        s
+    }
+
+    fn format_cost(&self, cost: f64) -> String {
+        format!("{}{:.4}", self.config.currency, cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserQuota;
+
+    fn store(config: UsageConfig) -> UsageStore {
+        UsageStore::new(&UsageConfig { enabled: false, ..config }).unwrap()
+    }
+
+    fn usage(prompt: u64, completion: u64) -> Usage {
+        Usage { prompt_tokens: prompt, completion_tokens: completion, total_tokens: prompt + completion }
+    }
+
+    #[test]
+    fn cost_uses_per_million_prices() {
+        let mut config = UsageConfig::default();
+        config.prices.insert("m".to_string(), ModelPrice { prompt: 2.0, completion: 8.0 });
+        let store = store(config);
+        let cost = store.cost("m", &usage(1_000_000, 500_000)).unwrap();
+        assert!((cost - 6.0).abs() < 1e-9);
+        assert!(store.cost("unknown", &usage(1, 1)).is_none());
+
+        store.record(Some(1), Some(7), "m", usage(250_000, 0)).unwrap();
+        store.record(Some(1), Some(7), "m", usage(250_000, 125_000)).unwrap();
+        let report = store.report(Some(1), Some(7));
+        assert!(report.contains("m: 输入 500000 / 输出 125000 / 合计 625000 tokens，$2.0000"), "{report}");
+    }
+
+    #[test]
+    fn daily_quota_only_counts_today() {
+        let store = store(UsageConfig {
+            daily_token_quota: 100,
+            user_quotas: vec![UserQuota { id: 8, daily_tokens: 0 }],
+            ..UsageConfig::default()
+        });
+        // 昨天的用量不计入今日配额
+        let yesterday = Local::now().date_naive().pred_opt().unwrap();
+        let key = UsageKey { day: yesterday, chat_id: Some(1), user_id: Some(7), model: "m".to_string() };
+        store.totals.lock().unwrap().insert(key, usage(1000, 0));
+        assert_eq!(store.used_today(7), 0);
+        assert!(store.check_quota(7).is_ok());
+
+        store.record(Some(1), Some(7), "m", usage(60, 40)).unwrap();
+        let err = store.check_quota(7).unwrap_err();
+        assert_eq!((err.used, err.limit), (100, 100));
+        // 其他用户不受影响；按用户覆盖为 0 表示不限
+        assert!(store.check_quota(9).is_ok());
+        store.record(Some(1), Some(8), "m", usage(1000, 0)).unwrap();
+        assert!(store.check_quota(8).is_ok());
+    }
+}